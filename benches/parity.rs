@@ -0,0 +1,38 @@
+//! Compares `Flags::parity` to a lookup table of the 256 bytes, which the emulator could use instead:
+//! `cargo bench --bench parity`
+
+#[allow(unused)]
+#[path = "../src/flags.rs"]
+mod flags;
+
+use std::{hint::black_box, time::Instant};
+
+use flags::Flags;
+
+/// Times every byte goes through each parity
+const ROUNDS: u32 = 1 << 18;
+
+/// Millions of bytes per second `parity` goes through
+fn rate(parity: impl Fn(u8) -> bool) -> f64 {
+    let mut even = 0u32;
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for value in 0..=0xffu8 {
+            even += u32::from(parity(black_box(value)));
+        }
+    }
+    let elapsed = start.elapsed();
+
+    black_box(even);
+    256. * f64::from(ROUNDS) / elapsed.as_secs_f64() / 1e6
+}
+
+fn main() {
+    let mut table = [false; 256];
+    for (value, parity) in (0..=0xffu8).zip(table.iter_mut()) {
+        *parity = Flags::parity(value);
+    }
+
+    println!("Flags::parity: {:.0} million bytes per second", rate(Flags::parity));
+    println!("Lookup table: {:.0} million bytes per second", rate(|value| table[usize::from(value)]));
+}
@@ -0,0 +1,178 @@
+use std::{convert::TryFrom, error, fmt};
+
+use crate::cpu::CpuState;
+
+/// Errors raised when assembling, with the 1-based line they occurred at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    /// Mnemonic and operands matching no instruction
+    UnknownInstruction(usize),
+    /// Number which isn't `$` followed by hexadecimal digits, or doesn't fit its operand
+    InvalidNumber(usize),
+    /// ORG going back over code already assembled
+    InvalidOrigin(usize),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            AsmError::UnknownInstruction(line) => write!(f, "Line {line}: unknown instruction"),
+            AsmError::InvalidNumber(line) => write!(f, "Line {line}: invalid number"),
+            AsmError::InvalidOrigin(line) => write!(f, "Line {line}: ORG before the code already assembled"),
+        }
+    }
+}
+
+impl error::Error for AsmError {}
+
+/// Assembles source in the format of `CpuState::listing`: one instruction per line, numbers
+/// written `$` followed by hexadecimal digits, `ORG` to set the address of the following code,
+/// `DB` for raw bytes and comments starting with `;`. Mnemonics are those of the disassembler.
+/// Returns the address of the first byte and the bytes, gaps left by `ORG` filled with zeros.
+pub fn assemble(source: &str) -> Result<(u16, Vec<u8>), AsmError> {
+    let templates = templates();
+    let mut origin = None;
+    let mut bytes = Vec::new();
+
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let text = text.split(';').next().unwrap_or_default().trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = split(text);
+        match mnemonic.as_str() {
+            "ORG" => {
+                let address = match operands.as_slice() {
+                    [address] => u16::try_from(number(address, line)?).map_err(|_| AsmError::InvalidNumber(line))?,
+                    _ => return Err(AsmError::UnknownInstruction(line)),
+                };
+                let start = *origin.get_or_insert(address);
+                let offset = usize::from(address.checked_sub(start).ok_or(AsmError::InvalidOrigin(line))?);
+                if offset < bytes.len() {
+                    return Err(AsmError::InvalidOrigin(line));
+                }
+                bytes.resize(offset, 0);
+            }
+            "DB" => {
+                for operand in &operands {
+                    bytes.push(u8::try_from(number(operand, line)?).map_err(|_| AsmError::InvalidNumber(line))?);
+                }
+            }
+            _ => {
+                // The numeric operand, if any, is matched by the `$` of the templates
+                let mut value = None;
+                let mut key = mnemonic;
+                for operand in &operands {
+                    key.push(' ');
+                    if operand.starts_with('$') {
+                        value = Some(number(operand, line)?);
+                        key.push('$');
+                    } else {
+                        key.push_str(&operand.to_ascii_uppercase());
+                    }
+                }
+
+                let &(_, op_code) = templates.iter()
+                                             .find(|(template, _)| *template == key)
+                                             .ok_or(AsmError::UnknownInstruction(line))?;
+                bytes.push(op_code);
+                match (CpuState::opcode_length(op_code), value) {
+                    (1, None) => {}
+                    (2, Some(value)) => bytes.push(u8::try_from(value).map_err(|_| AsmError::InvalidNumber(line))?),
+                    (3, Some(value)) => {
+                        let value = u16::try_from(value).map_err(|_| AsmError::InvalidNumber(line))?;
+                        bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                    _ => return Err(AsmError::UnknownInstruction(line)),
+                }
+            }
+        }
+    }
+
+    Ok((origin.unwrap_or(0), bytes))
+}
+
+/// Mnemonic, upper case, and operands of an instruction
+fn split(text: &str) -> (String, Vec<&str>) {
+    let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let operands = operands.split(',')
+                           .map(str::trim)
+                           .filter(|operand| !operand.is_empty())
+                           .collect();
+
+    (mnemonic.to_ascii_uppercase(), operands)
+}
+
+/// Parses a number written `$` followed by hexadecimal digits
+fn number(text: &str, line: usize) -> Result<u32, AsmError> {
+    text.strip_prefix('$')
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .ok_or(AsmError::InvalidNumber(line))
+}
+
+/// Disassembly of each opcode with its numeric operand replaced by `$`, as matched by `assemble`.
+/// Undocumented opcodes disassemble like the documented one they duplicate, which comes first.
+fn templates() -> Vec<(String, u8)> {
+    let mut templates: Vec<(String, u8)> = Vec::with_capacity(256);
+
+    for op_code in 0..=255 {
+        let cpu = CpuState::from_rom([op_code, 0, 0], 0, 0).expect("Three bytes fit in memory");
+        let (_, name) = cpu.disassemble_next();
+        let (mnemonic, operands) = split(&name);
+        let mut template = mnemonic;
+        for operand in operands {
+            template.push(' ');
+            template.push_str(if operand.starts_with('$') { "$" } else { operand });
+        }
+
+        if templates.iter().all(|(existing, _)| *existing != template) {
+            templates.push((template, op_code));
+        }
+    }
+
+    templates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listings_assemble_back_to_their_bytes() {
+        // LXI SP, $2400; MVI A, $3c; CALL $0010; undocumented JMP $0000 and NOP; DB; at $0010: OUT $04; RET
+        let mut rom = vec![0x31, 0x00, 0x24, 0x3e, 0x3c, 0xcd, 0x10, 0x00, 0xcb, 0x00, 0x00, 0x08, 0xff, 0x00, 0x00, 0x00];
+        rom.extend_from_slice(&[0xd3, 0x04, 0xc9]);
+        let cpu = CpuState::from_rom(&rom, 0, 0).unwrap();
+
+        let listing = cpu.listing(0, rom.len() as u16, false);
+        assert_eq!(assemble(&listing), Ok((0, rom.clone())));
+        // Cycle annotations are comments
+        assert_eq!(assemble(&cpu.listing(0, rom.len() as u16, true)), Ok((0, rom)));
+    }
+
+    #[test]
+    #[cfg(feature = "default_rom")]
+    fn the_embedded_rom_code_round_trips() {
+        let rom = include_bytes!("invaders.rom");
+        let cpu = CpuState::from_rom(&rom[..], 0, 0).unwrap();
+
+        let (origin, bytes) = assemble(&cpu.listing(0, 0x2000, false)).unwrap();
+        assert_eq!(origin, 0);
+        // The last instruction may read operands past the code
+        assert_eq!(bytes[..0x2000], rom[..0x2000]);
+    }
+
+    #[test]
+    fn origins_leave_gaps_and_errors_give_their_line() {
+        assert_eq!(assemble("\tORG $0100\n\tnop\n\tORG $0104\n\tmvi a, $ff"), Ok((0x0100, vec![0x00, 0, 0, 0, 0x3e, 0xff])));
+
+        assert_eq!(assemble("NOP\nFOO A"), Err(AsmError::UnknownInstruction(2)));
+        assert_eq!(assemble("MVI A"), Err(AsmError::UnknownInstruction(1)));
+        assert_eq!(assemble("MVI A, $100"), Err(AsmError::InvalidNumber(1)));
+        assert_eq!(assemble("JMP 1234"), Err(AsmError::UnknownInstruction(1)));
+        assert_eq!(assemble("DB $1g"), Err(AsmError::InvalidNumber(1)));
+        assert_eq!(assemble("ORG $0100\nNOP\nORG $0100"), Err(AsmError::InvalidOrigin(3)));
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+use crate::{clock::Clock, input::JoypadState, invaders::SpaceInvaders};
+
+/// Starts a game by itself once the game has been left in attract mode for a while,
+/// for kiosk or screensaver use
+pub struct AutoStart {
+    idle_timeout: Duration,
+    /// When the game was last seen in attract mode with no control pressed
+    idle_since: Option<Instant>,
+}
+
+impl AutoStart {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            idle_since: None,
+        }
+    }
+
+    /// Called once per frame, after the controls read from the player were set,
+    /// inserting a coin and starting a 1 player game once the idle timeout elapsed
+    pub fn update(&mut self, clock: &impl Clock, invaders: &mut SpaceInvaders) {
+        // The keys pulsed to start the game are pressed too, so it doesn't count as idle while they play
+        if !invaders.in_attract_mode() || invaders.input_state() != JoypadState::new() {
+            self.idle_since = None;
+            return;
+        }
+
+        let now = clock.now();
+        match self.idle_since {
+            Some(idle_since) if now.duration_since(idle_since) >= self.idle_timeout => {
+                self.idle_since = None;
+                invaders.insert_coin();
+                invaders.press_start();
+            }
+            Some(_) => {}
+            None => self.idle_since = Some(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::GameKey;
+
+    /// Clock stopped at a given time
+    struct StoppedClock(Instant);
+
+    impl Clock for StoppedClock {
+        fn now(&self) -> Instant {
+            self.0
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.0 += duration;
+        }
+    }
+
+    #[test]
+    fn coin_then_start_are_pressed_after_the_idle_timeout() {
+        // RAM is cleared, which is attract mode
+        let mut invaders = SpaceInvaders::from_rom([]).unwrap();
+        assert!(invaders.in_attract_mode());
+        let start = Instant::now();
+        let mut auto_start = AutoStart::new(Duration::from_secs(10));
+
+        auto_start.update(&StoppedClock(start), &mut invaders);
+        auto_start.update(&StoppedClock(start + Duration::from_millis(9_999)), &mut invaders);
+        assert_eq!(invaders.input_state(), JoypadState::new());
+
+        auto_start.update(&StoppedClock(start + Duration::from_secs(10)), &mut invaders);
+        let clock = StoppedClock(start + Duration::from_secs(11));
+        let mut pressed = Vec::new();
+        for _ in 0..30 {
+            auto_start.update(&clock, &mut invaders);
+            let input = invaders.input_state();
+            pressed.push((input.is_pressed(GameKey::Credit), input.is_pressed(GameKey::P1Start)));
+            invaders.run_frames(1).unwrap();
+        }
+
+        // A coin, then start, each held then released once
+        pressed.dedup();
+        assert_eq!(pressed, [(true, false), (false, false), (false, true), (false, false)]);
+    }
+}
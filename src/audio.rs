@@ -0,0 +1,78 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use crate::error::Error;
+
+/// Frames of samples queued at most, so latency doesn't build up when frames run early
+const MAX_QUEUED_FRAMES: usize = 4;
+
+/// Plays mono samples on the default output device. Samples are queued a frame at a time
+/// by the emulation, and played from the device's own thread, which plays silence when the queue runs dry.
+pub struct AudioOutput {
+    /// Playing until dropped
+    _stream: cpal::Stream,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl AudioOutput {
+    /// Opens the default output device. Fails when there is none, e.g. on headless machines,
+    /// or when it doesn't take floating point samples.
+    pub fn new() -> Result<Self, Error> {
+        let device = cpal::default_host().default_output_device()
+                                          .ok_or_else(|| Error::Audio("no output device".into()))?;
+        let config = device.default_output_config().map_err(audio_error)?;
+        if config.sample_format() != cpal::SampleFormat::F32 {
+            return Err(Error::Audio(format!("unsupported sample format {:?}", config.sample_format())));
+        }
+        let channels = usize::from(config.channels());
+        let sample_rate = config.sample_rate().0;
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let played = Arc::clone(&queue);
+        let stream = device.build_output_stream(
+            &config.config(),
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let mut queue = played.lock().unwrap_or_else(PoisonError::into_inner);
+                // Each sample goes to every channel
+                for frame in data.chunks_mut(channels) {
+                    frame.fill(queue.pop_front().unwrap_or(0.));
+                }
+            },
+            |e| eprintln!("Audio error: {e}"),
+            None,
+        ).map_err(audio_error)?;
+        stream.play().map_err(audio_error)?;
+
+        Ok(Self { _stream: stream, queue, sample_rate })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Number of samples played during a frame of `frame_duration`
+    pub fn samples_per_frame(&self, frame_duration: Duration) -> usize {
+        (f64::from(self.sample_rate) * frame_duration.as_secs_f64()).round() as usize
+    }
+
+    /// Queues the samples of a frame to be played after the ones already queued,
+    /// dropping the oldest ones when more than a few frames are queued
+    pub fn queue(&self, samples: &[f32]) {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.extend(samples);
+
+        let excess = queue.len().saturating_sub(MAX_QUEUED_FRAMES * samples.len());
+        queue.drain(..excess);
+    }
+}
+
+fn audio_error(e: impl fmt::Display) -> Error {
+    Error::Audio(e.to_string())
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::VecDeque,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Source of time for frame pacing, replaceable to make pacing deterministic
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// Real time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Keeps frames a fixed duration apart, regardless of how long they take to emulate
+pub struct FrameLimiter<C: Clock> {
+    clock: C,
+    frame_duration: Duration,
+    /// When the next frame is due, once the first frame ran
+    next_frame: Option<Instant>,
+}
+
+impl<C: Clock> FrameLimiter<C> {
+    pub fn new(clock: C, frame_duration: Duration) -> Self {
+        Self {
+            clock,
+            frame_duration,
+            next_frame: None,
+        }
+    }
+
+    /// Sleeps until the next frame is due
+    pub fn wait(&mut self) {
+        let now = self.clock.now();
+
+        let next_frame = match self.next_frame {
+            Some(next_frame) if next_frame > now => {
+                self.clock.sleep(next_frame - now);
+                next_frame
+            }
+            // Running late, start over from now instead of rushing to catch up
+            _ => now,
+        };
+
+        self.next_frame = Some(next_frame + self.frame_duration);
+    }
+}
+
+/// Rolling estimate of the achieved frame rate, over the last few frames
+pub struct FpsCounter<C: Clock> {
+    clock: C,
+    /// When each of the last frames ended, oldest first
+    timestamps: VecDeque<Instant>,
+    /// Number of frames the estimate is averaged over
+    window: usize,
+}
+
+impl<C: Clock> FpsCounter<C> {
+    pub fn new(clock: C, window: usize) -> Self {
+        Self {
+            clock,
+            timestamps: VecDeque::with_capacity(window + 1),
+            window: window.max(1),
+        }
+    }
+
+    /// Records that a frame just ended
+    pub fn tick(&mut self) {
+        self.timestamps.push_back(self.clock.now());
+        if self.timestamps.len() > self.window + 1 {
+            self.timestamps.pop_front();
+        }
+    }
+
+    /// Frames per second over the recorded frames, once at least 2 frames ended
+    pub fn fps(&self) -> Option<f64> {
+        let first = self.timestamps.front()?;
+        let last = self.timestamps.back()?;
+        let elapsed = last.duration_since(*first).as_secs_f64();
+
+        if elapsed > 0.0 {
+            Some(f64::from((self.timestamps.len() - 1) as u32) / elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clock only moving forward when slept on or advanced by hand, recording the sleeps
+    struct MockClock {
+        now: Instant,
+        sleeps: Vec<Duration>,
+    }
+
+    impl MockClock {
+        fn new() -> Self {
+            Self { now: Instant::now(), sleeps: Vec::new() }
+        }
+    }
+
+    impl Clock for &mut MockClock {
+        fn now(&self) -> Instant {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration;
+            self.sleeps.push(duration);
+        }
+    }
+
+    #[test]
+    fn frames_are_paced_by_the_remaining_time() {
+        let frame = Duration::from_millis(16);
+        let mut clock = MockClock::new();
+
+        let mut limiter = FrameLimiter::new(&mut clock, frame);
+        // The first frame doesn't wait
+        limiter.wait();
+        // Emulating takes 10ms of the 16ms
+        limiter.clock.now += Duration::from_millis(10);
+        limiter.wait();
+        // Running 4ms late starts over from now
+        limiter.clock.now += Duration::from_millis(20);
+        limiter.wait();
+        limiter.wait();
+
+        assert_eq!(clock.sleeps, vec![Duration::from_millis(6), frame]);
+    }
+
+    #[test]
+    fn fps_are_averaged_over_the_last_frames() {
+        let mut clock = MockClock::new();
+        let mut counter = FpsCounter::new(&mut clock, 4);
+        assert_eq!(counter.fps(), None);
+        counter.tick();
+        assert_eq!(counter.fps(), None);
+
+        for _ in 0..4 {
+            counter.clock.now += Duration::from_millis(20);
+            counter.tick();
+        }
+        assert_eq!(counter.fps(), Some(50.0));
+
+        // Frames older than the window are forgotten
+        for _ in 0..4 {
+            counter.clock.now += Duration::from_millis(10);
+            counter.tick();
+        }
+        assert_eq!(counter.fps(), Some(100.0));
+    }
+}
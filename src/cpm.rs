@@ -0,0 +1,107 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{cpu::CpuState, error::Error, simple_io::SimpleIO};
+
+/// Address CP/M programs are loaded and started at
+const PROGRAM_START: u16 = 0x0100;
+/// BDOS entry point, called by test ROMs to print their output
+const BDOS_ENTRY: u16 = 0x0005;
+/// Printed by the test ROMs when all tests passed
+const SUCCESS_MESSAGE: &str = "CPU IS OPERATIONAL";
+
+/// Runs a CP/M test ROM (such as cpudiag or TST8080) until it returns to CP/M,
+/// printing its output. Returns whether the ROM reported success.
+pub fn run_test_rom(path: impl AsRef<Path>) -> Result<bool, Error> {
+    run_test_rom_to(path, &mut io::stdout())
+}
+
+/// `run_test_rom`, writing the ROM's output to `out` instead of stdout.
+/// Only the ROM's output goes there, the emulator's own diagnostics go to stderr.
+pub fn run_test_rom_to(path: impl AsRef<Path>, out: &mut impl Write) -> Result<bool, Error> {
+    let rom = fs::read(path)?;
+
+    let mut cpu = CpuState::from_rom(&rom, PROGRAM_START as usize, PROGRAM_START)?;
+    cpu.set_rom_end(0);
+    cpu.set_warn_unwritten(true);
+    // BDOS calls are trapped before they run, then simply return
+    cpu.load_rom(&[0xc9], BDOS_ENTRY as usize)?;
+
+    // Test ROMs don't use any IO port
+    let mut io_state = SimpleIO::new();
+    let mut output = String::new();
+
+    // Returning to CP/M jumps to 0x0000. Without interrupts, halting stops the ROM for good.
+    while cpu.pc() != 0 && !cpu.is_halted() {
+        if cpu.pc() == BDOS_ENTRY {
+            let text = bdos_output(&cpu);
+            write!(out, "{text}")?;
+            output += &text;
+        }
+
+        cpu.emulate(&mut io_state)?;
+    }
+    writeln!(out)?;
+
+    Ok(output.contains(SUCCESS_MESSAGE))
+}
+
+/// Returns the text printed by the BDOS call about to be executed
+fn bdos_output(cpu: &CpuState) -> String {
+    match cpu.c() {
+        // Print the character in E
+        2 => char::from(cpu.e()).to_string(),
+        // Print the '$'-terminated string at DE
+        9 => cpu.read_string(cpu.de(), b'$'),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a program printing `message` through BDOS, then returning to CP/M
+    fn run_printing(message: &str) -> bool {
+        // LXI SP, $3f00; MVI C, 9; LXI D, $010e; CALL $0005; JMP $0000
+        let mut rom = vec![0x31, 0x00, 0x3f, 0x0e, 0x09, 0x11, 0x0e, 0x01, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00];
+        rom.extend(message.bytes());
+        rom.push(b'$');
+
+        let path = std::env::temp_dir().join(format!("rust-8080-test-{}.com", message.len()));
+        fs::write(&path, rom).unwrap();
+        let passed = run_test_rom_to(&path, &mut Vec::new()).unwrap();
+        fs::remove_file(&path).unwrap();
+        passed
+    }
+
+    #[test]
+    fn test_rom_success_is_detected_from_its_output() {
+        assert!(run_printing(SUCCESS_MESSAGE));
+        assert!(!run_printing("CPU HAS FAILED"));
+    }
+
+    #[test]
+    fn missing_test_rom_is_an_error() {
+        assert!(matches!(run_test_rom("/nonexistent/test.com"), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn only_the_rom_output_goes_to_stdout() {
+        // LXI SP, $3f00; MVI C, 9; LXI D, $0111; CALL $0005; JMP $0200, running into memory never written
+        let mut rom = vec![0x31, 0x00, 0x3f, 0x0e, 0x09, 0x11, 0x11, 0x01, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x02];
+        rom.extend(b"\0\0\0HELLO$");
+        let path = std::env::temp_dir().join("rust-8080-test-streams.com");
+        fs::write(&path, rom).unwrap();
+
+        // Executing memory never written is warned about, on stderr
+        let mut out = Vec::new();
+        assert!(!run_test_rom_to(&path, &mut out).unwrap());
+        assert_eq!(String::from_utf8(out).unwrap(), "HELLO\n");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
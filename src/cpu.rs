@@ -1,6 +1,10 @@
-use std::{self, fmt, process};
+use std::{self, collections::VecDeque, convert::TryFrom, fmt, mem};
 
-use crate::{flags::Flags, invaders::IOState};
+use crate::{
+    error::Error,
+    flags::Flags,
+    invaders::IOState,
+};
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -41,10 +45,214 @@ impl RegisterPair {
     pub fn msb_mut(&mut self) -> &mut u8 {
         unsafe { &mut self.one.1 }
     }
+
+    /// Both bytes, least significant first
+    pub fn bytes(self) -> [u8; 2] {
+        [self.lsb(), self.msb()]
+    }
+
+    /// Pair made of both bytes, least significant first
+    pub fn from_bytes([lsb, msb]: [u8; 2]) -> Self {
+        let mut pair = Self::new();
+        *pair.lsb_mut() = lsb;
+        *pair.msb_mut() = msb;
+        pair
+    }
 }
 
 const MEMORY_SIZE: usize = 0x4000;
 
+/// Cycles consumed by each `emulate` call while halted
+const HALTED_CYCLES: u64 = 4;
+/// Highest interrupt number, RST 7
+pub const MAX_INTERRUPT: u16 = 7;
+/// Memory is inline by default, making the state large to move and clone but saving an indirection
+#[cfg(not(feature = "boxed_memory"))]
+type Memory = [u8; MEMORY_SIZE];
+#[cfg(feature = "boxed_memory")]
+type Memory = Box<[u8; MEMORY_SIZE]>;
+
+/// Register index of M, the memory at HL, in opcodes
+const M_INDEX: u8 = 6;
+/// Size of a serialized state: A, BC, DE, HL, SP, PC, PSW, interrupts and memory
+const STATE_SIZE: usize = 1 + 2 * 5 + 1 + 1 + MEMORY_SIZE;
+
+/// Instruction boundaries seen while running, to catch execution landing inside an instruction
+#[derive(Clone)]
+struct AlignmentCheck {
+    /// One bit per address, set once an instruction started there
+    starts: Vec<u64>,
+    /// One bit per address, set once it was read as the operand of an instruction
+    operands: Vec<u64>,
+    /// Addresses of the instructions which started inside another instruction, in order
+    misaligned: Vec<u16>,
+}
+
+impl AlignmentCheck {
+    fn is_set(bits: &[u64], address: usize) -> bool {
+        bits[address / 64] & 1 << (address % 64) != 0
+    }
+
+    fn set(bits: &mut [u64], address: usize) {
+        bits[address / 64] |= 1 << (address % 64);
+    }
+
+    /// Records the instruction at `pc`, made of the bytes at the indices in memory `bytes`,
+    /// returning whether it was just found to overlap an instruction executed before
+    fn record(&mut self, pc: u16, bytes: &[usize]) -> bool {
+        let (start, operands) = bytes.split_first().expect("Instructions have an opcode");
+        let misaligned = Self::is_set(&self.operands, *start)
+            || operands.iter().any(|&operand| Self::is_set(&self.starts, operand));
+
+        Self::set(&mut self.starts, *start);
+        for &operand in operands {
+            Self::set(&mut self.operands, operand);
+        }
+
+        if misaligned && !self.misaligned.contains(&pc) {
+            self.misaligned.push(pc);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Write to RAM, as recorded by the write log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    /// Address of the instruction doing the write
+    pub pc: u16,
+    pub address: u16,
+    pub value: u8,
+}
+
+/// What the write log does with new writes once full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogPolicy {
+    /// Keep the most recent writes
+    DropOldest,
+    /// Keep the first writes, ignoring the following ones
+    Stop,
+}
+
+/// What the CPU does when execution runs past $ffff and wraps back to $0000,
+/// which usually means the program is running away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcWrapPolicy {
+    /// Keep going at $0000
+    Ignore,
+    /// Print a warning on stderr, then keep going
+    Warn,
+    /// Stop with `Error::PcWrap` after executing the instruction, with PC back at $0000
+    Break,
+}
+
+/// Writes recorded by the CPU, up to a maximum number so long runs don't run out of memory
+#[derive(Clone)]
+struct WriteLog {
+    writes: VecDeque<MemoryWrite>,
+    capacity: usize,
+    policy: LogPolicy,
+}
+
+impl WriteLog {
+    fn record(&mut self, write: MemoryWrite) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.writes.len() >= self.capacity {
+            match self.policy {
+                LogPolicy::DropOldest => {
+                    self.writes.pop_front();
+                }
+                LogPolicy::Stop => return,
+            }
+        }
+        self.writes.push_back(write);
+    }
+}
+
+/// Why `SpaceInvaders::run_to_breakpoint` stopped
+#[derive(Debug)]
+pub enum StopReason {
+    /// PC reached a breakpoint, before executing the instruction there
+    Breakpoint(u16),
+    /// The last instruction wrote to a watched address
+    Watchpoint(u16),
+    /// The CPU is halted, which only an interrupt ends
+    Halted,
+    /// The maximum number of instructions was executed
+    InstructionCap,
+    /// Emulation failed
+    Error(Error),
+}
+
+/// How much state a reset clears
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Power-on: registers and RAM are cleared, and the flags set to their power-on state
+    Cold,
+    /// Reset line: only PC and the interrupt state are reset, registers and RAM are kept
+    Warm,
+}
+
+/// Snapshot of the registers and flags, to compare with the reference CPU
+#[cfg(any(test, all(feature = "cpu_compare", debug_assertions)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub flags: Flags,
+}
+
+#[cfg(any(test, all(feature = "cpu_compare", debug_assertions)))]
+impl Registers {
+    /// Describes every register and flag differing in `actual`, taking `self` as the expected
+    /// state, such as "A: expected 0x3c, got 0x3d"
+    pub fn diff(&self, actual: &Registers) -> Vec<String> {
+        let bytes = [
+            ("A", self.a, actual.a),
+            ("B", self.b, actual.b),
+            ("C", self.c, actual.c),
+            ("D", self.d, actual.d),
+            ("E", self.e, actual.e),
+            ("H", self.h, actual.h),
+            ("L", self.l, actual.l),
+        ];
+        let words = [("SP", self.sp, actual.sp), ("PC", self.pc, actual.pc)];
+        let flags = [
+            ("sign", self.flags.sign, actual.flags.sign),
+            ("zero", self.flags.zero, actual.flags.zero),
+            ("aux carry", self.flags.aux_carry, actual.flags.aux_carry),
+            ("parity", self.flags.parity, actual.flags.parity),
+            ("carry", self.flags.carry, actual.flags.carry),
+        ];
+
+        let mut differences = Vec::new();
+        for &(name, expected, actual) in bytes.iter().filter(|&&(_, expected, actual)| expected != actual) {
+            differences.push(format!("{name}: expected {expected:#04x}, got {actual:#04x}"));
+        }
+        for &(name, expected, actual) in words.iter().filter(|&&(_, expected, actual)| expected != actual) {
+            differences.push(format!("{name}: expected {expected:#06x}, got {actual:#06x}"));
+        }
+        for &(name, expected, actual) in flags.iter().filter(|&&(_, expected, actual)| expected != actual) {
+            differences.push(format!("{name} flag: expected {expected}, got {actual}"));
+        }
+
+        differences
+    }
+}
+
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)] // Independent states of the CPU, and settings
 pub struct CpuState {
     a: u8,
     bc: RegisterPair,
@@ -52,26 +260,71 @@ pub struct CpuState {
     hl: RegisterPair,
     sp: u16,
     pc: u16,
-    memory: [u8; MEMORY_SIZE],
+    memory: Memory,
     flags: Flags,
     interrupts_enabled: bool,
+    /// Set by EI until the next instruction is executed, as EI takes effect after it
+    interrupt_delay: bool,
+    /// Interrupt requested during the EI delay, serviced when the delay is over
+    pending_interrupt: Option<u16>,
+    /// Set by HLT, until the next interrupt
+    halted: bool,
+    /// Memory below this address is read-only
+    rom_end: u16,
+    /// Whether writing to ROM is an error
+    strict: bool,
+    /// One bit per address, set once the address was loaded or written
+    written: Vec<u64>,
+    /// Whether to warn when PC enters memory that was never written
+    warn_unwritten: bool,
+    /// Where PC first entered memory that was never written, so a NOP slide only warns once
+    unwritten_entry: Option<u16>,
+    /// Instructions executed by `emulate`, not counting the time spent halted
+    instruction_count: u64,
+    /// Writes to RAM, when logging them
+    write_log: Option<WriteLog>,
+    /// Cycles taken by `emulate` since the last reset, halted time included
+    total_cycles: u64,
+    /// Instruction boundaries, when checking for misaligned execution
+    alignment_check: Option<AlignmentCheck>,
+    /// Extra cycles taken to acknowledge an interrupt
+    interrupt_latency: u64,
+    /// Cycles of the interrupts serviced since the last instruction, their RST and latency,
+    /// charged to the next one
+    latency_due: u64,
+    /// Interrupts serviced since the CPU was created
+    interrupt_count: u64,
+    /// Addresses `SpaceInvaders::run_to_breakpoint` stops at
+    breakpoints: Vec<u16>,
+    /// Addresses `SpaceInvaders::run_to_breakpoint` stops after writing to
+    watchpoints: Vec<u16>,
+    /// Watched address written by the last instruction
+    watchpoint_hit: Option<u16>,
+    /// What to do when execution runs past $ffff
+    pc_wrap_policy: PcWrapPolicy,
+    /// Interrupts being handled, innermost last, with the address of their return address on the stack
+    active_interrupts: Vec<(u16, u16)>,
+    /// Physical address of each byte placed by `execute`, and whether the instruction overwrote it
+    injected: Vec<(usize, bool)>,
 }
 
 impl fmt::Debug for CpuState {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        writeln!(f, "{:>4} {:>4} {:>4} {:>4} {:>4} {:>4} {:>4}",
-                 "a", "bc", "de", "hl", "pc", "sp", "flags")?;
-
-        write!(f,
-               "{:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {}",
-               self.a,
-               self.bc(),
-               self.de(),
-               self.hl(),
-               self.pc,
-               self.sp,
-               self.flags,
-        )
+        f.debug_struct("CpuState")
+         .field("a", &format_args!("{:02x}", self.a))
+         .field("b", &format_args!("{:02x}", self.b()))
+         .field("c", &format_args!("{:02x}", self.c()))
+         .field("d", &format_args!("{:02x}", self.d()))
+         .field("e", &format_args!("{:02x}", self.e()))
+         .field("h", &format_args!("{:02x}", self.h()))
+         .field("l", &format_args!("{:02x}", self.l()))
+         .field("sp", &format_args!("{:04x}", self.sp))
+         .field("pc", &format_args!("{:04x}", self.pc))
+         .field("flags", &self.flags)
+         .field("interrupts_enabled", &self.interrupts_enabled())
+         .field("halted", &self.halted)
+         .field("current_interrupt", &self.current_interrupt())
+         .finish_non_exhaustive()
     }
 }
 
@@ -107,33 +360,243 @@ impl std::default::Default for CpuState {
             hl: RegisterPair::new(),
             sp: 0,
             pc: 0,
-            memory: [0; MEMORY_SIZE],
-            flags: Flags {
-                zero: false,
-                sign: false,
-                parity: false,
-                carry: false,
-                aux_carry: false,
-            },
+            #[allow(clippy::useless_conversion)] // Boxes the memory with `boxed_memory`
+            memory: [0; MEMORY_SIZE].into(),
+            flags: Flags::POWER_ON,
             interrupts_enabled: false,
+            interrupt_delay: false,
+            pending_interrupt: None,
+            halted: false,
+            rom_end: 0x2000,
+            strict: false,
+            written: vec![0; MEMORY_SIZE / 64],
+            warn_unwritten: false,
+            unwritten_entry: None,
+            instruction_count: 0,
+            write_log: None,
+            total_cycles: 0,
+            alignment_check: None,
+            interrupt_latency: 0,
+            latency_due: 0,
+            interrupt_count: 0,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            pc_wrap_policy: PcWrapPolicy::Ignore,
+            active_interrupts: Vec::new(),
+            injected: Vec::new(),
         }
     }
 }
 
+/// Loads a ROM at address 0, starting execution there
+impl TryFrom<&[u8]> for CpuState {
+    type Error = Error;
+
+    fn try_from(rom: &[u8]) -> Result<Self, Error> {
+        Self::from_rom(rom, 0, 0)
+    }
+}
+
 impl CpuState {
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn from_rom(rom: &[u8], rom_start: usize, pc_start: u16) -> Self {
+    pub fn from_rom(rom: impl AsRef<[u8]>, rom_start: usize, pc_start: u16) -> Result<Self, Error> {
         let mut new = Self::new();
-        new.load_rom(rom, rom_start);
+        new.load_rom(rom.as_ref(), rom_start)?;
         new.pc = pc_start;
-        new
+        Ok(new)
     }
 
-    pub fn load_rom(&mut self, rom: &[u8], rom_start: usize) {
+    pub fn load_rom(&mut self, rom: &[u8], rom_start: usize) -> Result<(), Error> {
+        if rom_start + rom.len() > MEMORY_SIZE {
+            return Err(Error::RomTooLarge { size: rom.len(), start: rom_start });
+        }
+
         self.memory[rom_start..rom_start + rom.len()].clone_from_slice(rom);
+        self.mark_written(rom_start, rom.len());
+        Ok(())
+    }
+
+    /// Writes `bytes` to memory at `address`, ignoring the ROM guard.
+    /// Debug operation for placing programs and data in memory.
+    pub fn set_memory_slice(&mut self, address: u16, bytes: &[u8]) -> Result<(), Error> {
+        let start = address as usize;
+        if start + bytes.len() > MEMORY_SIZE {
+            return Err(Error::OutOfMemory { address, size: bytes.len() });
+        }
+
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        self.mark_written(start, bytes.len());
+        Ok(())
+    }
+
+    /// Jumps to address 0 with interrupts disabled, and starts counting total cycles and instructions over.
+    /// A cold reset also clears registers, flags and memory outside of ROM, which counts as never written again.
+    pub fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            self.a = 0;
+            *self.bc_mut() = 0;
+            *self.de_mut() = 0;
+            *self.hl_mut() = 0;
+            self.sp = 0;
+            self.flags = Flags::POWER_ON;
+
+            let rom_end = (self.rom_end as usize).min(MEMORY_SIZE);
+            for byte in &mut self.memory[rom_end..] {
+                *byte = 0;
+            }
+            self.clear_written(rom_end, MEMORY_SIZE - rom_end);
+            self.unwritten_entry = None;
+        }
+
+        self.pc = 0;
+        self.interrupts_enabled = false;
+        self.interrupt_delay = false;
+        self.pending_interrupt = None;
+        self.halted = false;
+        self.total_cycles = 0;
+        self.instruction_count = 0;
+        self.latency_due = 0;
+        self.active_interrupts.clear();
+    }
+
+    /// Serializes registers, flags and memory
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(STATE_SIZE);
+
+        state.push(self.a);
+        for pair in &[self.bc(), self.de(), self.hl(), self.sp, self.pc] {
+            state.extend_from_slice(&pair.to_le_bytes());
+        }
+        state.push(self.flags.psw());
+        state.push(
+            u8::from(self.interrupts_enabled)
+                | u8::from(self.halted) << 1
+                | u8::from(self.interrupt_delay) << 2
+                | self.pending_interrupt.map_or(0, |interrupt_num| 0b1000 | (interrupt_num as u8) << 4),
+        );
+        state.extend_from_slice(&self.memory[..]);
+
+        state
+    }
+
+    /// Restores a state produced by `save_state`
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        if state.len() != STATE_SIZE {
+            return Err(Error::InvalidState(format!(
+                "{} bytes, expected {}",
+                state.len(),
+                STATE_SIZE
+            )));
+        }
+
+        let read_pair = |index: usize| u16::from_le_bytes([state[1 + 2 * index], state[2 + 2 * index]]);
+
+        self.a = state[0];
+        *self.bc_mut() = read_pair(0);
+        *self.de_mut() = read_pair(1);
+        *self.hl_mut() = read_pair(2);
+        self.sp = read_pair(3);
+        self.pc = read_pair(4);
+        self.flags.set_psw(state[11]);
+        self.interrupts_enabled = state[12] & 1 != 0;
+        self.halted = state[12] & 2 != 0;
+        self.interrupt_delay = state[12] & 4 != 0;
+        self.pending_interrupt = if state[12] & 8 != 0 {
+            Some(u16::from(state[12] >> 4 & 0b111))
+        } else {
+            None
+        };
+        self.memory.clone_from_slice(&state[13..]);
+        // Which bytes were written isn't saved, consider them all valid
+        self.mark_written(0, MEMORY_SIZE);
+        // Neither are the interrupt handlers running, so none are tracked until the next interrupt
+        self.active_interrupts.clear();
+
+        Ok(())
+    }
+
+    /// Sets the end of the read-only ROM area. Use 0 to make all memory writable.
+    pub fn set_rom_end(&mut self, rom_end: u16) {
+        self.rom_end = rom_end;
+    }
+
+    /// In strict mode, writing to ROM is an error instead of being ignored
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Extra cycles taken to acknowledge an interrupt, 0 by default. They are added to the cycles
+    /// of the handler's first instruction along with those of the RST, on top of the current
+    /// instruction completing first.
+    pub fn set_interrupt_latency(&mut self, cycles: u64) {
+        self.interrupt_latency = cycles;
+    }
+
+    /// What to do when an instruction runs past $ffff, back to $0000. Ignored by default.
+    pub fn set_pc_wrap_policy(&mut self, policy: PcWrapPolicy) {
+        self.pc_wrap_policy = policy;
+    }
+
+    /// Prints a warning the first time PC enters memory that was never loaded or written,
+    /// which usually means the program crashed and is running through zeroed memory
+    pub fn set_warn_unwritten(&mut self, warn_unwritten: bool) {
+        self.warn_unwritten = warn_unwritten;
+    }
+
+    /// Starts recording writes to RAM, keeping at most `capacity` of them according to `policy`,
+    /// and forgetting any previous writes. `None` stops recording them.
+    pub fn set_write_log(&mut self, log: Option<(usize, LogPolicy)>) {
+        self.write_log = log.map(|(capacity, policy)| WriteLog {
+            writes: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+        });
+    }
+
+    /// Checks whether execution lands inside an instruction executed before, such as a jump
+    /// into the operand of an LXI, which misaligns the disassembly. Each such instruction is
+    /// reported once on stderr and recorded. Self-modifying code also triggers it.
+    /// Off by default, as it slows down emulation.
+    pub fn set_check_alignment(&mut self, check: bool) {
+        self.alignment_check = if check {
+            Some(AlignmentCheck {
+                starts: vec![0; MEMORY_SIZE / 64],
+                operands: vec![0; MEMORY_SIZE / 64],
+                misaligned: Vec::new(),
+            })
+        } else {
+            None
+        };
+    }
+
+    /// Addresses of the instructions found to start inside another one since the check was enabled
+    pub fn misaligned_instructions(&self) -> &[u16] {
+        self.alignment_check.as_ref().map_or(&[], |check| &check.misaligned[..])
+    }
+
+    /// Writes to RAM recorded since the write log was enabled, oldest first
+    pub fn write_log(&self) -> impl Iterator<Item = &MemoryWrite> + '_ {
+        self.write_log.iter().flat_map(|log| log.writes.iter())
+    }
+
+    #[cfg(any(test, all(feature = "cpu_compare", debug_assertions)))]
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            b: self.b(),
+            c: self.c(),
+            d: self.d(),
+            e: self.e(),
+            h: self.h(),
+            l: self.l(),
+            sp: self.sp,
+            pc: self.pc,
+            flags: self.flags,
+        }
     }
 
     pub fn pc(&self) -> u16 {
@@ -192,24 +655,255 @@ impl CpuState {
         self.read_byte(self.hl())
     }
 
+    /// Whether the CPU is halted, waiting for an interrupt
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
     pub fn flags(&self) -> &Flags {
         &self.flags
     }
 
     pub fn memory(&self) -> &[u8] {
-        &self.memory
+        &self.memory[..]
+    }
+
+    /// Reads the byte at `address` like the CPU does, addresses past the end of memory included
+    pub fn peek(&self, address: u16) -> u8 {
+        self.read_byte(address)
+    }
+
+    /// Cycles taken since the CPU was created or last reset, for timestamping events.
+    /// Only ever increases in between.
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// Reads bytes from `address` up to `terminator` excluded, such as the '$' ending CP/M strings,
+    /// or up to the end of memory if there is none. Each byte is read as a character.
+    pub fn read_string(&self, address: u16, terminator: u8) -> String {
+        self.memory
+            .get(address as usize..)
+            .unwrap_or(&[])
+            .iter()
+            .take_while(|&&byte| byte != terminator)
+            .map(|&byte| char::from(byte))
+            .collect()
+    }
+
+    /// All of memory, writable. Test operation for setting up memory in bulk,
+    /// which ignores the ROM guard like `set_memory_slice`.
+    /// Since any byte may be written, all of memory counts as written afterwards.
+    #[cfg(test)]
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.mark_written(0, MEMORY_SIZE);
+        &mut self.memory[..]
+    }
+
+    /// Number of instructions executed since the CPU was created or last reset.
+    /// Interrupts and halted time don't count.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// Reads `depth` words from SP upward, top of the stack first.
+    /// Addresses past the end of memory read the RAM mirror, like any other read.
+    pub fn stack(&self, depth: usize) -> Vec<u16> {
+        let word = |index: usize| self.read_bytes(self.sp.wrapping_add(2 * index as u16));
+
+        (0..depth).map(word).collect()
+    }
+
+    /// Whether an interrupt would be serviced right away: false after DI,
+    /// and after EI until the instruction following it has executed
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled && !self.interrupt_delay
+    }
+
+    /// Interrupt whose handler is running, the innermost one when interrupts are nested.
+    /// A handler is entered when its interrupt is serviced, and left by the RET using the return
+    /// address pushed by the interrupt, or any RET further up the stack. Handlers leaving another way,
+    /// e.g. by reloading SP, are only left once a RET goes past their return address.
+    pub fn current_interrupt(&self) -> Option<u16> {
+        self.active_interrupts.last().map(|&(interrupt_num, _)| interrupt_num)
+    }
+
+    /// Interrupts serviced since the CPU was created, including those latched during the EI delay
+    pub fn interrupt_count(&self) -> u64 {
+        self.interrupt_count
     }
 
-    pub fn interrupt(&mut self, interrupt_num: u16) {
-        if self.interrupts_enabled {
-            self.push(self.pc);
-            self.pc = 8 * interrupt_num;
-            self.interrupts_enabled = false;
+    /// Whether an interrupt requested during the EI delay is waiting to be serviced
+    pub fn interrupt_pending(&self) -> bool {
+        self.pending_interrupt.is_some()
+    }
+
+    /// Services interrupt `interrupt_num` if interrupts are enabled.
+    /// During the EI delay, the interrupt is instead serviced before the next instruction.
+    /// Returns whether the interrupt was serviced immediately, or an error if it isn't one of RST 0 to 7.
+    pub fn interrupt(&mut self, interrupt_num: u16) -> Result<bool, Error> {
+        if interrupt_num > MAX_INTERRUPT {
+            return Err(Error::InvalidInterrupt(interrupt_num));
+        }
+        if !self.interrupts_enabled {
+            return Ok(false);
+        }
+
+        if self.interrupt_delay {
+            self.pending_interrupt = Some(interrupt_num);
+            return Ok(false);
+        }
+
+        self.service_interrupt(interrupt_num)?;
+        Ok(true)
+    }
+
+    /// Memory address accessed by the opcode `op` if it were executed at PC with the current registers:
+    /// HL for M operands, BC or DE for LDAX/STAX, SP for XTHL, and the immediate address of
+    /// direct addressing. `None` for opcodes which don't access memory, stack pushes and pops aside.
+    pub fn effective_address(&self, op: u8) -> Option<u16> {
+        match op {
+            // HLT sits where MOV M, M would be
+            0x76 => None,
+            // MOV r, M / MOV M, r, INR/DCR/MVI M, arithmetic and logic with M
+            0x46 | 0x4e | 0x56 | 0x5e | 0x66 | 0x6e | 0x7e | 0x70..=0x77 | 0x34 | 0x35 | 0x36 |
+            0x86 | 0x8e | 0x96 | 0x9e | 0xa6 | 0xae | 0xb6 | 0xbe => Some(self.hl()),
+            // LDAX/STAX B
+            0x02 | 0x0a => Some(self.bc()),
+            // LDAX/STAX D
+            0x12 | 0x1a => Some(self.de()),
+            // SHLD, LHLD, STA, LDA
+            0x22 | 0x2a | 0x32 | 0x3a => Some(self.read_bytes_immediate()),
+            // XTHL
+            0xe3 => Some(self.sp),
+            _ => None,
+        }
+    }
+
+    /// Sets the addresses `is_at_breakpoint` checks, replacing the previous ones
+    pub fn set_breakpoints(&mut self, breakpoints: Vec<u16>) {
+        self.breakpoints = breakpoints;
+    }
+
+    /// Sets the addresses whose writes `take_watchpoint_hit` reports, replacing the previous ones.
+    /// Writes through the RAM mirror hit the watchpoints of the RAM they mirror, and vice versa.
+    pub fn set_watchpoints(&mut self, watchpoints: Vec<u16>) {
+        self.watchpoints = watchpoints;
+    }
+
+    /// Watchpoint written to since the last call, checked after each instruction
+    pub fn take_watchpoint_hit(&mut self) -> Option<u16> {
+        self.watchpoint_hit.take()
+    }
+
+    /// Whether PC is on a breakpoint
+    pub fn is_at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.pc)
+    }
+
+    /// Executes the instruction in `bytes` as if it was in memory at PC, then restores the bytes
+    /// the instruction didn't overwrite. PC is advanced or jumps like for any instruction.
+    /// A pending interrupt is serviced first, and the instruction placed at the start of its handler.
+    /// Only the opcode's length is used: extra bytes are ignored, missing ones are read from memory.
+    #[cfg(test)]
+    pub fn execute(&mut self, bytes: &[u8], io_state: &mut dyn IOState) -> Result<u64, Error> {
+        let bytes = match bytes.first() {
+            Some(&op_code) => &bytes[..bytes.len().min(Self::opcode_length(op_code) as usize)],
+            None => return Ok(0),
+        };
+
+        // Otherwise `emulate` would service it, then run the handler's instruction instead
+        self.service_pending_interrupt()?;
+
+        // Placed where `emulate` reads them, wrapping around memory like PC
+        let mut originals = Vec::with_capacity(bytes.len());
+        for (offset, &byte) in (0..).zip(bytes) {
+            let physical_address = self.physical_address(self.pc.wrapping_add(offset));
+            originals.push((self.memory[physical_address], self.is_physically_written(physical_address)));
+            self.injected.push((physical_address, false));
+            self.memory[physical_address] = byte;
+            self.mark_written(physical_address, 1);
         }
+
+        let cycles = self.emulate(io_state);
+
+        for ((physical_address, overwritten), (byte, written)) in mem::take(&mut self.injected).into_iter().zip(originals) {
+            if !overwritten {
+                self.memory[physical_address] = byte;
+                if !written {
+                    self.clear_written(physical_address, 1);
+                }
+            }
+        }
+
+        cycles
+    }
+
+    /// Returns the bytes and name of the instruction at PC
+    pub fn disassemble_next(&self) -> (Vec<u8>, String) {
+        let length = Self::opcode_length(self.read_byte(self.pc));
+        let bytes = (0..length)
+            .map(|offset| self.read_byte(self.pc.wrapping_add(offset)))
+            .collect();
+
+        (bytes, self.next_opcode())
+    }
+
+    /// Lazily disassembles the instructions from `start` (inclusive) to `end` (exclusive),
+    /// yielding each instruction's address and name
+    pub fn instructions(&self, start: u16, end: u16) -> impl Iterator<Item = (u16, String)> + '_ {
+        let mut address = start;
+
+        std::iter::from_fn(move || {
+            if address >= end {
+                return None;
+            }
+
+            let instruction = (address, self.op_name(address));
+            address = address
+                .checked_add(Self::opcode_length(self.read_byte(address)))
+                .unwrap_or(end);
+
+            Some(instruction)
+        })
+    }
+
+    /// Disassembles the instructions from `start` to `end` as source for an 8080 assembler,
+    /// with the address of each instruction as a comment. Undocumented opcodes are emitted
+    /// as `DB`, since assemblers would encode their mnemonic as the documented opcode.
+    /// With `cycles`, each line also shows the cycles the instruction takes,
+    /// both when not taken and when taken for conditional calls and returns, e.g. `11/17`.
+    pub fn listing(&self, start: u16, end: u16, cycles: bool) -> String {
+        let mut listing = format!("\tORG ${start:04x}\n");
+
+        listing.extend(self.instructions(start, end).map(|(address, name)| {
+            let op_code = self.read_byte(address);
+            let line = if Self::is_undocumented(op_code) {
+                let bytes = (0..Self::opcode_length(op_code))
+                    .map(|offset| format!("${:02x}", self.read_byte(address.wrapping_add(offset))))
+                    .collect::<Vec<_>>();
+                format!("DB {}", bytes.join(", "))
+            } else {
+                name
+            };
+
+            if cycles {
+                format!("\t{}\t; ${:04x}, {} cycles\n", line, address, Self::cycle_annotation(op_code))
+            } else {
+                format!("\t{line}\t; ${address:04x}\n")
+            }
+        }));
+
+        listing
     }
 
     // Private
 
+    /// Opcodes not in Intel's documentation, duplicating a documented instruction
+    fn is_undocumented(op_code: u8) -> bool {
+        matches!(op_code, 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xcb | 0xd9 | 0xdd | 0xed | 0xfd)
+    }
+
     fn set_af(&mut self, value: u16) {
         self.flags.set_psw(value as u8);
         self.a = (value >> 8) as u8;
@@ -251,80 +945,196 @@ impl CpuState {
         self.hl.lsb_mut()
     }
 
-    fn m_mut(&mut self) -> &mut u8 {
-        self.byte_mut(self.hl())
+    fn set_m(&mut self, value: u8) -> Result<(), Error> {
+        self.write_byte(self.hl(), value)
     }
 
-    fn byte_mut(&mut self, address: u16) -> &mut u8 {
-        if address < 0x2000 {
-            panic!("Trying to write to ROM at address ${:04x}", address)
+    /// Register selected by a 3-bit opcode field: B, C, D, E, H, L, M or A
+    fn register(&self, index: u8) -> u8 {
+        match index {
+            0 => self.b(),
+            1 => self.c(),
+            2 => self.d(),
+            3 => self.e(),
+            4 => self.h(),
+            5 => self.l(),
+            M_INDEX => self.m(),
+            _ => self.a,
         }
-        &mut self.memory[address as usize]
+    }
+
+    fn set_register(&mut self, index: u8, value: u8) -> Result<(), Error> {
+        match index {
+            0 => *self.b_mut() = value,
+            1 => *self.c_mut() = value,
+            2 => *self.d_mut() = value,
+            3 => *self.e_mut() = value,
+            4 => *self.h_mut() = value,
+            5 => *self.l_mut() = value,
+            M_INDEX => self.set_m(value)?,
+            _ => self.a = value,
+        }
+
+        Ok(())
     }
 
     /// Reads the byte at the specified address
     fn read_byte(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+        self.memory[self.physical_address(address)]
+    }
+
+    /// Index in memory of `address`. Addresses past the end of memory mirror the RAM after ROM,
+    /// like on the Space Invaders board where $4000 onwards mirrors $2000-$3fff.
+    fn physical_address(&self, address: u16) -> usize {
+        let address = address as usize;
+        if address < MEMORY_SIZE {
+            return address;
+        }
+
+        let ram_start = (self.rom_end as usize).min(MEMORY_SIZE);
+        match MEMORY_SIZE - ram_start {
+            // Everything is ROM, fold onto the whole of memory
+            0 => address % MEMORY_SIZE,
+            ram_size => ram_start + (address - MEMORY_SIZE) % ram_size,
+        }
     }
 
     /// Reads two bytes starting at the specified address
     fn read_bytes(&self, address: u16) -> u16 {
-        ((self.read_byte(address + 1) as u16) << 8) | self.read_byte(address) as u16
+        (u16::from(self.read_byte(address.wrapping_add(1))) << 8) | u16::from(self.read_byte(address))
     }
 
     /// Reads the byte following the current instruction
     fn read_byte_immediate(&self) -> u8 {
-        self.read_byte(self.pc + 1)
+        self.read_byte(self.pc.wrapping_add(1))
     }
 
     /// Reads two bytes following the current instruction
     fn read_bytes_immediate(&self) -> u16 {
-        self.read_bytes(self.pc + 1)
+        self.read_bytes(self.pc.wrapping_add(1))
+    }
+
+    /// Writes a byte to memory.
+    /// Writes to ROM are an error in strict mode, and are ignored otherwise.
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        let physical_address = self.physical_address(address);
+        if physical_address < self.rom_end as usize {
+            return if self.strict {
+                Err(Error::RomWrite(address))
+            } else {
+                Ok(())
+            };
+        }
+
+        self.memory[physical_address] = value;
+        self.mark_written(physical_address, 1);
+        if let Some((_, overwritten)) = self.injected.iter_mut().find(|(injected, _)| *injected == physical_address) {
+            *overwritten = true;
+        }
+        let watched = self.watchpoints.iter()
+                                      .copied()
+                                      .find(|&watchpoint| self.physical_address(watchpoint) == physical_address);
+        if watched.is_some() {
+            self.watchpoint_hit = watched;
+        }
+        if let Some(log) = &mut self.write_log {
+            log.record(MemoryWrite { pc: self.pc, address, value });
+        }
+        Ok(())
+    }
+
+    fn mark_written(&mut self, start: usize, len: usize) {
+        for address in start..start + len {
+            self.written[address / 64] |= 1 << (address % 64);
+        }
+    }
+
+    fn clear_written(&mut self, start: usize, len: usize) {
+        for address in start..start + len {
+            self.written[address / 64] &= !(1 << (address % 64));
+        }
+    }
+
+    fn is_written(&self, address: u16) -> bool {
+        self.is_physically_written(self.physical_address(address))
     }
 
-    fn write_byte(&mut self, address: u16, value: u8) {
-        *self.byte_mut(address) = value
+    fn is_physically_written(&self, physical_address: usize) -> bool {
+        self.written[physical_address / 64] & 1 << (physical_address % 64) != 0
     }
 
-    fn write_bytes(&mut self, address: u16, value: u16) {
-        self.write_byte(address, value as u8);
-        self.write_byte(address + 1, (value >> 8) as u8);
+    /// Writes a little endian 16-bit value.
+    /// In strict mode, nothing is written if either byte is in ROM.
+    fn write_bytes(&mut self, address: u16, value: u16) -> Result<(), Error> {
+        let next_address = address.wrapping_add(1);
+
+        if self.strict {
+            let in_rom = |&&address: &&u16| self.physical_address(address) < self.rom_end as usize;
+            if let Some(&rom_address) = [address, next_address].iter().find(in_rom) {
+                return Err(Error::RomWrite(rom_address));
+            }
+        }
+
+        self.write_byte(address, value as u8)?;
+        self.write_byte(next_address, (value >> 8) as u8)
     }
 
     fn jmp(&mut self, adr: u16) {
         self.pc = adr;
     }
 
-    fn call(&mut self, adr: u16) {
-        self.push(self.pc + 3);
+    fn call(&mut self, adr: u16) -> Result<(), Error> {
+        self.push(self.pc.wrapping_add(3))?;
         self.pc = adr;
+        Ok(())
     }
 
     fn ret(&mut self) {
+        // Returning through the return address pushed by an interrupt, or past it, leaves its handler
+        while self.active_interrupts.last().map_or(false, |&(_, return_sp)| self.sp >= return_sp) {
+            self.active_interrupts.pop();
+        }
         self.pc = self.pop();
     }
 
+    /// Pops a word off the stack. SP wraps around the address space like on the 8080.
     fn pop(&mut self) -> u16 {
-        self.sp += 2;
-        self.read_bytes(self.sp - 2)
+        let value = self.read_bytes(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
+    }
+
+    /// Pushes a word on the stack. SP only moves once the word is written,
+    /// so a failed write leaves the stack as it was.
+    fn push(&mut self, value: u16) -> Result<(), Error> {
+        let sp = self.sp.wrapping_sub(2);
+        self.write_bytes(sp, value)?;
+        self.sp = sp;
+        Ok(())
     }
 
-    fn push(&mut self, value: u16) {
-        self.sp -= 2;
-        self.write_bytes(self.sp, value);
+    /// Increments a register pair. Like all 16-bit increments, doesn't affect flags.
+    fn inx(pair: &mut u16) {
+        *pair = pair.wrapping_add(1);
+    }
+
+    /// Decrements a register pair. Like all 16-bit decrements, doesn't affect flags.
+    fn dcx(pair: &mut u16) {
+        *pair = pair.wrapping_sub(1);
     }
 
     /// Increments `operand`
     fn inr(&mut self, operand: u8) -> u8 {
         let result = operand.wrapping_add(1);
-        self.flags.set_all_but_carry(result);
+        self.flags.set_all_but_carry(result, (operand & 0xf) + 1);
         result
     }
 
     /// Decrements `operand`
     fn dcr(&mut self, operand: u8) -> u8 {
         let result = operand.wrapping_sub(1);
-        self.flags.set_all_but_carry(result);
+        // Subtracting 1 is adding 0xff, which carries out of the low nibble unless it is 0
+        self.flags.set_all_but_carry(result, (operand & 0xf) + 0xf);
         result
     }
 
@@ -338,7 +1148,8 @@ impl CpuState {
     /// Add `operand` + carry to A
     fn adc(&mut self, operand: u8) {
         let result = (self.a as u16).wrapping_add(operand as u16).wrapping_add(self.flags.carry as u16);
-        self.flags.set_all(result, (self.a & 0xf).wrapping_add(operand.wrapping_add(self.flags.carry as u8) & 0xf));
+        // The carry in counts toward the low nibble, even when the operand's low nibble is 0xf
+        self.flags.set_all(result, (self.a & 0xf) + (operand & 0xf) + u8::from(self.flags.carry));
         self.a = result as u8;
     }
 
@@ -351,28 +1162,40 @@ impl CpuState {
 
     /// Subtract `operand` from A with borrow
     fn sbb(&mut self, operand: u8) {
-        let result = (self.a as u16).wrapping_sub(operand as u16).wrapping_sub(self.flags.carry as u16);
-        self.flags.set_all(result, (self.a & 0xf).wrapping_sub(operand.wrapping_sub(self.flags.carry as u8) & 0xf));
+        let result = u16::from(self.a).wrapping_sub(u16::from(operand)).wrapping_sub(u16::from(self.flags.carry));
+        self.flags.set_all(result, (self.a & 0xf).wrapping_sub(operand & 0xf).wrapping_sub(u8::from(self.flags.carry)));
         self.a = result as u8;
     }
 
     /// Add `operand` to HL
     fn dad(&mut self, operand: u16) {
         let result = (self.hl() as u32).wrapping_add(operand as u32);
-        self.flags.set_carry(result as u16);
+        // Only carry out of bit 15 is affected
+        self.flags.carry = result > 0xffff;
         *self.hl_mut() = result as u16;
     }
 
-    /// Immediate bitwise AND A
-    fn ani(&mut self) {
-        self.a &= self.read_byte_immediate();
-        self.flags.set_all_but_aux_carry(self.a as u16);
+    /// Arithmetic or logic operation between A and `operand`, selected by bits 3 to 5 of its opcode,
+    /// shared by the register and immediate forms: ADD, ADC, SUB, SBB, ANA, XRA, ORA or CMP
+    fn arithmetic(&mut self, operation: u8, operand: u8) {
+        match operation {
+            0 => self.add(operand),
+            1 => self.adc(operand),
+            2 => self.sub(operand),
+            3 => self.sbb(operand),
+            4 => self.and(operand),
+            5 => self.xor(operand),
+            6 => self.or(operand),
+            _ => self.cmp(operand),
+        }
     }
 
-    /// Bitwise AND between A and `operand`
+    /// Bitwise AND between A and `operand`.
+    /// Unlike the 8085, the 8080 sets aux carry to the OR of bit 3 of both operands.
     fn and(&mut self, operand: u8) {
+        self.flags.aux_carry = (self.a | operand) & 0x08 != 0;
         self.a &= operand;
-        self.flags.set_all(self.a as u16, self.a);
+        self.flags.set_all_but_aux_carry(u16::from(self.a));
     }
 
     /// Bitwise OR between A and `operand`
@@ -394,76 +1217,139 @@ impl CpuState {
     }
 
     fn daa(&mut self) {
-        let mut result = self.a as u16;
-
-        let lsb = result & 0xf;
+        let lsb = self.a & 0xf;
+        let msb = self.a >> 4;
+        let mut correction = 0;
 
         if self.flags.aux_carry || lsb > 9 {
-            result += 6;
-
-            if result & 0xf < lsb {
-                self.flags.aux_carry = true;
-            }
+            correction |= 0x06;
         }
-
-        let lsb = result & 0xf;
-        let mut msb = (result >> 4) & 0xf;
-
-        if self.flags.carry || msb > 9 {
-            msb += 6;
+        // The high digit also overflows when the low digit correction carries into a 9
+        let carry = self.flags.carry || msb > 9 || (msb == 9 && lsb > 9);
+        if carry {
+            correction |= 0x60;
         }
 
-        let result = (msb << 4) | lsb;
-        self.flags.set_all_but_aux_carry(result);
+        let result = u16::from(self.a) + u16::from(correction);
+        self.flags.set_all(result, lsb + (correction & 0xf));
+        // Carry is set if the high digit was corrected, and never cleared
+        self.flags.carry = carry;
 
         self.a = result as u8;
     }
 
+    /// Calls the interrupt handler (RST `interrupt_num`)
+    fn service_interrupt(&mut self, interrupt_num: u16) -> Result<(), Error> {
+        self.push(self.pc)?;
+        self.active_interrupts.push((interrupt_num, self.sp));
+        self.pc = 8 * interrupt_num;
+        self.interrupts_enabled = false;
+        self.halted = false;
+        self.latency_due += u64::from(CYCLES[0xc7]) + self.interrupt_latency;
+        self.interrupt_count += 1;
+        Ok(())
+    }
+
     fn next_opcode(&self) -> String {
         self.op_name(self.pc)
     }
 
     /// Executes the next instruction.
     /// Advances PC appropriately, and returns the number of cycles taken.
-    pub fn emulate(&mut self, io_state: &mut IOState) -> u64 {
+    #[must_use = "the cycles taken drive the machine's timing"]
+    pub fn emulate(&mut self, io_state: &mut dyn IOState) -> Result<u64, Error> {
+        // The RST and latency of an interrupt are taken before the first instruction of its handler
+        let cycles = self.emulate_uncounted(io_state)? + mem::take(&mut self.latency_due);
+        self.total_cycles += cycles;
+        Ok(cycles)
+    }
+
+    /// Services the interrupt latched during the EI delay, once the delay is over
+    fn service_pending_interrupt(&mut self) -> Result<(), Error> {
+        if !self.interrupt_delay {
+            if let Some(interrupt_num) = self.pending_interrupt.take() {
+                self.service_interrupt(interrupt_num)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `emulate`, without adding to the total cycles
+    fn emulate_uncounted(&mut self, io_state: &mut dyn IOState) -> Result<u64, Error> {
+        self.service_pending_interrupt()?;
+        self.interrupt_delay = false;
+
+        // Idle until the next interrupt, still taking time so the interrupt comes
+        if self.halted {
+            return Ok(HALTED_CYCLES);
+        }
+
         let op_code = self.read_byte(self.pc);
 
+        self.instruction_count += 1;
+
+        if let Some(mut check) = self.alignment_check.take() {
+            // Mirrored addresses are the same instruction
+            let length = usize::from(Self::opcode_length(op_code));
+            let mut bytes = [0; 3];
+            for (offset, byte) in (0..).zip(&mut bytes[..length]) {
+                *byte = self.physical_address(self.pc.wrapping_add(offset));
+            }
+
+            if check.record(self.pc, &bytes[..length]) {
+                eprintln!("Instruction at ${:04x} overlaps an instruction executed before", self.pc);
+            }
+            self.alignment_check = Some(check);
+        }
+
+        if self.warn_unwritten && self.unwritten_entry.is_none() && !self.is_written(self.pc) {
+            eprintln!("Executing memory that was never written at ${:04x}", self.pc);
+            self.unwritten_entry = Some(self.pc);
+        }
+
         if cfg!(feature="logging") && self.pc != 0xada && self.pc != 0xadd && self.pc != 0xade {
-            println!("{}", self);
+            eprintln!("{self}");
         }
 
-        let (op_length, cycles) = match op_code {
-            // NOP
-            0x00 | 0x20 => (1, 4),
+        // Set by conditional calls and returns whose condition is met, as they take longer
+        let mut taken = false;
+        let op_length = match op_code {
+            // NOP, and its undocumented aliases
+            0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 1,
             // LXI B, D16
             0x01 => {
                 *self.bc_mut() = self.read_bytes_immediate();
-                (3, 10)
+                3
             }
             // STAX B
             0x02 => {
-                self.write_byte(self.bc(), self.a);
-                (1, 7)
+                self.write_byte(self.bc(), self.a)?;
+                1
             }
             // INX B
             0x03 => {
-                *self.bc_mut() = self.bc().wrapping_add(1);
-                (1, 5)
-            }
-            // INR B
-            0x04 => {
-                *self.b_mut() = self.inr(self.b());
-                (1, 5)
-            }
-            // DCR B
-            0x05 => {
-                *self.b_mut() = self.dcr(self.b());
-                (1, 5)
-            }
-            // MVI B, D8
-            0x06 => {
-                *self.b_mut() = self.read_byte_immediate();
-                (2, 7)
+                Self::inx(self.bc_mut());
+                1
+            }
+            // INR r
+            0x04 | 0x0c | 0x14 | 0x1c | 0x24 | 0x2c | 0x34 | 0x3c => {
+                let register = (op_code >> 3) & 0b111;
+                let result = self.inr(self.register(register));
+                self.set_register(register, result)?;
+                1
+            }
+            // DCR r
+            0x05 | 0x0d | 0x15 | 0x1d | 0x25 | 0x2d | 0x35 | 0x3d => {
+                let register = (op_code >> 3) & 0b111;
+                let result = self.dcr(self.register(register));
+                self.set_register(register, result)?;
+                1
+            }
+            // MVI r, D8
+            0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e => {
+                let register = (op_code >> 3) & 0b111;
+                self.set_register(register, self.read_byte_immediate())?;
+                2
             }
             // RLC
             0x07 => {
@@ -471,32 +1357,22 @@ impl CpuState {
                 self.a <<= 1;
                 self.a |= bit7 >> 7;
                 self.flags.carry = bit7 != 0;
-                (1, 4)
+                1
             }
             // DAD B
             0x09 => {
                 self.dad(self.bc());
-                (1, 10)
+                1
             }
             // LDAX B
             0x0a => {
                 self.a = self.read_byte(self.bc());
-                (1, 7)
-            }
-            // INR C
-            0x0c => {
-                *self.c_mut() = self.inr(self.c());
-                (1, 5)
+                1
             }
-            // DCR C
-            0x0d => {
-                *self.c_mut() = self.dcr(self.c());
-                (1, 5)
-            }
-            // MVI C, D8
-            0x0e => {
-                *self.c_mut() = self.read_byte_immediate();
-                (2, 7)
+            // DCX B
+            0x0b => {
+                Self::dcx(self.bc_mut());
+                1
             }
             // RRC
             0x0f => {
@@ -504,37 +1380,22 @@ impl CpuState {
                 self.a >>= 1;
                 self.a |= bit0 << 7;
                 self.flags.carry = bit0 != 0;
-                (1, 4)
+                1
             }
             // LXI D, D16
             0x11 => {
                 *self.de_mut() = self.read_bytes_immediate();
-                (3, 10)
+                3
             }
             // STAX D
             0x12 => {
-                self.write_byte(self.de(), self.a);
-                (1, 7)
+                self.write_byte(self.de(), self.a)?;
+                1
             }
             // INX D
             0x13 => {
-                *self.de_mut() = self.de().wrapping_add(1);
-                (1, 5)
-            }
-            // INR D
-            0x14 => {
-                *self.d_mut() = self.inr(self.d());
-                (1, 5)
-            }
-            // DCR D
-            0x15 => {
-                *self.d_mut() = self.dcr(self.d());
-                (1, 5)
-            }
-            // MVI D, D8
-            0x16 => {
-                *self.d_mut() = self.read_byte_immediate();
-                (2, 7)
+                Self::inx(self.de_mut());
+                1
             }
             // RAL
             0x17 => {
@@ -542,27 +1403,22 @@ impl CpuState {
                 self.a <<= 1;
                 self.a |= self.flags.carry as u8;
                 self.flags.carry = bit7 != 0;
-                (1, 4)
+                1
             }
             // DAD D
             0x19 => {
                 self.dad(self.de());
-                (1, 10)
+                1
             }
             // LDAX D
             0x1a => {
                 self.a = self.read_byte(self.de());
-                (1, 7)
+                1
             }
             // DCX D
             0x1b => {
-                *self.de_mut() = self.de().wrapping_sub(1);
-                (1, 5)
-            }
-            // MVI E, D8
-            0x1e => {
-                *self.e_mut() = self.read_byte_immediate();
-                (2, 7)
+                Self::dcx(self.de_mut());
+                1
             }
             // RAR
             0x1f => {
@@ -570,669 +1426,340 @@ impl CpuState {
                 self.a >>= 1;
                 if self.flags.carry { self.a |= 1 << 7; }
                 self.flags.carry = bit0 != 0;
-                (1, 4)
+                1
             }
             // LXI H, D16
             0x21 => {
                 *self.hl_mut() = self.read_bytes_immediate();
-                (3, 10)
+                3
             }
             // SHLD adr
             0x22 => {
-                self.write_bytes(self.read_bytes_immediate(), self.hl());
-                (3, 16)
+                self.write_bytes(self.read_bytes_immediate(), self.hl())?;
+                3
             }
             // INX H
             0x23 => {
-                *self.hl_mut() = self.hl().wrapping_add(1);
-                (1, 5)
-            }
-            // MVI H, D8
-            0x26 => {
-                *self.h_mut() = self.read_byte_immediate();
-                (2, 7)
+                Self::inx(self.hl_mut());
+                1
             }
             // DAA
             0x27 => {
                 self.daa();
-                (1, 4)
+                1
             }
             // DAD H
             0x29 => {
                 self.dad(self.hl());
-                (1, 10)
+                1
             }
             // LHLD adr
             0x2a => {
                 *self.hl_mut() = self.read_bytes(self.read_bytes_immediate());
-                (3, 16)
+                3
             }
             // DCX H
             0x2b => {
-                *self.hl_mut() = self.hl().wrapping_sub(1);
-                (1, 5)
-            }
-            // INR L
-            0x2c => {
-                *self.l_mut() = self.inr(self.l());
-                (1, 5)
-            }
-            // MVI L, D8
-            0x2e => {
-                *self.l_mut() = self.read_byte_immediate();
-                (2, 7)
+                Self::dcx(self.hl_mut());
+                1
             }
             // CMA
             0x2f => {
                 self.a = !self.a;
-                (1, 4)
+                1
             }
             // LXI SP, D16
             0x31 => {
                 self.sp = self.read_bytes_immediate();
-                (3, 10)
+                3
             }
             // STA adr
             0x32 => {
-                self.write_byte(self.read_bytes_immediate(), self.a);
-                (3, 13)
-            }
-            // INR M
-            0x34 => {
-                *self.m_mut() = self.inr(self.m());
-                (1, 10)
+                self.write_byte(self.read_bytes_immediate(), self.a)?;
+                3
             }
-            // DCR M
-            0x35 => {
-                *self.m_mut() = self.dcr(self.m());
-                (1, 10)
-            }
-            // MVI M, D8
-            0x36 => {
-                *self.m_mut() = self.read_byte_immediate();
-                (2, 10)
+            // INX SP
+            0x33 => {
+                Self::inx(&mut self.sp);
+                1
             }
             // STC
             0x37 => {
                 self.flags.carry = true;
-                (1, 4)
+                1
+            }
+            // DAD SP
+            0x39 => {
+                self.dad(self.sp);
+                1
             }
             // LDA adr
             0x3a => {
                 self.a = self.read_byte(self.read_bytes_immediate());
-                (3, 13)
-            }
-            // INR A
-            0x3c => {
-                self.a = self.inr(self.a);
-                (1, 5)
-            }
-            // DCR A
-            0x3d => {
-                self.a = self.dcr(self.a);
-                (1, 7)
+                3
             }
-            // MVI A, D8
-            0x3e => {
-                self.a = self.read_byte_immediate();
-                (2, 7)
+            // DCX SP
+            0x3b => {
+                Self::dcx(&mut self.sp);
+                1
             }
             // CMC
             0x3f => {
                 self.flags.carry = !self.flags.carry;
-                (1, 4)
-            }
-            // MOV B,C
-            0x41 => {
-                *self.b_mut() = self.c();
-                (1, 5)
-            }
-            // MOV B,M
-            0x46 => {
-                *self.b_mut() = self.m();
-                (1, 7)
-            }
-            // MOV B,A
-            0x47 => {
-                *self.b_mut() = self.a;
-                (1, 5)
-            }
-            // MOV C,B
-            0x48 => {
-                *self.c_mut() = self.b();
-                (1, 5)
-            }
-            // MOV C,M
-            0x4e => {
-                *self.c_mut() = self.m();
-                (1, 7)
-            }
-            // MOV C,A
-            0x4f => {
-                *self.c_mut() = self.a;
-                (1, 5)
-            }
-            // MOV D,M
-            0x56 => {
-                *self.d_mut() = self.m();
-                (1, 7)
-            }
-            // MOV D,A
-            0x57 => {
-                *self.d_mut() = self.a;
-                (1, 5)
-            }
-            // MOV E,M
-            0x5e => {
-                *self.e_mut() = self.m();
-                (1, 7)
-            }
-            // MOV E,A
-            0x5f => {
-                *self.e_mut() = self.a;
-                (1, 5)
-            }
-            // MOV H,C
-            0x61 => {
-                *self.h_mut() = self.c();
-                (1, 5)
-            }
-            // MOV H,L
-            0x65 => {
-                *self.h_mut() = self.l();
-                (1, 5)
-            }
-            // MOV H,M
-            0x66 => {
-                *self.h_mut() = self.m();
-                (1, 7)
-            }
-            // MOV H,A
-            0x67 => {
-                *self.h_mut() = self.a;
-                (1, 5)
-            }
-            // MOV L,B
-            0x68 => {
-                *self.l_mut() = self.b();
-                (1, 5)
-            }
-            // MOV L,C
-            0x69 => {
-                *self.l_mut() = self.c();
-                (1, 5)
-            }
-            // MOV L,A
-            0x6f => {
-                *self.l_mut() = self.a;
-                (1, 5)
-            }
-            // MOV M,B
-            0x70 => {
-                *self.m_mut() = self.b();
-                (1, 7)
-            }
-            // MOV M,C
-            0x71 => {
-                *self.m_mut() = self.c();
-                (1, 7)
+                1
+            }
+            // MOV r1,r2
+            0x40..=0x75 | 0x77..=0x7f => {
+                let (destination, source) = ((op_code >> 3) & 0b111, op_code & 0b111);
+                self.set_register(destination, self.register(source))?;
+                1
+            }
+            // ADD/ADC/SUB/SBB/ANA/XRA/ORA/CMP r
+            0x80..=0xbf => {
+                self.arithmetic((op_code >> 3) & 0b111, self.register(op_code & 0b111));
+                1
+            }
+            // ADI/ACI/SUI/SBI/ANI/XRI/ORI/CPI D8
+            0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => {
+                self.arithmetic((op_code >> 3) & 0b111, self.read_byte_immediate());
+                2
             }
             // HLT
             0x76 => {
-                println!("HLT instruction received");
-                process::exit(0)
-            }
-            // MOV M,A
-            0x77 => {
-                *self.m_mut() = self.a;
-                (1, 7)
-            }
-            // MOV A,B
-            0x78 => {
-                self.a = self.b();
-                (1, 5)
-            }
-            // MOV A,C
-            0x79 => {
-                self.a = self.c();
-                (1, 5)
-            }
-            // MOV A,D
-            0x7a => {
-                self.a = self.d();
-                (1, 5)
-            }
-            // MOV A,E
-            0x7b => {
-                self.a = self.e();
-                (1, 5)
-            }
-            // MOV A,H
-            0x7c => {
-                self.a = self.h();
-                (1, 5)
-            }
-            // MOV A,L
-            0x7d => {
-                self.a = self.l();
-                (1, 5)
-            }
-            // MOV A,M
-            0x7e => {
-                self.a = self.m();
-                (1, 7)
-            }
-            // ADD B
-            0x80 => {
-                self.add(self.b());
-                (1, 4)
-            }
-            // ADD C
-            0x81 => {
-                self.add(self.c());
-                (1, 4)
-            }
-            // ADD D
-            0x82 => {
-                self.add(self.d());
-                (1, 4)
-            }
-            // ADD E
-            0x83 => {
-                self.add(self.e());
-                (1, 4)
-            }
-            // ADD H
-            0x84 => {
-                self.add(self.h());
-                (1, 4)
-            }
-            // ADD L
-            0x85 => {
-                self.add(self.l());
-                (1, 4)
-            }
-            // ADD M
-            0x86 => {
-                self.add(self.m());
-                (1, 7)
-            }
-            // ADD A
-            0x87 => {
-                self.add(self.a);
-                (1, 4)
-            }
-            // ADC D
-            0x8a => {
-                self.adc(self.d());
-                (1, 4)
-            }
-            // SUB A
-            0x97 => {
-                self.sub(self.a);
-                (1, 4)
-            }
-            // ANA B
-            0xa0 => {
-                self.and(self.b());
-                (1, 4)
-            }
-            // ANA C
-            0xa1 => {
-                self.and(self.c());
-                (1, 4)
-            }
-            // ANA D
-            0xa2 => {
-                self.and(self.d());
-                (1, 4)
-            }
-            // ANA E
-            0xa3 => {
-                self.and(self.e());
-                (1, 4)
-            }
-            // ANA H
-            0xa4 => {
-                self.and(self.h());
-                (1, 4)
-            }
-            // ANA L
-            0xa5 => {
-                self.and(self.l());
-                (1, 4)
-            }
-            // ANA M
-            0xa6 => {
-                self.and(self.m());
-                (1, 7)
-            }
-            // ANA A
-            0xa7 => {
-                self.and(self.a);
-                (1, 4)
-            }
-            // XRA B
-            0xa8 => {
-                self.xor(self.b());
-                (1, 4)
-            }
-            // XRA C
-            0xa9 => {
-                self.xor(self.c());
-                (1, 4)
-            }
-            // XRA D
-            0xaa => {
-                self.xor(self.d());
-                (1, 4)
-            }
-            // XRA E
-            0xab => {
-                self.xor(self.e());
-                (1, 4)
-            }
-            // XRA H
-            0xac => {
-                self.xor(self.h());
-                (1, 4)
-            }
-            // XRA L
-            0xad => {
-                self.xor(self.l());
-                (1, 4)
-            }
-            // XRA M
-            0xae => {
-                self.xor(self.m());
-                (1, 7)
-            }
-            // XRA A
-            0xaf => {
-                self.xor(self.a);
-                (1, 4)
-            }
-            // ORA B
-            0xb0 => {
-                self.or(self.b());
-                (1, 4)
-            }
-            // ORA C
-            0xb1 => {
-                self.or(self.c());
-                (1, 4)
-            }
-            // ORA D
-            0xb2 => {
-                self.or(self.d());
-                (1, 4)
-            }
-            // ORA E
-            0xb3 => {
-                self.or(self.e());
-                (1, 4)
-            }
-            // ORA H
-            0xb4 => {
-                self.or(self.h());
-                (1, 4)
-            }
-            // ORA L
-            0xb5 => {
-                self.or(self.l());
-                (1, 4)
-            }
-            // ORA M
-            0xb6 => {
-                self.or(self.m());
-                (1, 7)
-            }
-            // ORA A
-            0xb7 => {
-                self.or(self.a);
-                (1, 4)
-            }
-            // CMP B
-            0xb8 => {
-                self.cmp(self.b());
-                (1, 4)
-            }
-            // CMP C
-            0xb9 => {
-                self.cmp(self.c());
-                (1, 4)
-            }
-            // CMP D
-            0xba => {
-                self.cmp(self.d());
-                (1, 4)
-            }
-            // CMP E
-            0xbb => {
-                self.cmp(self.e());
-                (1, 4)
-            }
-            // CMP H
-            0xbc => {
-                self.cmp(self.h());
-                (1, 4)
-            }
-            // CMP L
-            0xbd => {
-                self.cmp(self.l());
-                (1, 4)
-            }
-            // CMP M
-            0xbe => {
-                self.cmp(self.m());
-                (1, 7)
-            }
-            // CMP A
-            0xbf => {
-                self.cmp(self.a);
-                (1, 4)
+                // PC still moves past HLT, so the interrupt waking the CPU returns after it
+                self.halted = true;
+                1
             }
             // RNZ
             0xc0 => {
                 if self.flags.zero {
-                    (1, 5)
+                    1
                 } else {
+                    taken = true;
                     self.ret();
-                    (0, 11)
+                    0
                 }
             }
             // POP B
             0xc1 => {
                 *self.bc_mut() = self.pop();
-                (1, 10)
+                1
             }
             // JNZ adr
             0xc2 => {
                 if self.flags.zero {
-                    (3, 10)
+                    3
                 } else {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 }
             }
-            // JMP adr
-            0xc3 => {
+            // JMP adr, and its undocumented alias
+            0xc3 | 0xcb => {
                 self.jmp(self.read_bytes_immediate());
-                (0, 10)
+                0
             }
             // CNZ adr
             0xc4 => {
                 if self.flags.zero {
-                    (3, 11)
+                    3
                 } else {
-                    self.call(self.read_bytes_immediate());
-                    (0, 17)
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
                 }
             }
             // PUSH B
             0xc5 => {
-                self.push(self.bc());
-                (1, 11)
-            }
-            // ADI D8
-            0xc6 => {
-                self.add(self.read_byte_immediate());
-                (2, 7)
+                self.push(self.bc())?;
+                1
             }
             // RZ
             0xc8 => {
                 if self.flags.zero {
+                    taken = true;
                     self.ret();
-                    (0, 11)
+                    0
                 } else {
-                    (1, 5)
+                    1
                 }
             }
-            // RET
-            0xc9 => {
+            // RET, and its undocumented alias
+            0xc9 | 0xd9 => {
                 self.ret();
-                (0, 10)
+                0
             }
             // JZ adr
             0xca => {
                 if self.flags.zero {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 } else {
-                    (3, 10)
+                    3
                 }
             }
             // CZ adr
             0xcc => {
                 if self.flags.zero {
-                    self.call(self.read_bytes_immediate());
-                    (0, 17)
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
                 } else {
-                    (3, 11)
+                    3
                 }
             }
-            // CALL adr
-            0xcd => {
-                self.call(self.read_bytes_immediate());
-                (0, 17)
+            // CALL adr, and its undocumented aliases
+            0xcd | 0xdd | 0xed | 0xfd => {
+                self.call(self.read_bytes_immediate())?;
+                0
             }
             // RNC
             0xd0 => {
                 if self.flags.carry {
-                    (1, 5)
+                    1
                 } else {
+                    taken = true;
                     self.ret();
-                    (0, 11)
+                    0
                 }
             }
             // POP D
             0xd1 => {
                 *self.de_mut() = self.pop();
-                (1, 10)
+                1
             }
             // JNC adr
             0xd2 => {
                 if self.flags.carry {
-                    (3, 10)
+                    3
                 } else {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 }
             }
             // OUT D8
             0xd3 => {
-                io_state.output(self.read_byte_immediate(), self.a);
-                (2, 10)
+                io_state.output(self.read_byte_immediate(), self.a)?;
+                2
             }
             // CNC adr
             0xd4 => {
                 if self.flags.carry {
-                    (3, 11)
+                    3
                 } else {
-                    self.call(self.read_bytes_immediate());
-                    (0, 17)
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
                 }
             }
             // PUSH D
             0xd5 => {
-                self.push(self.de());
-                (1, 11)
-            }
-            // SUI d8
-            0xd6 => {
-                self.sub(self.read_byte_immediate());
-                (2, 7)
+                self.push(self.de())?;
+                1
             }
             // RC
             0xd8 => {
                 if self.flags.carry {
+                    taken = true;
                     self.ret();
-                    (0, 11)
+                    0
                 } else {
-                    (1, 5)
+                    1
                 }
             }
             // JC adr
             0xda => {
                 if self.flags.carry {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 } else {
-                    (3, 10)
+                    3
                 }
             }
             // IN D8
             0xdb => {
-                self.a = io_state.input(self.read_byte_immediate());
-                (2, 10)
+                self.a = io_state.input(self.read_byte_immediate())?;
+                2
             }
-            // SBI D8
-            0xde => {
-                self.sbb(self.read_byte_immediate());
-                (2, 7)
+            // CC adr
+            0xdc => {
+                if self.flags.carry {
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
+                } else {
+                    3
+                }
+            }
+            // RPO
+            0xe0 => {
+                if self.flags.parity {
+                    1
+                } else {
+                    taken = true;
+                    self.ret();
+                    0
+                }
             }
             // POP H
             0xe1 => {
                 *self.hl_mut() = self.pop();
-                (1, 10)
+                1
             }
             // JPO adr
             0xe2 => {
                 if self.flags.parity {
-                    (3, 10)
+                    3
                 } else {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 }
             }
             // XTHL
             0xe3 => {
-                let tmp = self.hl();
-                *self.hl_mut() = self.pop();
-                self.push(tmp);
-                (1, 18)
+                // Swapped in place, so a failed write leaves both HL and the stack as they were
+                let top = self.read_bytes(self.sp);
+                self.write_bytes(self.sp, self.hl())?;
+                *self.hl_mut() = top;
+                1
+            }
+            // CPO adr
+            0xe4 => {
+                if self.flags.parity {
+                    3
+                } else {
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
+                }
             }
             // PUSH H
             0xe5 => {
-                self.push(self.hl());
-                (1, 11)
+                self.push(self.hl())?;
+                1
             }
-            // ANI D8
-            0xe6 => {
-                self.ani();
-                (2, 7)
+            // RPE
+            0xe8 => {
+                if self.flags.parity {
+                    taken = true;
+                    self.ret();
+                    0
+                } else {
+                    1
+                }
             }
+            // PCHL
             0xe9 => {
                 self.jmp(self.hl());
-                (0, 5)
+                0
             }
             // JPE adr
             0xea => {
                 if self.flags.parity {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 } else {
-                    (3, 10)
+                    3
                 }
             }
             // XCHG
@@ -1240,132 +1767,232 @@ impl CpuState {
                 let tmp = self.de();
                 *self.de_mut() = self.hl();
                 *self.hl_mut() = tmp;
-                (1, 5)
+                1
+            }
+            // CPE adr
+            0xec => {
+                if self.flags.parity {
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
+                } else {
+                    3
+                }
+            }
+            // RP
+            0xf0 => {
+                if self.flags.sign {
+                    1
+                } else {
+                    taken = true;
+                    self.ret();
+                    0
+                }
             }
             // POP AF
             0xf1 => {
                 let pop = self.pop();
                 self.set_af(pop);
-                (1, 10)
+                1
             }
             // JP adr
             0xf2 => {
                 if self.flags.sign {
-                    (3, 10)
+                    3
                 } else {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 }
             }
             // DI
             0xf3 => {
                 self.interrupts_enabled = false;
-                (1, 4)
+                self.pending_interrupt = None;
+                1
+            }
+            // CP adr
+            0xf4 => {
+                if self.flags.sign {
+                    3
+                } else {
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
+                }
             }
             // PUSH AF
             0xf5 => {
-                self.push(self.af());
-                (1, 11)
+                self.push(self.af())?;
+                1
+            }
+            // RM
+            0xf8 => {
+                if self.flags.sign {
+                    taken = true;
+                    self.ret();
+                    0
+                } else {
+                    1
+                }
             }
-            // ORI d8
-            0xf6 => {
-                self.or(self.read_byte_immediate());
-                (2, 7)
+            // SPHL
+            0xf9 => {
+                self.sp = self.hl();
+                1
             }
             // JM adr
             0xfa => {
                 if self.flags.sign {
                     self.jmp(self.read_bytes_immediate());
-                    (0, 10)
+                    0
                 } else {
-                    (3, 10)
+                    3
                 }
             }
             // EI
             0xfb => {
                 self.interrupts_enabled = true;
-                (1, 4)
-            }
-            // CPI D8
-            0xfe => {
-                self.cmp(self.read_byte_immediate());
-                (2, 7)
-            }
-            // Unimplemented
-            _ => {
-                println!(
-                    "Unimplemented instruction: {:04x} {:02x} {}",
-                    self.pc,
-                    op_code,
-                    self.next_opcode()
-                );
-                process::exit(0)
+                self.interrupt_delay = true;
+                1
+            }
+            // CM adr
+            0xfc => {
+                if self.flags.sign {
+                    taken = true;
+                    self.call(self.read_bytes_immediate())?;
+                    0
+                } else {
+                    3
+                }
+            }
+            // RST n
+            0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => {
+                self.push(self.pc.wrapping_add(1))?;
+                self.pc = u16::from(op_code & 0b0011_1000);
+                0
             }
         };
 
-        self.pc += op_length;
-        cycles
+        // Taken jumps, calls and returns set PC themselves, only running on to the next instruction wraps
+        let address = self.pc;
+        self.pc = self.pc.wrapping_add(op_length);
+        if u32::from(address) + u32::from(op_length) > 0xffff {
+            match self.pc_wrap_policy {
+                PcWrapPolicy::Ignore => {}
+                PcWrapPolicy::Warn => eprintln!("Execution runs past $ffff at ${address:04x}"),
+                PcWrapPolicy::Break => return Err(Error::PcWrap(address)),
+            }
+        }
+
+        Ok(Self::cycles_for(op_code, taken))
+    }
+
+    /// Cycles taken by `op_code`, depending on whether its condition is met for conditional
+    /// calls and returns. Uses the same tables as `emulate`, `CYCLES` and `TAKEN_EXTRA_CYCLES`.
+    pub fn cycles_for(op_code: u8, taken: bool) -> u64 {
+        let cycles = u64::from(CYCLES[op_code as usize]);
+
+        if taken {
+            cycles + u64::from(TAKEN_EXTRA_CYCLES[op_code as usize])
+        } else {
+            cycles
+        }
+    }
+
+    /// Group of `op_code` in the instruction set summary of the 8080 datasheet: `transfer` for data transfer,
+    /// `arithmetic`, `logical`, `branch`, or `control` for stack, I/O and machine control
+    pub fn category(op_code: u8) -> &'static str {
+        match op_code {
+            // HLT sits where MOV M, M would be
+            0x76 => "control",
+            // MOV, MVI, LXI, STAX, LDAX, SHLD, LHLD, STA, LDA, XCHG
+            0x40..=0x7f => "transfer",
+            _ if op_code & 0b1100_0111 == 0b0000_0110 => "transfer",
+            0x01 | 0x11 | 0x21 | 0x31 | 0x02 | 0x12 | 0x0a | 0x1a | 0x22 | 0x2a | 0x32 | 0x3a | 0xeb => "transfer",
+            // ADD, ADC, SUB, SBB, their immediate forms, INR, DCR, INX, DCX, DAD, DAA
+            0x80..=0x9f | 0xc6 | 0xce | 0xd6 | 0xde | 0x27 => "arithmetic",
+            _ if op_code & 0b1100_0110 == 0b0000_0100 => "arithmetic",
+            _ if op_code & 0b1100_0111 == 0b0000_0011 || op_code & 0b1100_1111 == 0b0000_1001 => "arithmetic",
+            // ANA, XRA, ORA, CMP, their immediate forms, rotations, CMA, STC, CMC
+            0xa0..=0xbf | 0xe6 | 0xee | 0xf6 | 0xfe | 0x07 | 0x0f | 0x17 | 0x1f | 0x2f | 0x37 | 0x3f => "logical",
+            // Conditional returns, jumps and calls, RST, and the unconditional ones with their aliases
+            _ if matches!(op_code & 0b1100_0111, 0b1100_0000 | 0b1100_0010 | 0b1100_0100 | 0b1100_0111) => "branch",
+            0xc3 | 0xcb | 0xc9 | 0xd9 | 0xcd | 0xdd | 0xed | 0xfd | 0xe9 => "branch",
+            // PUSH, POP, XTHL, SPHL, IN, OUT, EI, DI, NOP
+            _ => "control",
+        }
+    }
+
+    /// Cycles taken by `op_code`, written `not taken/taken` when they depend on the condition
+    pub fn cycle_annotation(op_code: u8) -> String {
+        let (not_taken, taken) = (Self::cycles_for(op_code, false), Self::cycles_for(op_code, true));
+
+        if not_taken == taken {
+            not_taken.to_string()
+        } else {
+            format!("{not_taken}/{taken}")
+        }
     }
 
     /// Returns the name of the instruction at the specified address in memory
     fn op_name(&self, address: u16) -> String {
         match self.read_byte(address) {
             0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => "NOP".into(),
-            0x01 => format!("LXI B, ${:04x}", self.read_bytes(address + 1)),
+            0x01 => format!("LXI B, ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0x02 => "STAX B".into(),
             0x03 => "INX B".into(),
             0x04 => "INR B".into(),
             0x05 => "DCR B".into(),
-            0x06 => format!("MVI B, ${:02x}", self.read_byte(address + 1)),
+            0x06 => format!("MVI B, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x07 => "RLC".into(),
             0x09 => "DAD B".into(),
             0x0a => "LDAX B".into(),
             0x0b => "DCX B".into(),
             0x0c => "INR C".into(),
             0x0d => "DCR C".into(),
-            0x0e => format!("MVI C, ${:02x}", self.read_byte(address + 1)),
+            0x0e => format!("MVI C, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x0f => "RRC".into(),
-            0x11 => format!("LXI D, ${:04x}", self.read_bytes(address + 1)),
+            0x11 => format!("LXI D, ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0x12 => "STAX D".into(),
             0x13 => "INX D".into(),
             0x14 => "INR D".into(),
             0x15 => "DCR D".into(),
-            0x16 => format!("MVI D, ${:02x}", self.read_byte(address + 1)),
+            0x16 => format!("MVI D, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x17 => "RAL".into(),
             0x19 => "DAD D".into(),
             0x1a => "LDAX D".into(),
             0x1b => "DCX D".into(),
             0x1c => "INR E".into(),
             0x1d => "DCR E".into(),
-            0x1e => format!("MVI E, ${:02x}", self.read_byte(address + 1)),
+            0x1e => format!("MVI E, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x1f => "RAR".into(),
-            0x21 => format!("LXI H, ${:04x}", self.read_bytes(address + 1)),
-            0x22 => format!("SHLD ${:04x}", self.read_bytes(address + 1)),
+            0x21 => format!("LXI H, ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0x22 => format!("SHLD ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0x23 => "INX H".into(),
             0x24 => "INR H".into(),
             0x25 => "DCR H".into(),
-            0x26 => format!("MVI H, ${:02x}", self.read_byte(address + 1)),
+            0x26 => format!("MVI H, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x27 => "DAA".into(),
             0x29 => "DAD H".into(),
-            0x2a => format!("LHLD ${:04x}", self.read_bytes(address + 1)),
+            0x2a => format!("LHLD ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0x2b => "DCX H".into(),
             0x2c => "INR L".into(),
             0x2d => "DCR L".into(),
-            0x2e => format!("MVI L, ${:02x}", self.read_byte(address + 1)),
+            0x2e => format!("MVI L, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x2f => "CMA".into(),
-            0x31 => format!("LXI SP, ${:04x}", self.read_bytes(address + 1)),
-            0x32 => format!("STA ${:04x}", self.read_bytes(address + 1)),
+            0x31 => format!("LXI SP, ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0x32 => format!("STA ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0x33 => "INX SP".into(),
             0x34 => "INR M".into(),
             0x35 => "DCR M".into(),
-            0x36 => format!("MVI M, ${:02x}", self.read_byte(address + 1)),
+            0x36 => format!("MVI M, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x37 => "STC".into(),
             0x39 => "DAD SP".into(),
-            0x3a => format!("LDA ${:04x}", self.read_bytes(address + 1)),
+            0x3a => format!("LDA ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0x3b => "DCX SP".into(),
             0x3c => "INR A".into(),
             0x3d => "DCR A".into(),
-            0x3e => format!("MVI A, ${:02x}", self.read_byte(address + 1)),
+            0x3e => format!("MVI A, ${:02x}", self.read_byte(address.wrapping_add(1))),
             0x3f => "CMC".into(),
             0x40 => "MOV B,B".into(),
             0x41 => "MOV B,C".into(),
@@ -1497,63 +2124,1625 @@ impl CpuState {
             0xbf => "CMP A".into(),
             0xc0 => "RNZ".into(),
             0xc1 => "POP B".into(),
-            0xc2 => format!("JNZ ${:04x}", self.read_bytes(address + 1)),
-            0xc3 | 0xcb => format!("JMP ${:04x}", self.read_bytes(address + 1)),
-            0xc4 => format!("CNZ ${:04x}", self.read_bytes(address + 1)),
+            0xc2 => format!("JNZ ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xc3 | 0xcb => format!("JMP ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xc4 => format!("CNZ ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0xc5 => "PUSH B".into(),
-            0xc6 => format!("ADI ${:02x}", self.read_byte(address + 1)),
+            0xc6 => format!("ADI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xc7 => "RST 0".into(),
             0xc8 => "RZ".into(),
             0xc9 | 0xd9 => "RET".into(),
-            0xca => format!("JZ ${:04x}", self.read_bytes(address + 1)),
-            0xcc => format!("CZ ${:04x}", self.read_bytes(address + 1)),
-            0xcd | 0xdd | 0xed | 0xfd => format!("CALL ${:04x}", self.read_bytes(address + 1)),
-            0xce => format!("ACI ${:02x}", self.read_byte(address + 1)),
+            0xca => format!("JZ ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xcc => format!("CZ ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xcd | 0xdd | 0xed | 0xfd => format!("CALL ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xce => format!("ACI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xcf => "RST 1".into(),
             0xd0 => "RNC".into(),
             0xd1 => "POP D".into(),
-            0xd2 => format!("JNC ${:04x}", self.read_bytes(address + 1)),
-            0xd3 => format!("OUT ${:02x}", self.read_byte(address + 1)),
-            0xd4 => format!("CNC ${:04x}", self.read_bytes(address + 1)),
+            0xd2 => format!("JNC ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xd3 => format!("OUT ${:02x}", self.read_byte(address.wrapping_add(1))),
+            0xd4 => format!("CNC ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0xd5 => "PUSH D".into(),
-            0xd6 => format!("SUI ${:02x}", self.read_byte(address + 1)),
+            0xd6 => format!("SUI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xd7 => "RST 2".into(),
             0xd8 => "RC".into(),
-            0xda => format!("JC ${:04x}", self.read_bytes(address + 1)),
-            0xdb => format!("IN ${:02x}", self.read_byte(address + 1)),
-            0xdc => format!("CC ${:04x}", self.read_bytes(address + 1)),
-            0xde => "SBI D8".into(),
+            0xda => format!("JC ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xdb => format!("IN ${:02x}", self.read_byte(address.wrapping_add(1))),
+            0xdc => format!("CC ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xde => format!("SBI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xdf => "RST 3".into(),
             0xe0 => "RPO".into(),
             0xe1 => "POP H".into(),
-            0xe2 => format!("JPO ${:04x}", self.read_bytes(address + 1)),
+            0xe2 => format!("JPO ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0xe3 => "XTHL".into(),
-            0xe4 => format!("CPO ${:04x}", self.read_bytes(address + 1)),
+            0xe4 => format!("CPO ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0xe5 => "PUSH H".into(),
-            0xe6 => format!("ANI ${:02x}", self.read_byte(address + 1)),
+            0xe6 => format!("ANI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xe7 => "RST 4".into(),
             0xe8 => "RPE".into(),
             0xe9 => "PCHL".into(),
-            0xea => format!("JPE ${:04x}", self.read_bytes(address + 1)),
+            0xea => format!("JPE ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0xeb => "XCHG".into(),
-            0xec => format!("CPE ${:04x}", self.read_bytes(address + 1)),
-            0xee => format!("XRI ${:02x}", self.read_byte(address + 1)),
+            0xec => format!("CPE ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xee => format!("XRI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xef => "RST 5".into(),
             0xf0 => "RP".into(),
-            0xf1 => "POP AF".into(),
-            0xf2 => format!("JP ${:04x}", self.read_bytes(address + 1)),
+            0xf1 => "POP PSW".into(),
+            0xf2 => format!("JP ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0xf3 => "DI".into(),
-            0xf4 => format!("CP ${:04x}", self.read_bytes(address + 1)),
-            0xf5 => "PUSH AF".into(),
-            0xf6 => format!("ORI ${:02x}", self.read_byte(address + 1)),
+            0xf4 => format!("CP ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xf5 => "PUSH PSW".into(),
+            0xf6 => format!("ORI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xf7 => "RST 6".into(),
             0xf8 => "RM".into(),
             0xf9 => "SPHL".into(),
-            0xfa => format!("JM ${:04x}", self.read_bytes(address + 1)),
+            0xfa => format!("JM ${:04x}", self.read_bytes(address.wrapping_add(1))),
             0xfb => "EI".into(),
-            0xfc => format!("CM ${:04x}", self.read_bytes(address + 1)),
-            0xfe => format!("CPI ${:02x}", self.read_byte(address + 1)),
+            0xfc => format!("CM ${:04x}", self.read_bytes(address.wrapping_add(1))),
+            0xfe => format!("CPI ${:02x}", self.read_byte(address.wrapping_add(1))),
             0xff => "RST 7".into(),
         }
     }
+
+    /// Returns the length in bytes of the instruction with the specified opcode
+    pub fn opcode_length(op_code: u8) -> u16 {
+        match op_code {
+            // MVI, immediate arithmetic/logic, IN, OUT
+            0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e |
+            0xc6 | 0xce | 0xd3 | 0xd6 | 0xdb | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => 2,
+            // LXI, direct addressing
+            0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2a | 0x32 | 0x3a |
+            // Jumps
+            0xc2 | 0xc3 | 0xca | 0xcb | 0xd2 | 0xda | 0xe2 | 0xea | 0xf2 | 0xfa |
+            // Calls
+            0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc | 0xdd | 0xe4 | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Cycles taken by each opcode from the 8080 datasheet, when the condition isn't met
+/// for conditional calls and returns. Conditional jumps take the same time either way.
+pub const CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 0
+    4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 1
+    4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4, // 2
+    4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4, // 3
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 4
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 5
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 6
+    7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5, // 7
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 8
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 9
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // a
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // b
+    5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // c
+    5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // d
+    5, 10, 10, 18, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // e
+    5, 10, 10,  4, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // f
+];
+
+/// Cycles added to `CYCLES` when the condition of a conditional call or return is met
+pub const TAKEN_EXTRA_CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 0
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 1
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 2
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 3
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 4
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 5
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 6
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 7
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 8
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // 9
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // a
+    0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0, // b
+    6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0, // c
+    6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0, // d
+    6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0, // e
+    6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0,  6,  0,  0,  0, // f
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_io::SimpleIO;
+
+    /// CPU running `program` from address 0, with all of memory writable
+    fn cpu_with(program: &[u8]) -> CpuState {
+        let mut cpu = CpuState::from_rom(program, 0, 0).unwrap();
+        cpu.set_rom_end(0);
+        cpu
+    }
+
+    #[test]
+    fn instructions_walk_by_length() {
+        // NOP; LXI B, $1234; MVI A, $56; JMP $0000
+        let cpu = cpu_with(&[0x00, 0x01, 0x34, 0x12, 0x3e, 0x56, 0xc3, 0x00, 0x00]);
+
+        let instructions = cpu.instructions(0, 9).collect::<Vec<_>>();
+        assert_eq!(
+            instructions,
+            vec![
+                (0, "NOP".to_string()),
+                (1, "LXI B, $1234".to_string()),
+                (4, "MVI A, $56".to_string()),
+                (6, "JMP $0000".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn every_opcode_is_decoded_and_executed() {
+        // `emulate` matches every opcode, which the compiler checks, so only decoding can miss one
+        for op_code in 0..=0xff {
+            let mut cpu = cpu_with(&[op_code, 0x00, 0x00]);
+            let name = cpu.op_name(0);
+            assert!(!name.is_empty() && !name.contains("Unknown"), "{:02x} decodes to {:?}", op_code, name);
+            cpu.sp = 0x3000;
+            assert!(cpu.emulate(&mut SimpleIO::new()).is_ok(), "{:02x} fails", op_code);
+        }
+    }
+
+    #[test]
+    fn clones_run_independently() {
+        // LXI B, $1234; INX B; MVI A, $01; STA $3000
+        let mut cpu = cpu_with(&[0x01, 0x34, 0x12, 0x03, 0x3e, 0x01, 0x32, 0x00, 0x30]);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+        let mut clone = cpu.clone();
+        for _ in 0..3 {
+            clone.emulate(&mut SimpleIO::new()).unwrap();
+        }
+        assert_eq!((clone.bc(), clone.a(), clone.peek(0x3000)), (0x1235, 0x01, 0x01));
+
+        assert_eq!(cpu.pc(), 3);
+        assert_eq!((cpu.bc(), cpu.a(), cpu.peek(0x3000)), (0x1234, 0x00, 0x00));
+    }
+
+    #[test]
+    fn stack_wraps_around_the_address_space() {
+        // PUSH B; POP D
+        let mut cpu = cpu_with(&[0xc5, 0xd1]);
+        *cpu.bc_mut() = 0xbeef;
+
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        assert_eq!(cpu.sp(), 0xfffe);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        assert_eq!((cpu.sp(), cpu.de()), (0x0000, 0xbeef));
+    }
+
+    #[test]
+    fn failed_pushes_leave_the_stack_unchanged() {
+        // PUSH B; CALL $0000; RST 1; XTHL
+        let mut cpu = CpuState::from_rom([0xc5, 0xcd, 0x00, 0x00, 0xcf, 0xe3], 0, 0).unwrap();
+        cpu.set_strict(true);
+        cpu.sp = 0x1000;
+        *cpu.hl_mut() = 0x1234;
+
+        for pc in &[0, 1, 4, 5] {
+            cpu.pc = *pc;
+            assert!(matches!(cpu.emulate(&mut SimpleIO::new()), Err(Error::RomWrite(_))));
+            assert_eq!((cpu.pc(), cpu.sp(), cpu.hl()), (*pc, 0x1000, 0x1234));
+        }
+    }
+
+    #[test]
+    fn former_panics_are_errors() {
+        assert!(matches!(
+            CpuState::from_rom(vec![0; MEMORY_SIZE + 1], 0, 0),
+            Err(Error::RomTooLarge { size, start: 0 }) if size == MEMORY_SIZE + 1
+        ));
+        assert!(matches!(
+            CpuState::from_rom(vec![0; 2], MEMORY_SIZE - 1, 0),
+            Err(Error::RomTooLarge { .. })
+        ));
+
+        let mut cpu = CpuState::new();
+        assert!(matches!(
+            cpu.set_memory_slice(0x3fff, &[0, 0]),
+            Err(Error::OutOfMemory { address: 0x3fff, size: 2 })
+        ));
+
+        // MVI M, $01 with HL in ROM
+        let mut cpu = CpuState::from_rom([0x36, 0x01], 0, 0).unwrap();
+        cpu.set_strict(true);
+        assert!(matches!(cpu.emulate(&mut SimpleIO::new()), Err(Error::RomWrite(0x0000))));
+    }
+
+    #[test]
+    fn immediate_operands_wrap_around_the_address_space() {
+        let mut cpu = CpuState::new();
+        cpu.set_rom_end(0);
+        // LXI B at $fffe, its operand at $ffff and $0000 through the RAM mirror
+        cpu.set_memory_slice(0x3ffe, &[0x01, 0x34]).unwrap();
+        cpu.set_memory_slice(0x0000, &[0x12]).unwrap();
+        cpu.pc = 0xfffe;
+
+        assert_eq!(cpu.disassemble_next(), (vec![0x01, 0x34, 0x12], "LXI B, $1234".to_string()));
+        assert_eq!(cpu.emulate(&mut SimpleIO::new()).unwrap(), 10);
+        assert_eq!(cpu.bc(), 0x1234);
+        assert_eq!(cpu.pc(), 0x0001);
+    }
+
+    #[test]
+    fn rom_writes_are_ignored_unless_strict() {
+        // MVI A, $ff; STA $0000
+        let program = [0x3e, 0xff, 0x32, 0x00, 0x00];
+
+        let mut lenient = CpuState::from_rom(program, 0, 0).unwrap();
+        lenient.emulate(&mut SimpleIO::new()).unwrap();
+        lenient.emulate(&mut SimpleIO::new()).unwrap();
+        assert_eq!(lenient.peek(0x0000), 0x3e);
+        assert_eq!(lenient.pc(), 5);
+
+        let mut strict = CpuState::from_rom(program, 0, 0).unwrap();
+        strict.set_strict(true);
+        strict.emulate(&mut SimpleIO::new()).unwrap();
+        assert!(matches!(strict.emulate(&mut SimpleIO::new()), Err(Error::RomWrite(0x0000))));
+        assert_eq!(strict.peek(0x0000), 0x3e);
+    }
+
+    #[test]
+    fn inx_and_dcx_leave_flags_unchanged() {
+        for &(op_code, start, result) in &[
+            (0x03, 0xffff, 0x0000), (0x13, 0xffff, 0x0000), (0x23, 0xffff, 0x0000), (0x33, 0xffff, 0x0000),
+            (0x0b, 0x0000, 0xffff), (0x1b, 0x0000, 0xffff), (0x2b, 0x0000, 0xffff), (0x3b, 0x0000, 0xffff),
+        ] {
+            for &psw in &[0x00, 0xff] {
+                let mut cpu = cpu_with(&[op_code]);
+                cpu.flags.set_psw(psw);
+                let pair = match op_code >> 4 {
+                    0 => cpu.bc_mut(),
+                    1 => cpu.de_mut(),
+                    2 => cpu.hl_mut(),
+                    _ => &mut cpu.sp,
+                };
+                *pair = start;
+
+                cpu.emulate(&mut SimpleIO::new()).unwrap();
+                let pair = [cpu.bc(), cpu.de(), cpu.hl(), cpu.sp()][usize::from(op_code >> 4)];
+                assert_eq!(pair, result, "{op_code:02x}");
+                assert!(Flags::psw_eq(cpu.flags().psw(), psw), "{:02x} changed the flags", op_code);
+            }
+        }
+    }
+
+    #[test]
+    fn memory_slices_span_the_rom_boundary() {
+        let mut cpu = CpuState::new();
+        cpu.set_memory_slice(0x1ffe, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(&cpu.memory()[0x1ffe..0x2002], &[1, 2, 3, 4]);
+        assert_eq!((cpu.peek(0x1fff), cpu.peek(0x2000)), (2, 3));
+        assert!(cpu.is_written(0x1ffe) && cpu.is_written(0x2001));
+        assert!(!cpu.is_written(0x2002));
+    }
+
+    /// Panics with every register and flag differing from `expected`, if any
+    fn assert_state(cpu: &CpuState, expected: Registers) {
+        let differences = expected.diff(&cpu.registers());
+        assert!(differences.is_empty(), "state mismatch:\n{}", differences.join("\n"));
+    }
+
+    #[test]
+    fn assert_state_accepts_the_same_state() {
+        // MVI B, $02
+        let mut cpu = cpu_with(&[0x06, 0x02]);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+        assert_state(&cpu, Registers { b: 0x02, pc: 2, ..CpuState::new().registers() });
+    }
+
+    #[test]
+    #[should_panic(expected = "state mismatch:\nB: expected 0x01, got 0x02")]
+    fn assert_state_names_the_mismatched_register() {
+        // MVI B, $02
+        let mut cpu = cpu_with(&[0x06, 0x02]);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+        assert_state(&cpu, Registers { b: 0x01, pc: 2, ..CpuState::new().registers() });
+    }
+
+    #[test]
+    fn and_sets_aux_carry_from_bit_3_of_either_operand() {
+        for &(a, operand, aux_carry) in &[(0x08, 0x00, true), (0x00, 0x08, true), (0x08, 0x08, true), (0xf7, 0xf7, false)] {
+            // ANA B, then ANI with the same operand
+            let mut cpu = cpu_with(&[0xa0, 0xe6, operand]);
+            for _ in 0..2 {
+                cpu.a = a;
+                *cpu.b_mut() = operand;
+                cpu.flags.aux_carry = !aux_carry;
+                cpu.flags.carry = true;
+                cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+                assert_eq!(cpu.a(), a & operand);
+                assert_eq!(cpu.flags().aux_carry, aux_carry, "{a:02x} & {operand:02x}");
+                assert!(!cpu.flags().carry);
+            }
+        }
+    }
+
+    #[test]
+    fn interrupts_are_enabled_after_the_instruction_following_ei() {
+        // EI, NOP, NOP
+        let mut cpu = cpu_with(&[0xfb, 0x00, 0x00]);
+        let mut io = SimpleIO::new();
+
+        cpu.emulate(&mut io).unwrap();
+        assert!(!cpu.interrupts_enabled());
+        // The debug output shows the EI delay too
+        assert!(format!("{cpu:?}").contains("interrupts_enabled: false"));
+        cpu.emulate(&mut io).unwrap();
+        assert!(cpu.interrupts_enabled());
+        assert!(!cpu.interrupt_pending());
+    }
+
+    #[test]
+    fn latched_interrupts_are_counted_and_charged_when_serviced() {
+        // LXI SP, $1000; EI; NOP, with a NOP handler at $0008
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x10, 0xfb, 0x00]);
+        let mut io = SimpleIO::new();
+        cpu.set_interrupt_latency(3);
+        cpu.emulate(&mut io).unwrap();
+        cpu.emulate(&mut io).unwrap();
+
+        assert!(!cpu.interrupt(1).unwrap());
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 4);
+        assert!(cpu.interrupt_pending());
+        assert_eq!(cpu.interrupt_count(), 0);
+
+        // NOP, RST 1 and the latency
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 4 + 11 + 3);
+        assert_eq!(cpu.interrupt_count(), 1);
+        assert_eq!(cpu.pc(), 9);
+        assert_eq!(cpu.stack(1), vec![5]);
+    }
+
+    #[test]
+    fn debug_output_lists_each_register() {
+        let mut cpu = cpu_with(&[]);
+        cpu.a = 0xa1;
+        *cpu.bc_mut() = 0xb2c3;
+        *cpu.de_mut() = 0xd4e5;
+        *cpu.hl_mut() = 0x4f60;
+        cpu.sp = 0x1234;
+        cpu.pc = 0x0567;
+
+        let debug = format!("{cpu:?}");
+        for field in &["a: a1", "b: b2", "c: c3", "d: d4", "e: e5", "h: 4f", "l: 60", "sp: 1234", "pc: 0567", "flags: "] {
+            assert!(debug.contains(field), "{} missing from {}", field, debug);
+        }
+    }
+
+    #[test]
+    fn register_families_decode_every_operand() {
+        // B, C, D, E, H, L, M at $1000 and A
+        const VALUES: [u8; 8] = [0x11, 0x22, 0x33, 0x44, 0x10, 0x00, 0x99, 0x77];
+        let run = |program: &[u8]| {
+            let mut cpu = cpu_with(program);
+            for (index, &value) in VALUES.iter().enumerate().filter(|&(index, _)| index != 6) {
+                cpu.set_register(index as u8, value).unwrap();
+            }
+            cpu.set_memory_slice(0x1000, &[VALUES[6]]).unwrap();
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+            cpu
+        };
+
+        for source in 0..8 {
+            for destination in (0..8).filter(|&destination| (destination, source) != (6, 6)) {
+                let cpu = run(&[0x40 | destination << 3 | source]);
+                assert_eq!(cpu.register(destination), VALUES[usize::from(source)], "MOV {destination} {source}");
+            }
+
+            let operand = VALUES[usize::from(source)];
+            let a = VALUES[7];
+            let expected = [
+                (a.wrapping_add(operand), u16::from(a) + u16::from(operand) > 0xff),
+                (a.wrapping_add(operand), u16::from(a) + u16::from(operand) > 0xff),
+                (a.wrapping_sub(operand), operand > a),
+                (a.wrapping_sub(operand), operand > a),
+                (a & operand, false),
+                (a ^ operand, false),
+                (a | operand, false),
+                (a, operand > a),
+            ];
+            for (operation, &(result, carry)) in expected.iter().enumerate() {
+                let cpu = run(&[0x80 | (operation as u8) << 3 | source]);
+                assert_eq!((cpu.a, cpu.flags.carry), (result, carry), "operation {operation} {source}");
+            }
+
+            let register = source;
+            assert_eq!(run(&[0x04 | register << 3]).register(register), operand.wrapping_add(1), "INR {register}");
+            assert_eq!(run(&[0x05 | register << 3]).register(register), operand.wrapping_sub(1), "DCR {register}");
+            assert_eq!(run(&[0x06 | register << 3, 0x5a]).register(register), 0x5a, "MVI {register}");
+        }
+    }
+
+    #[test]
+    fn register_pair_bytes_are_least_significant_first() {
+        let mut pair = RegisterPair::new();
+        *pair.both_mut() = 0x1234;
+
+        assert_eq!(pair.bytes(), [pair.lsb(), pair.msb()]);
+        assert_eq!(pair.bytes(), [0x34, 0x12]);
+        assert_eq!(RegisterPair::from_bytes(pair.bytes()).both(), 0x1234);
+    }
+
+    #[test]
+    fn disassemble_next_returns_the_instruction_bytes() {
+        // NOP; JMP $1234
+        let mut cpu = cpu_with(&[0x00, 0xc3, 0x34, 0x12]);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+        let (bytes, text) = cpu.disassemble_next();
+        assert_eq!(bytes, vec![0xc3, 0x34, 0x12]);
+        assert_eq!(text, cpu.op_name(1));
+        assert!(text.contains("JMP") && text.contains("1234"), "{}", text);
+    }
+
+    /// `(opcode, a, operand, carry, aux_carry, result, [sign, zero, aux_carry, parity, carry])`
+    type FlagCase = (u8, u8, u8, bool, bool, u8, [bool; 5]);
+
+    /// Flag cases at the edges of carry and aux carry. Subtractions set aux carry on a borrow
+    /// from the low nibble, like carry.
+    const FLAG_CASES: [FlagCase; 18] = [
+        // ADI: carry out of the low nibble, then out of the byte
+        (0xc6, 0x0f, 0x01, false, false, 0x10, [false, false, true, false, false]),
+        (0xc6, 0xff, 0x01, false, false, 0x00, [false, true, true, true, true]),
+        // ACI: the carry in alone makes the low nibble carry
+        (0xce, 0x00, 0x0f, true, false, 0x10, [false, false, true, false, false]),
+        (0xce, 0xff, 0xff, true, false, 0xff, [true, false, true, true, true]),
+        // SUI: borrow from the high nibble
+        (0xd6, 0x10, 0x01, false, false, 0x0f, [false, false, true, true, false]),
+        // SBI: the borrow in alone makes the low nibble borrow
+        (0xde, 0x0f, 0x0f, true, false, 0xff, [true, false, true, true, true]),
+        (0xde, 0x00, 0xff, true, false, 0x00, [false, true, true, true, true]),
+        // CPI leaves A unchanged
+        (0xfe, 0x00, 0x01, false, false, 0x00, [true, false, true, true, true]),
+        // INR and DCR leave carry unchanged
+        (0x3c, 0x0f, 0x00, false, false, 0x10, [false, false, true, false, false]),
+        (0x3c, 0xff, 0x00, true, false, 0x00, [false, true, true, true, true]),
+        (0x3d, 0x00, 0x00, false, false, 0xff, [true, false, false, true, false]),
+        (0x3d, 0x10, 0x00, false, false, 0x0f, [false, false, false, true, false]),
+        (0x3d, 0x01, 0x00, false, false, 0x00, [false, true, true, true, false]),
+        // ANI: aux carry from bit 3 of either operand
+        (0xe6, 0x08, 0x00, true, false, 0x00, [false, true, true, true, false]),
+        // DAA: both digits corrected at once, none, from carry, from aux carry
+        (0x27, 0x9a, 0x00, false, false, 0x00, [false, true, true, true, true]),
+        (0x27, 0x99, 0x00, false, false, 0x99, [true, false, false, true, false]),
+        (0x27, 0x00, 0x00, true, false, 0x60, [false, false, false, true, true]),
+        (0x27, 0x12, 0x00, false, true, 0x18, [false, false, false, true, false]),
+    ];
+
+    #[test]
+    fn flag_edge_cases() {
+        for &(op_code, a, operand, carry, aux_carry, result, expected) in &FLAG_CASES {
+            let mut cpu = cpu_with(&[op_code, operand]);
+            cpu.a = a;
+            cpu.flags.carry = carry;
+            cpu.flags.aux_carry = aux_carry;
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+            let flags = cpu.flags();
+            assert_eq!(
+                (cpu.a(), [flags.sign, flags.zero, flags.aux_carry, flags.parity, flags.carry]),
+                (result, expected),
+                "{} with A={:02x}, operand={:02x}, carry={}, aux carry={}",
+                cpu.op_name(0),
+                a,
+                operand,
+                carry,
+                aux_carry
+            );
+        }
+    }
+
+    #[test]
+    fn interrupts_wake_a_halted_cpu_past_hlt() {
+        // LXI SP, $1000; EI; HLT; MVI A, $42, with a RET handler at $0008
+        let mut program = vec![0x31, 0x00, 0x10, 0xfb, 0x76, 0x3e, 0x42, 0x00];
+        program.push(0xc9);
+        let mut cpu = cpu_with(&program);
+        let mut io = SimpleIO::new();
+        for _ in 0..3 {
+            cpu.emulate(&mut io).unwrap();
+        }
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.emulate(&mut io).unwrap(), HALTED_CYCLES);
+
+        assert!(cpu.interrupt(1).unwrap());
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.stack(1), vec![5]);
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.pc(), 5);
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.a(), 0x42);
+        assert!(!cpu.is_halted());
+    }
+
+    #[test]
+    fn only_the_eight_rst_interrupts_exist() {
+        let mut cpu = cpu_with(&[0xfb, 0x00]);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+        assert!(matches!(cpu.interrupt(8), Err(Error::InvalidInterrupt(8))));
+        assert!(matches!(cpu.interrupt(8192), Err(Error::InvalidInterrupt(8192))));
+        assert!(cpu.interrupts_enabled());
+        assert!(cpu.interrupt(7).unwrap());
+        assert_eq!(cpu.pc(), 0x38);
+    }
+
+    #[test]
+    fn rotates_only_affect_a_and_carry() {
+        // (opcode, a, carry, result, carry)
+        let cases = [
+            // RLC and RRC copy the bit rotated out to carry
+            (0x07, 0x81, false, 0x03, true),
+            (0x07, 0x40, true, 0x80, false),
+            (0x0f, 0x81, false, 0xc0, true),
+            (0x0f, 0x02, true, 0x01, false),
+            // RAL and RAR rotate through carry
+            (0x17, 0x80, false, 0x00, true),
+            (0x17, 0x01, true, 0x03, false),
+            (0x1f, 0x01, false, 0x00, true),
+            (0x1f, 0x80, true, 0xc0, false),
+        ];
+
+        for &(op_code, a, carry, result, carry_out) in &cases {
+            let mut cpu = cpu_with(&[op_code]);
+            cpu.a = a;
+            cpu.flags.carry = carry;
+            cpu.flags.zero = true;
+            cpu.flags.parity = true;
+            cpu.flags.aux_carry = true;
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+            let name = cpu.op_name(0);
+            assert_eq!((cpu.a(), cpu.flags().carry), (result, carry_out), "{name} of {a:02x}");
+            assert_eq!((cpu.flags().zero, cpu.flags().sign), (true, false), "{name}");
+            assert_eq!((cpu.flags().parity, cpu.flags().aux_carry), (true, true), "{name}");
+        }
+    }
+
+    #[test]
+    fn registers_snapshot_matches_the_accessors() {
+        // LXI B, $0102; LXI D, $0304; LXI H, $0506; LXI SP, $0708; MVI A, $09; STC
+        let mut cpu = cpu_with(&[0x01, 0x02, 0x01, 0x11, 0x04, 0x03, 0x21, 0x06, 0x05, 0x31, 0x08, 0x07, 0x3e, 0x09, 0x37]);
+        let mut io = SimpleIO::new();
+        for _ in 0..6 {
+            cpu.emulate(&mut io).unwrap();
+        }
+
+        let registers = cpu.registers();
+        assert_eq!(
+            [registers.a, registers.b, registers.c, registers.d, registers.e, registers.h, registers.l],
+            [cpu.a(), cpu.b(), cpu.c(), cpu.d(), cpu.e(), cpu.h(), cpu.l()]
+        );
+        assert_eq!([registers.b, registers.c, registers.d, registers.e, registers.h, registers.l], [1, 2, 3, 4, 5, 6]);
+        assert_eq!((registers.sp, registers.pc, registers.a), (cpu.sp(), cpu.pc(), 9));
+        assert_eq!(registers.flags, *cpu.flags());
+        assert!(registers.flags.carry);
+    }
+
+    #[test]
+    fn boxed_memory_shrinks_the_cpu_state() {
+        let size = mem::size_of::<CpuState>();
+        if cfg!(feature = "boxed_memory") {
+            assert!(size < 1024, "{} bytes", size);
+        } else {
+            assert!(size > MEMORY_SIZE, "{} bytes", size);
+        }
+
+        // Memory behaves the same, wherever it lives
+        // LXI H, $3fff; MVI M, $42
+        let mut cpu = cpu_with(&[0x21, 0xff, 0x3f, 0x36, 0x42]);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        assert_eq!(cpu.memory().len(), MEMORY_SIZE);
+        assert_eq!(cpu.memory()[0x3fff], 0x42);
+    }
+
+    #[test]
+    fn writes_straddling_rom_write_neither_byte() {
+        // LXI H, $a55a; SHLD $1fff; SHLD $ffff
+        let mut cpu = CpuState::from_rom([0x21, 0x5a, 0xa5, 0x22, 0xff, 0x1f, 0x22, 0xff, 0xff], 0, 0).unwrap();
+        cpu.set_rom_end(0x2000);
+        cpu.set_strict(true);
+        let mut io = SimpleIO::new();
+        cpu.emulate(&mut io).unwrap();
+
+        // ROM, then RAM
+        assert!(matches!(cpu.emulate(&mut io), Err(Error::RomWrite(0x1fff))));
+        assert_eq!(cpu.memory()[0x2000], 0);
+
+        // RAM mirrored at $3fff, then ROM once wrapped around
+        cpu.pc = 6;
+        assert!(matches!(cpu.emulate(&mut io), Err(Error::RomWrite(0x0000))));
+        assert_eq!(cpu.memory()[0x3fff], 0);
+        assert_eq!(cpu.memory()[0x0000], 0x21);
+    }
+
+    #[test]
+    fn conditional_calls_take_longer_when_taken() {
+        assert_eq!((CpuState::cycles_for(0xc4, false), CpuState::cycles_for(0xc4, true)), (11, 17));
+        assert_eq!((CpuState::cycles_for(0x00, false), CpuState::cycles_for(0x00, true)), (4, 4));
+
+        for &(zero, cycles) in &[(true, 11), (false, 17)] {
+            // LXI SP, $1000; CNZ $0000
+            let mut cpu = cpu_with(&[0x31, 0x00, 0x10, 0xc4, 0x00, 0x00]);
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+            cpu.flags.zero = zero;
+            assert_eq!(cpu.emulate(&mut SimpleIO::new()).unwrap(), cycles);
+        }
+    }
+
+    #[test]
+    fn cold_resets_clear_ram_and_warm_resets_keep_it() {
+        for &(kind, kept) in &[(ResetKind::Cold, false), (ResetKind::Warm, true)] {
+            // MVI A, $42; STA $2000
+            let mut cpu = CpuState::from_rom([0x3e, 0x42, 0x32, 0x00, 0x20], 0, 0).unwrap();
+            cpu.set_rom_end(0x2000);
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+            assert_eq!(cpu.memory()[0x2000], 0x42);
+
+            cpu.reset(kind);
+            assert_eq!(cpu.pc(), 0);
+            assert_eq!(cpu.memory()[0x2000] == 0x42, kept, "{kind:?}");
+            assert_eq!(cpu.a() == 0x42, kept, "{kind:?}");
+            assert_eq!(cpu.is_written(0x2000), kept, "{kind:?}");
+            // ROM is never cleared
+            assert_eq!(cpu.memory()[..2], [0x3e, 0x42]);
+            assert!(cpu.is_written(0));
+        }
+    }
+
+    #[test]
+    fn stack_reads_the_top_first() {
+        // LXI SP, $1000; LXI B, $1111; LXI D, $2222; LXI H, $3333; PUSH B; PUSH D; PUSH H
+        let mut cpu = cpu_with(&[
+            0x31, 0x00, 0x10, 0x01, 0x11, 0x11, 0x11, 0x22, 0x22, 0x21, 0x33, 0x33, 0xc5, 0xd5, 0xe5,
+        ]);
+        for _ in 0..7 {
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+        }
+
+        assert_eq!(cpu.stack(3), vec![0x3333, 0x2222, 0x1111]);
+        assert_eq!(cpu.stack(0), vec![]);
+
+        // Reading past the top of the address space wraps around
+        cpu.sp = 0xfffe;
+        cpu.set_memory_slice(0, &[0x34, 0x12]).unwrap();
+        assert_eq!(cpu.stack(2)[1], 0x1234);
+    }
+
+    /// Program running every opcode at least once from $0100, then halting.
+    /// Branches lead to the next instruction whether taken or not, calls and returns are balanced,
+    /// and memory is only accessed at $2000, $2100 and on the stack at $3000.
+    fn every_opcode_program() -> Vec<u8> {
+        // RET for each RST
+        let mut program: Vec<u8> = (0..0x100).map(|address| if address % 8 == 0 { 0xc9 } else { 0x00 }).collect();
+        // LXI SP, $3000
+        program.extend(&[0x31, 0x00, 0x30]);
+
+        for op_code in (0..=0xff).filter(|&op_code| op_code != 0x76) {
+            let here = program.len() as u16;
+            let [here_6, here_7, here_8] = [here + 6, here + 7, here + 8];
+            let uses_m = match op_code {
+                0x34..=0x36 => true,
+                0x40..=0x7f => op_code & 0b111 == 6 || (op_code >> 3) & 0b111 == 6,
+                0x80..=0xbf => op_code & 0b111 == 6,
+                _ => false,
+            };
+
+            match op_code {
+                // Conditional returns, in a subroutine returning either way
+                _ if op_code & 0b1100_0111 == 0b1100_0000 => program.extend(&[
+                    0xcd, here_6 as u8, (here_6 >> 8) as u8, 0xc3, here_8 as u8, (here_8 >> 8) as u8, op_code, 0xc9,
+                ]),
+                // RET, called
+                0xc9 | 0xd9 => program.extend(&[
+                    0xcd, here_6 as u8, (here_6 >> 8) as u8, 0xc3, here_7 as u8, (here_7 >> 8) as u8, op_code,
+                ]),
+                // Calls, to a RET
+                _ if op_code & 0b1100_0111 == 0b1100_0100 || matches!(op_code, 0xcd | 0xdd | 0xed | 0xfd) => {
+                    program.extend(&[
+                        op_code, here_6 as u8, (here_6 >> 8) as u8, 0xc3, here_7 as u8, (here_7 >> 8) as u8, 0xc9,
+                    ]);
+                }
+                // Jumps, to the next instruction
+                _ if op_code & 0b1100_0111 == 0b1100_0010 || matches!(op_code, 0xc3 | 0xcb) => {
+                    program.extend(&[op_code, (here + 3) as u8, ((here + 3) >> 8) as u8]);
+                }
+                // PCHL, to the next instruction
+                0xe9 => program.extend(&[0x21, (here + 4) as u8, ((here + 4) >> 8) as u8, op_code]),
+                // SPHL, back to the stack
+                0xf9 => program.extend(&[0x21, 0x00, 0x30, op_code]),
+                // LXI SP stays on the stack
+                0x31 => program.extend(&[op_code, 0x00, 0x30]),
+                // LDA, STA, LHLD and SHLD
+                0x22 | 0x2a | 0x32 | 0x3a => program.extend(&[op_code, 0x00, 0x21]),
+                // STAX and LDAX, through BC or DE set to $2000
+                0x02 | 0x0a => program.extend(&[0x01, 0x00, 0x20, op_code]),
+                0x12 | 0x1a => program.extend(&[0x11, 0x00, 0x20, op_code]),
+                // Operands in memory, through HL set to $2000
+                _ if uses_m => {
+                    program.extend(&[0x21, 0x00, 0x20, op_code]);
+                    program.resize(program.len() + CpuState::opcode_length(op_code) as usize - 1, 0x55);
+                }
+                // Everything else, with immediate operands of $55
+                _ => {
+                    program.push(op_code);
+                    program.resize(program.len() + CpuState::opcode_length(op_code) as usize - 1, 0x55);
+                }
+            }
+        }
+
+        program.push(0x76);
+        program
+    }
+
+    #[test]
+    fn every_opcode_runs_in_one_program() {
+        let program = every_opcode_program();
+        let mut cpu = cpu_with(&program);
+        cpu.pc = 0x0100;
+        let mut io = SimpleIO::new();
+        io.set_input(0x55, 0x99);
+
+        let mut executed = [0; 256];
+        while !cpu.is_halted() {
+            executed[usize::from(cpu.read_byte(cpu.pc))] += 1;
+            cpu.emulate(&mut io).unwrap();
+            assert!(cpu.instruction_count() < 10_000, "stuck at ${:04x}", cpu.pc());
+        }
+
+        let missed: Vec<_> = (0..256).filter(|&op_code| executed[op_code] == 0).collect();
+        assert!(missed.is_empty(), "opcodes not executed: {:02x?}", missed);
+        // Halted past the HLT at the end, with the stack balanced and interrupts enabled by EI
+        assert_eq!(cpu.pc(), program.len() as u16);
+        assert_eq!(cpu.sp(), 0x3000);
+        assert!(cpu.interrupts_enabled());
+        assert_eq!(io.outputs().len(), 1);
+        assert_eq!(io.outputs()[0].0, 0x55);
+    }
+
+    #[test]
+    fn saved_psw_round_trips_on_its_flag_bits() {
+        let mut cpu = cpu_with(&[]);
+        cpu.flags.carry = true;
+        cpu.flags.zero = true;
+        let mut state = cpu.save_state();
+        // Bits 3 and 5 set and bit 1 cleared, as another emulator could save them
+        state[11] = state[11] & !0b10 | 0b0010_1000;
+
+        cpu.load_state(&state).unwrap();
+        let saved = cpu.save_state();
+        assert_ne!(saved[11], state[11]);
+        assert!(Flags::psw_eq(saved[11], state[11]));
+        assert!(cpu.flags().carry && cpu.flags().zero && !cpu.flags().sign);
+    }
+
+    #[test]
+    fn di_disables_interrupts_and_ei_enables_them_after_its_delay() {
+        // EI, NOP, DI, EI, NOP
+        let mut cpu = cpu_with(&[0xfb, 0x00, 0xf3, 0xfb, 0x00]);
+        let mut io = SimpleIO::new();
+        assert!(!cpu.interrupts_enabled());
+
+        cpu.emulate(&mut io).unwrap();
+        assert!(!cpu.interrupts_enabled());
+        cpu.emulate(&mut io).unwrap();
+        assert!(cpu.interrupts_enabled());
+
+        cpu.emulate(&mut io).unwrap();
+        assert!(!cpu.interrupts_enabled());
+        assert!(!cpu.interrupt(1).unwrap());
+        assert!(!cpu.interrupt_pending());
+
+        // Requested during the EI delay, the interrupt is latched until the NOP has executed
+        cpu.emulate(&mut io).unwrap();
+        assert!(!cpu.interrupt(1).unwrap());
+        assert!(cpu.interrupt_pending());
+        cpu.emulate(&mut io).unwrap();
+        assert!(cpu.interrupts_enabled());
+        assert!(cpu.interrupt_pending());
+        // Then serviced before the next instruction, which is the first of its handler
+        cpu.emulate(&mut io).unwrap();
+        assert!(!cpu.interrupt_pending());
+        assert_eq!(cpu.pc(), 0x0009);
+    }
+
+    #[test]
+    fn entering_unwritten_memory_warns_once() {
+        // MVI A, $01, then running off the end of the ROM into zeroed memory
+        let mut cpu = cpu_with(&[0x3e, 0x01]);
+        cpu.set_warn_unwritten(true);
+        let mut io = SimpleIO::new();
+
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.unwritten_entry, None);
+        for _ in 0..16 {
+            cpu.emulate(&mut io).unwrap();
+        }
+        // Only the first NOP past the ROM is reported
+        assert_eq!(cpu.unwritten_entry, Some(0x0002));
+    }
+
+    #[test]
+    fn conditional_returns_not_taken_advance_pc_by_one() {
+        // RZ, RC, RPE and RM aren't taken with every flag clear, RNZ, RNC, RPO and RP with every flag set
+        for &(op_code, flags_set) in &[
+            (0xc8, false), (0xd8, false), (0xe8, false), (0xf8, false),
+            (0xc0, true), (0xd0, true), (0xe0, true), (0xf0, true),
+        ] {
+            let mut cpu = cpu_with(&[op_code]);
+            cpu.sp = 0x1000;
+            cpu.flags = Flags { sign: flags_set, zero: flags_set, aux_carry: false, parity: flags_set, carry: flags_set };
+
+            assert_eq!(cpu.emulate(&mut SimpleIO::new()).unwrap(), 5, "opcode {op_code:02x}");
+            assert_eq!(cpu.pc(), 0x0001, "opcode {op_code:02x}");
+            assert_eq!(cpu.sp(), 0x1000, "opcode {op_code:02x}");
+        }
+    }
+
+    #[test]
+    fn cpu_states_are_built_from_slices_that_fit_in_memory() {
+        let cpu = CpuState::try_from(&[0x3e, 0x42][..]).unwrap();
+        assert_eq!(cpu.pc(), 0x0000);
+        assert_eq!(cpu.peek(0x0001), 0x42);
+
+        let oversized = vec![0; MEMORY_SIZE + 1];
+        assert!(matches!(
+            CpuState::try_from(&oversized[..]),
+            Err(Error::RomTooLarge { size, start: 0 }) if size == MEMORY_SIZE + 1
+        ));
+        // A Vec works just as well as a slice
+        assert!(CpuState::from_rom(vec![0; MEMORY_SIZE], 0, 0).is_ok());
+    }
+
+    #[test]
+    fn executed_instructions_leave_memory_as_they_found_it() {
+        let mut cpu = cpu_with(&[0x00, 0xaa, 0xbb]);
+        let mut io = SimpleIO::new();
+        cpu.a = 0x10;
+
+        // ADI $05
+        assert_eq!(cpu.execute(&[0xc6, 0x05], &mut io).unwrap(), 7);
+        assert_eq!(cpu.a, 0x15);
+        assert_eq!(cpu.pc(), 0x0002);
+        assert_eq!(cpu.memory()[..3], [0x00, 0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn executed_instructions_keep_what_they_wrote_over_themselves() {
+        let mut cpu = cpu_with(&[0x00, 0xaa, 0xbb]);
+        cpu.a = 0x77;
+
+        // STA $0001, writing over its own address
+        cpu.execute(&[0x32, 0x01, 0x00], &mut SimpleIO::new()).unwrap();
+        assert_eq!(cpu.memory()[..3], [0x00, 0x77, 0xbb]);
+    }
+
+    #[test]
+    fn executed_instructions_wrap_around_memory_and_leave_it_unwritten() {
+        let mut cpu = cpu_with(&[]);
+        cpu.pc = 0x3ffe;
+
+        // LXI H, $1234, its last byte at $4000, mirroring $0000
+        assert_eq!(cpu.execute(&[0x21, 0x34, 0x12], &mut SimpleIO::new()).unwrap(), 10);
+        assert_eq!(cpu.hl(), 0x1234);
+        assert_eq!(cpu.pc(), 0x4001);
+        assert_eq!((cpu.memory()[0x3ffe], cpu.memory()[0x3fff], cpu.memory()[0]), (0, 0, 0));
+        assert!(!cpu.is_written(0x3ffe) && !cpu.is_written(0x3fff) && !cpu.is_written(0));
+    }
+
+    #[test]
+    fn executed_instructions_run_after_a_pending_interrupt() {
+        // LXI SP, $1000; EI
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x10, 0xfb]);
+        let mut io = SimpleIO::new();
+        cpu.emulate(&mut io).unwrap();
+        cpu.emulate(&mut io).unwrap();
+        assert!(!cpu.interrupt(1).unwrap());
+
+        // Still in the EI delay, the interrupt stays latched
+        cpu.execute(&[0x00], &mut io).unwrap();
+        assert!(cpu.interrupt_pending());
+
+        // MVI A, $42, run as the first instruction of the handler
+        let cycles = cpu.execute(&[0x3e, 0x42], &mut io).unwrap();
+        assert_eq!(cycles, u64::from(CYCLES[0xcf]) + 7);
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.pc(), 0x000a);
+        assert_eq!(cpu.stack(1), vec![0x0005]);
+        assert_eq!(cpu.memory()[8..10], [0x00, 0x00]);
+    }
+
+    #[test]
+    fn immediate_words_are_read_low_byte_first() {
+        // LXI B, $1234; JMP $0123; at $0123: CALL $2345
+        let mut cpu = cpu_with(&[0x01, 0x34, 0x12, 0xc3, 0x23, 0x01]);
+        cpu.set_memory_slice(0x0123, &[0xcd, 0x45, 0x23]).unwrap();
+        cpu.sp = 0x1000;
+        let mut io = SimpleIO::new();
+
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.bc(), 0x1234);
+        assert_eq!((cpu.register(0), cpu.register(1)), (0x12, 0x34));
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.pc(), 0x0123);
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.pc(), 0x2345);
+        // The return address is pushed low byte first too
+        assert_eq!(cpu.memory()[0x0ffe..0x1000], [0x26, 0x01]);
+    }
+
+    /// Runs `cpu` until it halts
+    fn run_to_halt(cpu: &mut CpuState) {
+        let mut io = SimpleIO::new();
+        while !cpu.is_halted() {
+            cpu.emulate(&mut io).unwrap();
+            assert!(cpu.instruction_count() < 1000, "stuck at ${:04x}", cpu.pc());
+        }
+    }
+
+    #[test]
+    fn bcd_scores_and_counters_add_up() {
+        // Adding 10 points to a score of 0995, 2 BCD digits per byte, low byte first like the game:
+        // LXI SP, $2400; LXI H, $20f8; LXI D, $0010;
+        // MOV A, M; ADD E; DAA; MOV M, A; INX H; MOV A, M; ADC D; DAA; MOV M, A;
+        // then counting to 12 with INR: MVI B, $0c; MVI A, $00; INR A; DAA; DCR B; JNZ $0016; HLT
+        let mut cpu = cpu_with(&[
+            0x31, 0x00, 0x24, 0x21, 0xf8, 0x20, 0x11, 0x10, 0x00,
+            0x7e, 0x83, 0x27, 0x77, 0x23, 0x7e, 0x8a, 0x27, 0x77,
+            0x06, 0x0c, 0x3e, 0x00, 0x3c, 0x27, 0x05, 0xc2, 0x16, 0x00, 0x76,
+        ]);
+        cpu.set_memory_slice(0x20f8, &[0x95, 0x09]).unwrap();
+
+        // Stop after the score update
+        while cpu.pc() != 0x0012 {
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+        }
+        // $95 + $10 = $a5, DAA carries into the high byte: $09 + $00 + carry = $0a, adjusted to $10
+        assert_eq!(cpu.memory()[0x20f8..0x20fa], [0x05, 0x10]);
+        assert_eq!(cpu.a, 0x10);
+        assert_eq!(cpu.flags, Flags { sign: false, zero: false, aux_carry: true, parity: false, carry: false });
+
+        // INR leaves carry alone, so each DAA only sees the aux carry of the low digit
+        run_to_halt(&mut cpu);
+        assert_eq!(cpu.a, 0x12);
+        // Flags of the last DCR B, down to 0 from 1 so without a borrow out of the low nibble
+        assert_eq!(cpu.flags, Flags { sign: false, zero: true, aux_carry: true, parity: true, carry: false });
+    }
+
+    #[test]
+    fn bcd_counters_wrap_from_99() {
+        // STC; CMC; MVI A, $99; INR A; DAA; HLT
+        let mut cpu = cpu_with(&[0x37, 0x3f, 0x3e, 0x99, 0x3c, 0x27, 0x76]);
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        // $99 + 1 = $9a, without aux carry as 9 + 1 fits in the low nibble
+        assert_eq!(cpu.a, 0x9a);
+        assert!(!cpu.flags.aux_carry);
+        assert!(!cpu.flags.carry);
+
+        // The low digit correction carries into the 9 of the high digit, wrapping to 00
+        run_to_halt(&mut cpu);
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.flags.zero);
+        assert!(cpu.flags.carry);
+        assert!(cpu.flags.aux_carry);
+    }
+
+    /// Not a test but a benchmark of memory accesses, to compare the array and boxed layouts:
+    /// `cargo test --release memory_access_rate -- --ignored --nocapture`, with and without `boxed_memory`.
+    /// It lives here rather than in `benches/`, which can't reach the CPU's memory without a library target.
+    #[test]
+    #[ignore = "benchmark, run on its own in release builds"]
+    fn memory_access_rate() {
+        const ACCESSES: u32 = 1 << 26;
+        let layout = if cfg!(feature = "boxed_memory") { "Boxed" } else { "Array" };
+        let mut cpu = cpu_with(&[]);
+        let mut checksum = 0u8;
+
+        let start = std::time::Instant::now();
+        for index in 0..ACCESSES {
+            let address = std::hint::black_box(index as u16 & 0x3fff);
+            cpu.write_byte(address, index as u8).unwrap();
+            checksum = checksum.wrapping_add(cpu.read_byte(address));
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "{} memory: {:.0} million reads and writes per second (checksum {:02x})",
+            layout,
+            2. * f64::from(ACCESSES) / elapsed.as_secs_f64() / 1e6,
+            checksum
+        );
+    }
+
+    #[test]
+    fn calls_return_to_the_following_instruction() {
+        // LXI SP, $1000; CALL $0010; HLT; at $0010: CALL $0020; RET; at $0020: RET
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x10, 0xcd, 0x10, 0x00, 0x76]);
+        cpu.set_memory_slice(0x0010, &[0xcd, 0x20, 0x00, 0xc9]).unwrap();
+        cpu.set_memory_slice(0x0020, &[0xc9]).unwrap();
+        let mut io = SimpleIO::new();
+        cpu.emulate(&mut io).unwrap();
+
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 17);
+        assert_eq!((cpu.pc(), cpu.stack(1)), (0x0010, vec![0x0006]));
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 17);
+        assert_eq!((cpu.pc(), cpu.stack(2)), (0x0020, vec![0x0013, 0x0006]));
+
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 10);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x0013, 0x0ffe));
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 10);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x0006, 0x1000));
+        assert_eq!(cpu.read_byte(cpu.pc()), 0x76);
+    }
+
+    #[test]
+    fn dad_only_changes_carry() {
+        // LXI H, $8000; LXI B, $8001; DAD B; DAD H
+        let mut cpu = cpu_with(&[0x21, 0x00, 0x80, 0x01, 0x01, 0x80, 0x09, 0x29]);
+        let mut io = SimpleIO::new();
+        let others = Flags { sign: true, zero: true, aux_carry: true, parity: true, carry: false };
+        cpu.flags = others;
+        cpu.emulate(&mut io).unwrap();
+        cpu.emulate(&mut io).unwrap();
+
+        // $8000 + $8001 overflows
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 10);
+        assert_eq!(cpu.hl(), 0x0001);
+        assert_eq!(cpu.flags, Flags { carry: true, ..others });
+
+        // Without overflow, carry is cleared and the other flags still untouched
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.hl(), 0x0002);
+        assert_eq!(cpu.flags, others);
+    }
+
+    /// A and carry after running the rotate `op_code` on `a` with `carry`
+    fn rotated(op_code: u8, a: u8, carry: bool) -> (u8, bool) {
+        let mut cpu = cpu_with(&[op_code]);
+        cpu.a = a;
+        cpu.flags.carry = carry;
+        assert_eq!(cpu.emulate(&mut SimpleIO::new()).unwrap(), 4);
+        (cpu.a(), cpu.flags().carry)
+    }
+
+    #[test]
+    fn rar_rotates_the_old_carry_into_bit_7() {
+        // RAR
+        assert_eq!(rotated(0x1f, 0x01, true), (0x80, true));
+        assert_eq!(rotated(0x1f, 0x01, false), (0x00, true));
+        assert_eq!(rotated(0x1f, 0x80, false), (0x40, false));
+        assert_eq!(rotated(0x1f, 0x02, true), (0x81, false));
+        // RRC copies bit 0 into bit 7 instead, whatever the carry
+        assert_eq!(rotated(0x0f, 0x01, false), (0x80, true));
+        assert_eq!(rotated(0x0f, 0x02, true), (0x01, false));
+    }
+
+    #[test]
+    fn ral_rotates_the_old_carry_into_bit_0() {
+        // RAL
+        assert_eq!(rotated(0x17, 0x80, false), (0x00, true));
+        assert_eq!(rotated(0x17, 0x00, true), (0x01, false));
+        assert_eq!(rotated(0x17, 0x81, true), (0x03, true));
+        // RLC copies bit 7 into bit 0 instead, whatever the carry
+        assert_eq!(rotated(0x07, 0x80, false), (0x01, true));
+        assert_eq!(rotated(0x07, 0x00, true), (0x00, false));
+
+        // Rotating twice carries bit 7 back round into bit 0, like a 9-bit rotate
+        let mut cpu = cpu_with(&[0x17, 0x17]);
+        cpu.a = 0x80;
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        assert_eq!((cpu.a(), cpu.flags().carry), (0x01, false));
+    }
+
+    #[test]
+    fn flags_agree_with_the_low_byte_of_af() {
+        for psw in 0..=255u8 {
+            // POP PSW, popping `psw` and A=$5a
+            let mut cpu = cpu_with(&[0xf1, psw, 0x5a]);
+            cpu.sp = 0x0001;
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+
+            let flags = cpu.flags();
+            let af = cpu.af();
+            assert_eq!(af >> 8, 0x5a);
+            assert_eq!(
+                [flags.sign, flags.zero, flags.aux_carry, flags.parity, flags.carry],
+                [7, 6, 4, 2, 0].map(|bit| af & 1 << bit != 0),
+                "PSW {psw:02x}"
+            );
+            // Only the flag bits are kept, with bit 1 always set
+            assert_eq!(af as u8, psw & Flags::PSW_MASK | Flags::PSW_ALWAYS_SET);
+        }
+    }
+
+    #[test]
+    fn emulate_counts_each_instruction_once() {
+        // MVI A, $01; INR A; LXI B, $1234; NOP; JMP $0000
+        let mut cpu = cpu_with(&[0x3e, 0x01, 0x3c, 0x01, 0x34, 0x12, 0x00, 0xc3, 0x00, 0x00]);
+        assert_eq!(cpu.instruction_count(), 0);
+
+        let cycles: u64 = (0..5).map(|_| cpu.emulate(&mut SimpleIO::new()).unwrap()).sum();
+        assert_eq!(cpu.instruction_count(), 5);
+        assert_eq!(cycles, 7 + 5 + 10 + 4 + 10);
+    }
+
+    #[test]
+    fn diffs_describe_exactly_the_differing_fields() {
+        let expected = CpuState::new().registers();
+        assert!(expected.diff(&expected).is_empty());
+
+        let mut actual = Registers { a: 0x3d, ..expected };
+        actual.flags.carry = true;
+        assert_eq!(
+            Registers { a: 0x3c, ..expected }.diff(&actual),
+            ["A: expected 0x3c, got 0x3d", "carry flag: expected false, got true"]
+        );
+
+        assert_eq!(expected.diff(&Registers { pc: 0x1234, ..expected }), ["PC: expected 0x0000, got 0x1234"]);
+    }
+
+    #[test]
+    fn programs_written_through_memory_mut_run() {
+        // ROM covers all of the program, which `write_byte` would refuse to write
+        let mut cpu = CpuState::from_rom([0x00; 0x10], 0, 0).unwrap();
+        // MVI A, $41; INR A; STA $3000
+        cpu.memory_mut()[..6].copy_from_slice(&[0x3e, 0x41, 0x3c, 0x32, 0x00, 0x30]);
+
+        for _ in 0..3 {
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+        }
+        assert_eq!(cpu.a(), 0x42);
+        assert_eq!(cpu.memory()[0x3000], 0x42);
+        assert_eq!(cpu.pc(), 0x0006);
+    }
+
+    #[test]
+    fn effective_addresses_follow_the_registers_and_operands() {
+        // LXI H, $2410; MOV A, M; LDA $1234; STAX D; NOP
+        let mut cpu = cpu_with(&[0x21, 0x10, 0x24, 0x7e, 0x3a, 0x34, 0x12, 0x12, 0x00]);
+        let mut io = SimpleIO::new();
+
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.effective_address(0x7e), Some(0x2410));
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.effective_address(0x3a), Some(0x1234));
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.effective_address(0x12), Some(cpu.de()));
+
+        // Instructions not accessing memory through an operand
+        for op_code in [0x00, 0x76, 0x3c, 0xc3] {
+            assert_eq!(cpu.effective_address(op_code), None, "{op_code:02x}");
+        }
+    }
+
+    #[test]
+    fn push_psw_puts_a_above_the_flags() {
+        // LXI SP, $1000; STC; MVI A, $3c; PUSH PSW; POP B
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x10, 0x37, 0x3e, 0x3c, 0xf5, 0xc1]);
+        let mut io = SimpleIO::new();
+        for _ in 0..4 {
+            cpu.emulate(&mut io).unwrap();
+        }
+
+        // A is pushed first, to the higher address
+        assert_eq!(cpu.sp(), 0x0ffe);
+        assert_eq!(cpu.memory()[0x0fff], 0x3c);
+        assert_eq!(cpu.memory()[0x0ffe], Flags::PSW_ALWAYS_SET | 1);
+        assert_eq!(u16::from_le_bytes([cpu.memory()[0x0ffe], cpu.memory()[0x0fff]]), cpu.af());
+
+        // Popped into a pair, A is the high byte like in AF
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.bc(), cpu.af());
+
+        // Every flag set, with bit 1 as the 8080 always has it
+        cpu.set_af(0x5ad7);
+        assert_eq!((cpu.a(), cpu.af()), (0x5a, 0x5ad7));
+    }
+
+    #[test]
+    fn write_logs_keep_at_most_their_capacity() {
+        // LXI H, $2000; MOV M, L; INX H; JMP $0003, writing $00 to $2000, $01 to $2001 and so on
+        let program = [0x21, 0x00, 0x20, 0x75, 0x23, 0xc3, 0x03, 0x00];
+        let logged = |policy| {
+            let mut cpu = cpu_with(&program);
+            cpu.set_write_log(Some((3, policy)));
+            let mut io = SimpleIO::new();
+            for _ in 0..=(3 * 5) {
+                cpu.emulate(&mut io).unwrap();
+                assert!(cpu.write_log().count() <= 3);
+            }
+            cpu.write_log().map(|write| (write.address, write.value)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(logged(LogPolicy::DropOldest), [(0x2002, 0x02), (0x2003, 0x03), (0x2004, 0x04)]);
+        assert_eq!(logged(LogPolicy::Stop), [(0x2000, 0x00), (0x2001, 0x01), (0x2002, 0x02)]);
+
+        let mut cpu = cpu_with(&program);
+        cpu.set_write_log(Some((0, LogPolicy::DropOldest)));
+        for _ in 0..4 {
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+        }
+        assert_eq!(cpu.write_log().count(), 0);
+        // Writes record the instruction doing them
+        cpu.set_write_log(Some((1, LogPolicy::DropOldest)));
+        for _ in 0..3 {
+            cpu.emulate(&mut SimpleIO::new()).unwrap();
+        }
+        assert_eq!(cpu.write_log().next().map(|write| write.pc), Some(0x0003));
+    }
+
+    #[test]
+    fn strings_are_read_up_to_their_terminator() {
+        let mut cpu = CpuState::new();
+        cpu.set_memory_slice(0x2100, b"HELLO, WORLD$GARBAGE").unwrap();
+        assert_eq!(cpu.read_string(0x2100, b'$'), "HELLO, WORLD");
+        assert_eq!(cpu.read_string(0x210c, b'$'), "");
+
+        // Unterminated strings stop at the end of memory
+        let end = (MEMORY_SIZE - 3) as u16;
+        cpu.set_memory_slice(end, b"ABC").unwrap();
+        assert_eq!(cpu.read_string(end, b'$'), "ABC");
+        assert_eq!(cpu.read_string(0xffff, b'$'), "");
+    }
+
+    #[test]
+    fn taken_jumps_land_exactly_on_their_target() {
+        // JMP $0010; at $0010: JNZ $0020, then JZ $0030
+        let mut cpu = cpu_with(&[0xc3, 0x10, 0x00]);
+        cpu.set_memory_slice(0x0010, &[0xc2, 0x20, 0x00, 0xca, 0x30, 0x00]).unwrap();
+        let mut io = SimpleIO::new();
+
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 10);
+        assert_eq!(cpu.pc(), 0x0010);
+
+        // Not taken with zero set, going on to the next instruction
+        cpu.flags.zero = true;
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 10);
+        assert_eq!(cpu.pc(), 0x0013);
+
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 10);
+        assert_eq!(cpu.pc(), 0x0030);
+    }
+
+    #[test]
+    fn total_cycles_add_up_until_reset() {
+        // MVI A, $01; LXI B, $1234; INX B; NOP; JMP $0000
+        let mut cpu = cpu_with(&[0x3e, 0x01, 0x01, 0x34, 0x12, 0x03, 0x00, 0xc3, 0x00, 0x00]);
+        let mut io = SimpleIO::new();
+
+        let mut expected = 0;
+        for &cycles in &[7, 10, 5, 4, 10, 7] {
+            assert_eq!(cpu.emulate(&mut io).unwrap(), cycles);
+            expected += cycles;
+            assert_eq!(cpu.total_cycles(), expected);
+        }
+
+        cpu.reset(ResetKind::Warm);
+        assert_eq!(cpu.total_cycles(), 0);
+    }
+
+    #[test]
+    fn cycle_listings_show_both_costs_of_conditional_instructions() {
+        // NOP; CNZ $1234; RZ; JMP $0000
+        let cpu = cpu_with(&[0x00, 0xc4, 0x34, 0x12, 0xc8, 0xc3, 0x00, 0x00]);
+        let listing = cpu.listing(0, 8, true);
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert_eq!(lines[1], "\tNOP\t; $0000, 4 cycles");
+        assert_eq!(lines[2], "\tCNZ $1234\t; $0001, 11/17 cycles");
+        assert_eq!(lines[3], "\tRZ\t; $0004, 5/11 cycles");
+        // Conditional jumps take 10 cycles either way
+        assert_eq!(lines[4], "\tJMP $0000\t; $0005, 10 cycles");
+        assert_eq!(CpuState::cycle_annotation(0xc2), "10");
+    }
+
+    #[test]
+    fn jumps_into_an_operand_are_reported_as_misaligned() {
+        // LXI B, $0000; JMP $0001 into the LXI's operand, running it as two NOPs
+        let mut cpu = cpu_with(&[0x01, 0x00, 0x00, 0xc3, 0x01, 0x00]);
+        cpu.set_check_alignment(true);
+        let mut io = SimpleIO::new();
+        for _ in 0..5 {
+            cpu.emulate(&mut io).unwrap();
+        }
+        assert_eq!(cpu.misaligned_instructions(), [0x0001, 0x0002]);
+
+        // With RAM from $1000, $4005 mirrors $1005: JMP $1004; at $1004: LXI B, $0000; JMP $4005
+        let mut cpu = CpuState::from_rom([0xc3, 0x04, 0x10], 0, 0).unwrap();
+        cpu.set_rom_end(0x1000);
+        cpu.set_memory_slice(0x1004, &[0x01, 0x00, 0x00, 0xc3, 0x05, 0x40]).unwrap();
+        cpu.set_check_alignment(true);
+        for _ in 0..6 {
+            cpu.emulate(&mut io).unwrap();
+        }
+        assert_eq!(cpu.misaligned_instructions(), [0x4005, 0x4006]);
+    }
+
+    #[test]
+    fn fresh_cpus_have_bit_1_set_and_every_flag_clear() {
+        let mut cpu = cpu_with(&[0x37]);
+        assert_eq!(cpu.af(), 0x0002);
+        assert_eq!(*cpu.flags(), Flags::POWER_ON);
+        assert!(!(cpu.flags.sign || cpu.flags.zero || cpu.flags.aux_carry || cpu.flags.parity || cpu.flags.carry));
+
+        // Cold resets clear them again. STC sets carry.
+        cpu.emulate(&mut SimpleIO::new()).unwrap();
+        assert_eq!(cpu.af(), 0x0003);
+        cpu.reset(ResetKind::Cold);
+        assert_eq!(cpu.af(), 0x0002);
+    }
+
+    #[test]
+    fn conditional_instructions_cost_the_cycles_of_their_outcome() {
+        let mut io = SimpleIO::new();
+
+        // Conditions NZ, Z, NC, C, PO, PE, P and M test a flag against the low bit of their index
+        for condition in 0..8u8 {
+            for &taken in &[false, true] {
+                let flag = (condition & 1 == 1) == taken;
+                let cpu_at = |op_code: u8| {
+                    // op_code $0200 at $0100, with $0300 on top of the stack
+                    let mut cpu = cpu_with(&[]);
+                    cpu.set_memory_slice(0x0100, &[op_code, 0x00, 0x02]).unwrap();
+                    cpu.set_memory_slice(0x1000, &[0x00, 0x03]).unwrap();
+                    cpu.pc = 0x0100;
+                    cpu.sp = 0x1000;
+                    match condition >> 1 {
+                        0 => cpu.flags.zero = flag,
+                        1 => cpu.flags.carry = flag,
+                        2 => cpu.flags.parity = flag,
+                        _ => cpu.flags.sign = flag,
+                    }
+                    cpu
+                };
+
+                // Jcc: 10 cycles either way
+                let mut cpu = cpu_at(0xc2 | condition << 3);
+                assert_eq!(cpu.emulate(&mut io).unwrap(), 10, "J{condition} taken: {taken}");
+                assert_eq!(cpu.pc, if taken { 0x0200 } else { 0x0103 }, "J{condition} taken: {taken}");
+
+                // Ccc: 17 cycles pushing the return address, 11 otherwise
+                let mut cpu = cpu_at(0xc4 | condition << 3);
+                assert_eq!(cpu.emulate(&mut io).unwrap(), if taken { 17 } else { 11 }, "C{condition} taken: {taken}");
+                assert_eq!(cpu.pc, if taken { 0x0200 } else { 0x0103 }, "C{condition} taken: {taken}");
+                assert_eq!(cpu.sp, if taken { 0x0ffe } else { 0x1000 }, "C{condition} taken: {taken}");
+                if taken {
+                    assert_eq!(cpu.stack(1), [0x0103]);
+                }
+
+                // Rcc: 11 cycles popping the return address, 5 otherwise
+                let mut cpu = cpu_at(0xc0 | condition << 3);
+                assert_eq!(cpu.emulate(&mut io).unwrap(), if taken { 11 } else { 5 }, "R{condition} taken: {taken}");
+                assert_eq!(cpu.pc, if taken { 0x0300 } else { 0x0101 }, "R{condition} taken: {taken}");
+                assert_eq!(cpu.sp, if taken { 0x1002 } else { 0x1000 }, "R{condition} taken: {taken}");
+            }
+        }
+    }
+
+    #[test]
+    fn interrupt_latency_is_charged_before_the_handler_only_when_set() {
+        for &latency in &[0, 5] {
+            // EI; NOP, with a NOP handler at $0008
+            let mut cpu = cpu_with(&[0xfb, 0x00]);
+            let mut io = SimpleIO::new();
+            cpu.sp = 0x1000;
+            cpu.set_interrupt_latency(latency);
+            cpu.emulate(&mut io).unwrap();
+            cpu.emulate(&mut io).unwrap();
+            let before = cpu.total_cycles();
+
+            assert!(cpu.interrupt(1).unwrap());
+            assert_eq!(cpu.pc(), 0x0008);
+            // The handler's NOP takes the RST and the latency with it
+            assert_eq!(cpu.emulate(&mut io).unwrap(), 4 + 11 + latency, "latency {latency}");
+            assert_eq!(cpu.total_cycles() - before, 4 + 11 + latency, "latency {latency}");
+        }
+    }
+
+    #[test]
+    fn the_current_interrupt_lasts_until_its_handler_returns() {
+        // EI; NOP; NOP, with an RST 2 handler calling a subroutine before returning
+        let mut cpu = cpu_with(&[0xfb, 0x00, 0x00]);
+        // At $0010: CALL $0020; RET, at $0020: RET
+        cpu.set_memory_slice(0x0010, &[0xcd, 0x20, 0x00, 0xc9]).unwrap();
+        cpu.set_memory_slice(0x0020, &[0xc9]).unwrap();
+        cpu.sp = 0x1000;
+        let mut io = SimpleIO::new();
+        cpu.emulate(&mut io).unwrap();
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.current_interrupt(), None);
+
+        assert!(cpu.interrupt(2).unwrap());
+        assert_eq!(cpu.current_interrupt(), Some(2));
+        // The subroutine's return stays in the handler
+        for &pc in &[0x0020, 0x0013] {
+            cpu.emulate(&mut io).unwrap();
+            assert_eq!(cpu.pc(), pc);
+            assert_eq!(cpu.current_interrupt(), Some(2));
+        }
+
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.pc(), 0x0002);
+        assert_eq!(cpu.current_interrupt(), None);
+    }
+
+    #[test]
+    fn running_past_ffff_breaks_or_wraps_by_policy() {
+        // NOP at $ffff, mirroring $3fff
+        let mut cpu = cpu_with(&[0x00]);
+        cpu.pc = 0xffff;
+        let mut io = SimpleIO::new();
+
+        cpu.set_pc_wrap_policy(PcWrapPolicy::Break);
+        assert!(matches!(cpu.emulate(&mut io), Err(Error::PcWrap(0xffff))));
+        assert_eq!(cpu.pc(), 0x0000);
+        assert_eq!(Error::PcWrap(0xffff).to_string(), "Execution ran past $ffff at $ffff, the program is probably running away");
+
+        // Ignored by default, execution going on at $0000
+        cpu.set_pc_wrap_policy(PcWrapPolicy::Ignore);
+        cpu.pc = 0xffff;
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 4);
+        assert_eq!(cpu.pc(), 0x0000);
+        assert_eq!(cpu.emulate(&mut io).unwrap(), 4);
+        assert_eq!(cpu.pc(), 0x0001);
+    }
+
+    #[test]
+    fn branches_at_the_top_of_memory_only_wrap_when_not_taken() {
+        let mut io = SimpleIO::new();
+        // JMP $0100 and JZ $0100 at $fffd, RET and RST 1 at $ffff, mirroring $3ffd and $3fff
+        for &(address, ref bytes, zero, wraps) in &[
+            (0xfffd, vec![0xc3, 0x00, 0x01], false, false),
+            (0xfffd, vec![0xca, 0x00, 0x01], true, false),
+            (0xfffd, vec![0xca, 0x00, 0x01], false, true),
+            (0xffff, vec![0xc9], false, false),
+            (0xffff, vec![0xcf], false, false),
+        ] {
+            let mut cpu = cpu_with(&[]);
+            cpu.set_memory_slice(address & 0x3fff, bytes).unwrap();
+            cpu.set_memory_slice(0x2000, &[0x00, 0x01]).unwrap();
+            cpu.sp = 0x2000;
+            cpu.pc = address;
+            cpu.flags.zero = zero;
+            cpu.set_pc_wrap_policy(PcWrapPolicy::Break);
+
+            let result = cpu.emulate(&mut io);
+            assert_eq!(matches!(result, Err(Error::PcWrap(a)) if a == address), wraps, "{bytes:02x?}");
+            if !wraps {
+                assert_ne!(cpu.pc(), 0x0000, "{bytes:02x?}");
+            }
+        }
+    }
+
+    #[test]
+    fn cycle_tables_match_what_emulate_returns() {
+        for op_code in 0..=0xffu8 {
+            let conditional = matches!(op_code & 0b1100_0111, 0b1100_0000 | 0b1100_0100);
+            // Only conditional calls and returns take longer when their condition is met
+            assert_eq!(TAKEN_EXTRA_CYCLES[op_code as usize], if conditional { 6 } else { 0 }, "{op_code:02x}");
+
+            for &flag in &[false, true] {
+                let mut cpu = cpu_with(&[op_code, 0x00, 0x00]);
+                cpu.sp = 0x3000;
+                cpu.flags = Flags { sign: flag, zero: flag, aux_carry: flag, parity: flag, carry: flag };
+                // Conditions test a flag against the low bit of their index
+                let taken = conditional && flag == (op_code >> 3 & 1 == 1);
+
+                let cycles = cpu.emulate(&mut SimpleIO::new()).unwrap();
+                assert_eq!(cycles, CpuState::cycles_for(op_code, taken), "{op_code:02x} with flags {flag}");
+                assert_eq!(
+                    cycles,
+                    u64::from(CYCLES[op_code as usize] + if taken { TAKEN_EXTRA_CYCLES[op_code as usize] } else { 0 }),
+                    "{op_code:02x} with flags {flag}"
+                );
+            }
+        }
+
+        // A few datasheet timings: NOP, MOV M,A, LXI, INX, DAD, CALL, RST, XTHL
+        let datasheet = [(0x00, 4), (0x77, 7), (0x21, 10), (0x23, 5), (0x29, 10), (0xcd, 17), (0xff, 11), (0xe3, 18)];
+        for &(op_code, cycles) in &datasheet {
+            assert_eq!(CYCLES[op_code as usize], cycles, "{op_code:02x}");
+        }
+    }
+
+    #[test]
+    fn every_pair_round_trips_through_the_stack() {
+        let mut cpu = cpu_with(&[
+            // LXI SP, $1000; LXI B, $1234; LXI D, $5678; LXI H, $9abc; MVI A, $de
+            0x31, 0x00, 0x10, 0x01, 0x34, 0x12, 0x11, 0x78, 0x56, 0x21, 0xbc, 0x9a, 0x3e, 0xde,
+            // PUSH B; PUSH D; PUSH H; PUSH PSW
+            0xc5, 0xd5, 0xe5, 0xf5,
+            // LXI B, $0000; LXI D, $0000; LXI H, $0000; MVI A, $00
+            0x01, 0x00, 0x00, 0x11, 0x00, 0x00, 0x21, 0x00, 0x00, 0x3e, 0x00,
+            // POP PSW; POP H; POP D; POP B; POP PSW
+            0xf1, 0xe1, 0xd1, 0xc1, 0xf1,
+        ]);
+        // Popped last, with every bit set
+        cpu.set_memory_slice(0x1000, &[0xff, 0xff]).unwrap();
+        let mut io = SimpleIO::new();
+        for _ in 0..5 {
+            cpu.emulate(&mut io).unwrap();
+        }
+        cpu.flags = Flags { sign: true, zero: true, carry: true, ..Flags::POWER_ON };
+
+        // The high byte goes to the higher address, SP going down by 2
+        let pushed = [(0x0ffe, [0x34, 0x12]), (0x0ffc, [0x78, 0x56]), (0x0ffa, [0xbc, 0x9a]), (0x0ff8, [0xc3, 0xde])];
+        for &(sp, bytes) in &pushed {
+            cpu.emulate(&mut io).unwrap();
+            assert_eq!(cpu.sp(), sp);
+            assert_eq!(cpu.memory()[sp as usize..sp as usize + 2], bytes, "at ${sp:04x}");
+        }
+
+        for _ in 0..4 {
+            cpu.emulate(&mut io).unwrap();
+        }
+        // Loads leave the flags as they are
+        assert_eq!((cpu.bc(), cpu.de(), cpu.hl(), cpu.af()), (0, 0, 0, 0x00c3));
+
+        // Popped back in the reverse order, SP going up by 2
+        for &sp in &[0x0ffa, 0x0ffc, 0x0ffe, 0x1000] {
+            cpu.emulate(&mut io).unwrap();
+            assert_eq!(cpu.sp(), sp);
+        }
+        assert_eq!((cpu.af(), cpu.hl(), cpu.de(), cpu.bc()), (0xdec3, 0x9abc, 0x5678, 0x1234));
+
+        // POP PSW clears bits 3 and 5 and sets bit 1, whatever was pushed
+        cpu.emulate(&mut io).unwrap();
+        assert_eq!(cpu.sp(), 0x1002);
+        assert_eq!(cpu.af(), 0xffd7);
+    }
+
+    #[test]
+    fn parity_and_sign_calls_follow_their_polarity() {
+        // ORA A of $03 gives even parity and a clear sign, of $83 odd parity and a set sign
+        let calls = [
+            // CPE: parity even
+            (0xec, 0x03, true),
+            (0xec, 0x83, false),
+            // CPO: parity odd
+            (0xe4, 0x83, true),
+            (0xe4, 0x03, false),
+            // CP: plus, sign clear
+            (0xf4, 0x03, true),
+            (0xf4, 0x83, false),
+            // CM: minus, sign set
+            (0xfc, 0x83, true),
+            (0xfc, 0x03, false),
+        ];
+
+        for &(op_code, value, taken) in &calls {
+            // MVI A, value; ORA A; call $0200
+            let mut cpu = cpu_with(&[0x3e, value, 0xb7, op_code, 0x00, 0x02]);
+            cpu.sp = 0x1000;
+            let mut io = SimpleIO::new();
+            cpu.emulate(&mut io).unwrap();
+            cpu.emulate(&mut io).unwrap();
+            assert_eq!(cpu.flags.parity, value == 0x03);
+            assert_eq!(cpu.flags.sign, value == 0x83);
+
+            cpu.emulate(&mut io).unwrap();
+            if taken {
+                assert_eq!(cpu.pc(), 0x0200, "{op_code:02x} of {value:02x}");
+                assert_eq!(cpu.sp(), 0x0ffe);
+                assert_eq!(cpu.stack(1), [0x0006]);
+            } else {
+                assert_eq!(cpu.pc(), 0x0006, "{op_code:02x} of {value:02x}");
+                assert_eq!(cpu.sp(), 0x1000);
+            }
+        }
+    }
 }
@@ -0,0 +1,89 @@
+use std::{error, fmt, io};
+
+use crate::hex::HexError;
+
+/// Errors raised by the emulator
+#[derive(Debug)]
+pub enum Error {
+    /// Write to read-only memory at the specified address
+    RomWrite(u16),
+    /// Read from an input port the machine doesn't have
+    InvalidInputPort(u8),
+    /// Write to an output port the machine doesn't have
+    InvalidOutputPort(u8),
+    /// ROM too large to be loaded at the requested address
+    RomTooLarge { size: usize, start: usize },
+    /// Memory access of `size` bytes at `address` past the end of memory
+    OutOfMemory { address: u16, size: usize },
+    /// Instruction at the specified address running past the end of the address space, back to $0000
+    PcWrap(u16),
+    /// Save state which is malformed or was saved with a different ROM
+    InvalidState(String),
+    /// Image file which is malformed or in an unsupported format
+    InvalidImage(String),
+    /// Sound file which is malformed or in an unsupported format
+    InvalidSample(String),
+    /// Interrupt other than RST 0 to RST 7
+    InvalidInterrupt(u16),
+    /// Interrupt schedule which is empty, out of order or outside of the frame
+    InvalidInterruptSchedule(String),
+    /// Clock speed in Hz too slow to fit the interrupt schedule in a frame
+    InvalidClockSpeed(u64),
+    /// Screen buffer whose size doesn't match the window
+    BufferSize { expected: usize, actual: usize },
+    /// Window which kept failing to show the screen
+    Present(String),
+    /// Audio device which couldn't be opened
+    #[cfg(feature = "audio")]
+    Audio(String),
+    /// Malformed Intel HEX file
+    Hex(HexError),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Error::RomWrite(address) => write!(f, "Trying to write to ROM at address ${address:04x}"),
+            Error::InvalidInputPort(port) => write!(f, "Cannot read port: {port}"),
+            Error::InvalidOutputPort(port) => write!(f, "Cannot write to port: {port}"),
+            Error::RomTooLarge { size, start } => {
+                write!(f, "ROM of {size} bytes does not fit in memory at ${start:04x}")
+            }
+            Error::OutOfMemory { address, size } => {
+                write!(f, "{size} bytes at ${address:04x} do not fit in memory")
+            }
+            Error::PcWrap(address) => {
+                write!(f, "Execution ran past $ffff at ${address:04x}, the program is probably running away")
+            }
+            Error::InvalidState(reason) => write!(f, "Invalid state: {reason}"),
+            Error::InvalidImage(reason) => write!(f, "Invalid image: {reason}"),
+            Error::InvalidSample(reason) => write!(f, "Invalid sample: {reason}"),
+            Error::InvalidInterrupt(interrupt_num) => write!(f, "Invalid interrupt: {interrupt_num}, only 0 to 7 exist"),
+            Error::InvalidInterruptSchedule(reason) => write!(f, "Invalid interrupt schedule: {reason}"),
+            Error::InvalidClockSpeed(hz) => write!(f, "Clock speed of {hz} Hz is too slow for the interrupts of a frame"),
+            Error::BufferSize { expected, actual } => {
+                write!(f, "Screen buffer has {actual} pixels instead of {expected}")
+            }
+            Error::Present(reason) => write!(f, "Failed to update window buffer: {reason}"),
+            #[cfg(feature = "audio")]
+            Error::Audio(reason) => write!(f, "Audio unavailable: {reason}"),
+            Error::Hex(e) => write!(f, "{e}"),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<HexError> for Error {
+    fn from(e: HexError) -> Self {
+        Error::Hex(e)
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Flags {
     pub sign: bool,
     pub zero: bool,
@@ -34,9 +34,32 @@ impl fmt::Display for Flags {
 }
 
 impl Flags {
-    /// Returns flags as a single byte
-    pub fn psw(&self) -> u8 {
-        let mut psw = 0;
+    /// Bits of the PSW holding a flag. Bits 1, 3 and 5 are not flags,
+    /// and may differ between implementations.
+    #[cfg(any(test, all(feature = "cpu_compare", debug_assertions)))]
+    pub const PSW_MASK: u8 = 0b1101_0101;
+    /// Bit 1 of the PSW, always set by the 8080
+    pub const PSW_ALWAYS_SET: u8 = 0b0000_0010;
+
+    /// Flags at power-on. They are indeterminate on the real chip, the emulator clears them
+    /// so runs are reproducible.
+    pub const POWER_ON: Self = Self {
+        sign: false,
+        zero: false,
+        aux_carry: false,
+        parity: false,
+        carry: false,
+    };
+
+    /// Whether two PSW bytes hold the same flags, whatever their other bits
+    #[cfg(any(test, all(feature = "cpu_compare", debug_assertions)))]
+    pub fn psw_eq(a: u8, b: u8) -> bool {
+        (a ^ b) & Self::PSW_MASK == 0
+    }
+
+    /// Returns flags as a single byte, with bit 1 set like the 8080 does
+    pub fn psw(self) -> u8 {
+        let mut psw = Self::PSW_ALWAYS_SET;
 
         if self.sign {
             psw |= 1 << 7
@@ -81,10 +104,10 @@ impl Flags {
         self.set_carry(value);
     }
 
-    pub fn set_all_but_carry(&mut self, value: u8) {
+    pub fn set_all_but_carry(&mut self, value: u8, aux_value: u8) {
         self.set_sign(value);
         self.set_zero(value);
-        self.set_aux_carry(value);
+        self.set_aux_carry(aux_value);
         self.set_parity(value);
     }
 
@@ -101,10 +124,69 @@ impl Flags {
     }
 
     fn set_parity(&mut self, value: u8) {
-        self.parity = value.count_ones() % 2 == 0;
+        self.parity = Self::parity(value);
+    }
+
+    /// Parity flag of `value`: set when it has an even number of bits set, including 0
+    pub fn parity(value: u8) -> bool {
+        value.count_ones() % 2 == 0
     }
 
     pub fn set_carry(&mut self, value: u16) {
         self.carry = value > 0xff;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether each byte has an even number of bits set, 16 bytes per row
+    const PARITY: [bool; 256] = [
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        false, true, true, false, true, false, false, true, true, false, false, true, false, true, true, false,
+        true, false, false, true, false, true, true, false, false, true, true, false, true, false, false, true,
+    ];
+
+    #[test]
+    fn sign_zero_and_parity_of_every_byte() {
+        for value in 0..=0xffu8 {
+            let mut flags = Flags::POWER_ON;
+            flags.set_all_but_aux_carry(u16::from(value));
+
+            assert_eq!(flags.sign, value >= 0x80, "sign of {value:02x}");
+            assert_eq!(flags.zero, value == 0, "zero of {value:02x}");
+            assert_eq!(flags.parity, PARITY[usize::from(value)], "parity of {value:02x}");
+            assert!(!flags.carry);
+        }
+    }
+
+    #[test]
+    fn psw_comparison_ignores_the_bits_which_arent_flags() {
+        // Every flag set, with and without bits 1, 3 and 5
+        let (flags, all_bits) = (0b1101_0101, 0b1111_1111);
+        assert_ne!(flags, all_bits);
+        assert!(Flags::psw_eq(flags, all_bits));
+        assert!(!Flags::psw_eq(flags, flags & !1));
+    }
+
+    #[test]
+    fn parity_matches_the_lookup_table() {
+        for value in 0..=0xffu8 {
+            assert_eq!(Flags::parity(value), PARITY[usize::from(value)], "parity of {value:02x}");
+        }
+    }
+}
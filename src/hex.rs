@@ -0,0 +1,168 @@
+use std::{error, fmt};
+
+/// Errors raised when parsing an Intel HEX file, with the 1-based line they occurred at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HexError {
+    /// Record not starting with ':'
+    MissingStartCode(usize),
+    /// Record with non-hexadecimal characters or an odd number of digits
+    InvalidDigits(usize),
+    /// Record shorter or longer than its byte count says
+    InvalidLength(usize),
+    /// Record whose checksum doesn't match its contents
+    InvalidChecksum(usize),
+    /// Record of an unknown type, or addressing past 64 KiB
+    UnsupportedRecord(usize),
+    /// No end of file record
+    MissingEnd,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            HexError::MissingStartCode(line) => write!(f, "Line {line}: record doesn't start with ':'"),
+            HexError::InvalidDigits(line) => write!(f, "Line {line}: invalid hexadecimal digits"),
+            HexError::InvalidLength(line) => write!(f, "Line {line}: record length doesn't match its byte count"),
+            HexError::InvalidChecksum(line) => write!(f, "Line {line}: invalid checksum"),
+            HexError::UnsupportedRecord(line) => write!(f, "Line {line}: unsupported record"),
+            HexError::MissingEnd => write!(f, "Missing end of file record"),
+        }
+    }
+}
+
+impl error::Error for HexError {}
+
+/// Contents of an Intel HEX file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HexImage {
+    /// Data records, with the address and line of each
+    pub data: Vec<(u16, usize, Vec<u8>)>,
+    /// Start address, if the file has one
+    pub start: Option<u16>,
+}
+
+impl HexImage {
+    /// Flattens the data records into a binary image starting at address 0,
+    /// with the gaps between records filled with zeros
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let size = self.data
+                       .iter()
+                       .map(|(address, _, data)| *address as usize + data.len())
+                       .max()
+                       .unwrap_or(0);
+
+        let mut bytes = vec![0; size];
+        for (address, _, data) in &self.data {
+            let start = *address as usize;
+            bytes[start..start + data.len()].copy_from_slice(data);
+        }
+        bytes
+    }
+}
+
+/// Whether `contents` looks like an Intel HEX file rather than a binary image
+pub fn is_intel_hex(contents: &[u8]) -> bool {
+    contents.iter()
+            .find(|byte| !byte.is_ascii_whitespace())
+            .map_or(false, |&byte| byte == b':')
+        && contents.iter().all(u8::is_ascii)
+}
+
+/// Parses an Intel HEX file, validating each record's checksum
+pub fn parse(text: &str) -> Result<HexImage, HexError> {
+    let mut image = HexImage::default();
+
+    for (index, record) in text.lines().enumerate() {
+        let line = index + 1;
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+
+        let bytes = record_bytes(record, line)?;
+        if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+            return Err(HexError::InvalidLength(line));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(HexError::InvalidChecksum(line));
+        }
+
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let data = &bytes[4..bytes.len() - 1];
+
+        match (bytes[3], data) {
+            // Data
+            (0x00, _) => image.data.push((address, line, data.to_vec())),
+            // End of file
+            (0x01, _) => return Ok(image),
+            // Extended segment and linear addresses, only supported within the first 64 KiB
+            (0x02 | 0x04, [0, 0]) => {}
+            // Start segment address (CS:IP)
+            (0x03, &[cs_high, cs_low, ip_high, ip_low]) => {
+                let segment = u16::from_be_bytes([cs_high, cs_low]);
+                let offset = u16::from_be_bytes([ip_high, ip_low]);
+                image.start = Some(segment.wrapping_shl(4).wrapping_add(offset));
+            }
+            // Start linear address
+            (0x05, &[0, 0, high, low]) => image.start = Some(u16::from_be_bytes([high, low])),
+            _ => return Err(HexError::UnsupportedRecord(line)),
+        }
+    }
+
+    Err(HexError::MissingEnd)
+}
+
+/// Decodes the hexadecimal digits of a record following the ':' start code
+fn record_bytes(record: &str, line: usize) -> Result<Vec<u8>, HexError> {
+    if !record.starts_with(':') {
+        return Err(HexError::MissingStartCode(line));
+    }
+
+    let digits = &record.as_bytes()[1..];
+    if digits.len() % 2 != 0 {
+        return Err(HexError::InvalidDigits(line));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(HexError::InvalidDigits(line))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MVI A, $42; HLT at $0100, two bytes at $2000, starting at $0100
+    const PROGRAM: &str = ":030100003E427606\n\
+                           :02200000AABB79\n\
+                           :0400000500000100F6\n\
+                           :00000001FF\n";
+
+    #[test]
+    fn records_land_at_their_address() {
+        let image = parse(PROGRAM).unwrap();
+        let bytes = image.to_bytes();
+
+        assert_eq!(bytes[0x0100..0x0103], [0x3e, 0x42, 0x76]);
+        assert_eq!(bytes[0x2000..], [0xaa, 0xbb]);
+        assert_eq!(bytes[0x00ff], 0);
+        assert_eq!(image.start, Some(0x0100));
+        assert!(is_intel_hex(PROGRAM.as_bytes()));
+    }
+
+    #[test]
+    fn malformed_records_are_errors() {
+        assert_eq!(parse(":030100003E427607\n:00000001FF"), Err(HexError::InvalidChecksum(1)));
+        assert_eq!(parse(":00000001FF\n").map(|image| image.data.len()), Ok(0));
+        assert_eq!(parse("030100003E427606"), Err(HexError::MissingStartCode(1)));
+        assert_eq!(parse(":02200000AABB79\n:0300"), Err(HexError::InvalidLength(2)));
+        assert_eq!(parse(":0G"), Err(HexError::InvalidDigits(1)));
+        assert_eq!(parse(":02200000AABB79"), Err(HexError::MissingEnd));
+    }
+}
@@ -0,0 +1,158 @@
+/// Logical controls of a machine, independent of the window library
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameKey {
+    Credit,
+    P1Start,
+    P2Start,
+    P1Fire,
+    P1Left,
+    P1Right,
+    P2Fire,
+    P2Left,
+    P2Right,
+    Tilt,
+}
+
+impl GameKey {
+    fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// Set of currently pressed controls
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JoypadState(u16);
+
+impl JoypadState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_pressed(self, key: GameKey) -> bool {
+        self.0 & key.mask() != 0
+    }
+
+    pub fn set(&mut self, key: GameKey, pressed: bool) {
+        if pressed {
+            self.0 |= key.mask();
+        } else {
+            self.0 &= !key.mask();
+        }
+    }
+}
+
+/// How to handle both directions of a player being pressed at once, which keyboard rollover allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpposingInputs {
+    /// Pass both directions to the game
+    Allow,
+    /// Press neither direction
+    Cancel,
+    /// Press the direction pressed last
+    Latest,
+}
+
+/// Directions which can't be pressed together on the original controls
+const OPPOSING_KEYS: [(GameKey, GameKey); 2] = [
+    (GameKey::P1Left, GameKey::P1Right),
+    (GameKey::P2Left, GameKey::P2Right),
+];
+
+/// Applies an `OpposingInputs` policy to the controls read each frame
+pub struct InputSanitizer {
+    policy: OpposingInputs,
+    /// Controls last passed to `sanitize`
+    previous: JoypadState,
+    /// Controls last returned by `sanitize`
+    resolved: JoypadState,
+}
+
+impl InputSanitizer {
+    pub fn new(policy: OpposingInputs) -> Self {
+        Self {
+            policy,
+            previous: JoypadState::new(),
+            resolved: JoypadState::new(),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: OpposingInputs) {
+        self.policy = policy;
+    }
+
+    /// Resolves opposing directions pressed together in `joypad` according to the policy
+    pub fn sanitize(&mut self, joypad: JoypadState) -> JoypadState {
+        let mut sanitized = joypad;
+
+        for &(left, right) in &OPPOSING_KEYS {
+            if !joypad.is_pressed(left) || !joypad.is_pressed(right) {
+                continue;
+            }
+
+            let (keep_left, keep_right) = match self.policy {
+                OpposingInputs::Allow => (true, true),
+                OpposingInputs::Cancel => (false, false),
+                OpposingInputs::Latest => match (self.previous.is_pressed(left), self.previous.is_pressed(right)) {
+                    (true, false) => (false, true),
+                    (false, true) => (true, false),
+                    // Both still held, keep the direction chosen when the second one was pressed
+                    (true, true) => (self.resolved.is_pressed(left), self.resolved.is_pressed(right)),
+                    // Both pressed on the same frame
+                    (false, false) => (false, false),
+                },
+            };
+            sanitized.set(left, keep_left);
+            sanitized.set(right, keep_right);
+        }
+
+        self.previous = joypad;
+        self.resolved = sanitized;
+        sanitized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_pressed_and_released_independently() {
+        let mut joypad = JoypadState::new();
+        joypad.set(GameKey::P1Fire, true);
+        joypad.set(GameKey::P2Fire, true);
+        joypad.set(GameKey::P2Fire, false);
+
+        assert!(joypad.is_pressed(GameKey::P1Fire));
+        assert!(!joypad.is_pressed(GameKey::P2Fire));
+        assert!(!joypad.is_pressed(GameKey::Credit));
+    }
+
+    /// Controls with only `keys` pressed
+    fn pressed(keys: &[GameKey]) -> JoypadState {
+        let mut joypad = JoypadState::new();
+        for &key in keys {
+            joypad.set(key, true);
+        }
+        joypad
+    }
+
+    #[test]
+    fn opposing_directions_follow_the_policy() {
+        let both = pressed(&[GameKey::P1Left, GameKey::P1Right, GameKey::P2Fire]);
+
+        let allowed = InputSanitizer::new(OpposingInputs::Allow).sanitize(both);
+        assert_eq!(allowed, both);
+
+        // Other controls are left alone
+        let cancelled = InputSanitizer::new(OpposingInputs::Cancel).sanitize(both);
+        assert_eq!(cancelled, pressed(&[GameKey::P2Fire]));
+
+        let mut latest = InputSanitizer::new(OpposingInputs::Latest);
+        assert_eq!(latest.sanitize(pressed(&[GameKey::P1Left])), pressed(&[GameKey::P1Left]));
+        let right_pressed_last = pressed(&[GameKey::P1Right, GameKey::P2Fire]);
+        assert_eq!(latest.sanitize(both), right_pressed_last);
+        // Held together, the choice sticks
+        assert_eq!(latest.sanitize(both), right_pressed_last);
+        assert_eq!(latest.sanitize(pressed(&[GameKey::P1Left])), pressed(&[GameKey::P1Left]));
+    }
+}
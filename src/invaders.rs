@@ -1,241 +1,2528 @@
-#[cfg(feature = "cpu_compare")]
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
 use i8080;
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
+use crate::{cpu::Registers, flags::Flags};
 
-use std;
+use std::{self, cmp::Reverse, collections::VecDeque, fs, ops::Range, path::Path, time::Duration};
 
-use crate::cpu::{CpuState, RegisterPair};
+use crate::{
+    cpu::{CpuState, LogPolicy, MAX_INTERRUPT, PcWrapPolicy, ResetKind, StopReason},
+    error::Error,
+    hex,
+    input::{GameKey, InputSanitizer, JoypadState, OpposingInputs},
+    machine::Machine,
+    render::{PixelEffect, Present, RenderOptions, Renderer, RotatedRenderer},
+    shift_hardware::ShiftRegister,
+    sound::{SampleSet, SoundState},
+};
 
 /// Interface between the emulator's IO functions and the machine state
 pub trait IOState {
-    fn input(&self, port: u8) -> u8;
-    fn output(&mut self, port: u8, value: u8);
+    fn input(&self, port: u8) -> Result<u8, Error>;
+    fn output(&mut self, port: u8, value: u8) -> Result<(), Error>;
+}
+
+/// Counters accumulated over a run of the emulator, returned by all the `run_*` methods.
+/// Unlike the CPU's step methods, which return cycles, they count instructions and interrupts too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    /// Interrupts actually serviced by the CPU
+    pub interrupts: u64,
+}
+
+impl std::ops::AddAssign for RunStats {
+    fn add_assign(&mut self, other: Self) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+        self.interrupts += other.interrupts;
+    }
+}
+
+/// What happened to an interrupt request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptOutcome {
+    Serviced,
+    /// Requested during the EI delay, to be serviced after the next instruction
+    Latched,
+    /// Requested with interrupts disabled
+    Dropped,
+}
+
+/// Interrupt request, with the time it happened at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptEvent {
+    pub frame: u64,
+    /// Cycles since the start of the frame
+    pub cycle: u64,
+    /// PC when the interrupt was requested
+    pub pc: u16,
+    /// RST number
+    pub interrupt_num: u16,
+    pub outcome: InterruptOutcome,
+}
+
+/// Executions and cycles of each opcode, and accesses to each 256-byte page of memory
+pub struct OpcodeProfile {
+    counts: [u64; 256],
+    cycles: [u64; 256],
+    /// Accesses by the instructions, as found by `CpuState::effective_address`
+    page_accesses: [u64; 256],
+}
+
+impl OpcodeProfile {
+    fn new() -> Self {
+        Self {
+            counts: [0; 256],
+            cycles: [0; 256],
+            page_accesses: [0; 256],
+        }
+    }
+
+    fn record(&mut self, op_code: u8, cycles: u64, accessed: Option<u16>) {
+        self.counts[op_code as usize] += 1;
+        self.cycles[op_code as usize] += cycles;
+        if let Some(address) = accessed {
+            self.page_accesses[usize::from(address >> 8)] += 1;
+        }
+    }
+
+    /// The whole profile as CSV, with a `section,key,count,cycles` header. Lines of the `opcode` section
+    /// have the executions and cycles of each opcode executed, in hexadecimal. The `category` section sums
+    /// them for each category of `CpuState::category`. The `memory` section has the accesses to each page
+    /// of memory accessed, keyed by its start address, stack pushes and pops aside.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("section,key,count,cycles\n");
+
+        csv.extend(self.by_cycles().into_iter().map(|(op_code, count, cycles)| format!("opcode,{op_code:02x},{count},{cycles}\n")));
+
+        let mut categories: Vec<(&str, u64, u64)> = Vec::new();
+        for (op_code, count, cycles) in self.by_cycles() {
+            let category = CpuState::category(op_code);
+            match categories.iter_mut().find(|(name, _, _)| *name == category) {
+                Some((_, total_count, total_cycles)) => {
+                    *total_count += count;
+                    *total_cycles += cycles;
+                }
+                None => categories.push((category, count, cycles)),
+            }
+        }
+        csv.extend(categories.into_iter().map(|(category, count, cycles)| format!("category,{category},{count},{cycles}\n")));
+
+        csv.extend(self.page_accesses.iter()
+                                     .enumerate()
+                                     .filter(|&(_, &count)| count > 0)
+                                     .map(|(page, count)| format!("memory,{:04x},{},\n", page << 8, count)));
+
+        csv
+    }
+
+    /// Opcodes executed at least once with their execution count and total cycles,
+    /// most expensive first
+    pub fn by_cycles(&self) -> Vec<(u8, u64, u64)> {
+        let mut opcodes = (0..=255u8)
+            .filter(|&op_code| self.counts[op_code as usize] > 0)
+            .map(|op_code| (op_code, self.counts[op_code as usize], self.cycles[op_code as usize]))
+            .collect::<Vec<_>>();
+        opcodes.sort_by_key(|&(_, _, cycles)| Reverse(cycles));
+        opcodes
+    }
 }
 
 pub struct SpaceInvaders {
-    #[cfg(feature = "cpu_compare")]
-    ref_cpu: i8080::Cpu,
-    #[cfg(feature = "cpu_compare")]
-    ref_io_state: SpaceInvadersIO,
+    /// None once the reference CPU can no longer be kept in sync, until the next cold reset
+    #[cfg(all(feature = "cpu_compare", debug_assertions))]
+    reference: Option<Reference>,
     cpu: CpuState,
     io_state: SpaceInvadersIO,
-    window_buffer: [u32; 224 * 256],
+    /// Screen presented to the window, sized from the render options
+    window_buffer: Vec<u32>,
+    /// Whether to present the window once per frame instead of at each interrupt
+    interlaced: bool,
+    renderer: Box<dyn Renderer>,
+    render_options: RenderOptions,
+    /// Applied to each pixel of the presented screen, after rendering
+    effect: Option<PixelEffect>,
+    /// Screen with the effect applied, kept so the window buffer stays unaffected
+    effect_buffer: Vec<u32>,
+    /// Whether `audio_samples` outputs the sounds, or only silence
+    audio_enabled: bool,
+    rom_hash: u64,
+    /// Per-opcode counters, when profiling
+    profile: Option<Box<OpcodeProfile>>,
+    /// Controls last set by the caller, once sanitized
+    joypad: JoypadState,
+    input_sanitizer: InputSanitizer,
+    /// Keys queued by `insert_coin`/`press_start`, pulsed one after the other
+    pulses: VecDeque<GameKey>,
+    /// Frames into the pulse of the first queued key
+    pulse_frame: u32,
+    /// Frames without a watchdog kick after which the machine is reset, if enabled
+    watchdog_timeout: Option<u32>,
+    /// Frames since the watchdog was last kicked
+    watchdog_frames: u32,
+    /// Memory kept as is by resets, like battery-backed RAM
+    preserved_regions: Vec<Range<u16>>,
+    /// Interrupts fired during each frame, as `(cycle_offset, interrupt_num)` sorted by offset
+    interrupt_schedule: Vec<(u64, u16)>,
+    /// Length of a frame, from the clock speed
+    cycles_per_frame: u64,
+    /// Cycles the last part of the frame ran past its interrupt, as instructions can't be split
+    cycle_debt: u64,
+    /// Frames in a row the window failed to present
+    failed_presents: u32,
+    /// Value of `cycles` when the current frame started
+    frame_start_cycles: u64,
+    /// Interrupt requests, when logging them
+    interrupt_log: Option<Vec<InterruptEvent>>,
     instructions: u64,
     cycles: u64,
     frames: u64,
 }
 
 impl SpaceInvaders {
-    const CYCLES_PER_FRAME: u64 = 4_000_000 / 60;
+    const FRAMES_PER_SECOND: u64 = 60;
+    /// Cycles run per second, unless changed by `set_clock_speed`
+    pub const CLOCK_SPEED: u64 = 4_000_000;
+    const CYCLES_PER_FRAME: u64 = Self::CLOCK_SPEED / Self::FRAMES_PER_SECOND;
+    const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / Self::FRAMES_PER_SECOND);
+    /// RST 1 when the beam reaches the middle of the screen, RST 2 at the end of the frame
+    const INTERRUPT_SCHEDULE: [(u64, u16); 2] = [(Self::CYCLES_PER_FRAME / 2, 1), (Self::CYCLES_PER_FRAME, 2)];
     pub const SCREEN_WIDTH: usize = 224;
     pub const SCREEN_HEIGHT: usize = 256;
+    /// Size of a screen buffer, such as the one presented to the window
+    pub const SCREEN_PIXELS: usize = Self::SCREEN_WIDTH * Self::SCREEN_HEIGHT;
+    /// White on black, at the screen size
+    const RENDER_OPTIONS: RenderOptions = RenderOptions {
+        width: Self::SCREEN_WIDTH,
+        height: Self::SCREEN_HEIGHT,
+        foreground: 0xff_ff_ff_ff,
+        background: 0x00_00_00_00,
+    };
+    /// RAM flag set by the game while a game is being played
+    const GAME_MODE_ADDRESS: usize = 0x20ef;
+    /// RAM holding the number of credits, in BCD
+    pub const CREDITS_REGION: Range<u16> = 0x20eb..0x20ec;
+
+    /// Controls read by the game
+    pub const SUPPORTED_KEYS: &[GameKey] = &[
+        GameKey::Credit,
+        GameKey::P1Start,
+        GameKey::P2Start,
+        GameKey::P1Fire,
+        GameKey::P1Left,
+        GameKey::P1Right,
+        GameKey::P2Fire,
+        GameKey::P2Left,
+        GameKey::P2Right,
+        GameKey::Tilt,
+    ];
+    /// The game's character set in ROM: A to Z, 0 to 9, then symbols, 8 bytes each
+    const FONT_ADDRESS: usize = 0x1e00;
+    /// One column of characters is 8 bytes apart in video memory, one per screen column
+    const TEXT_COLUMNS: usize = Self::SCREEN_WIDTH / 8;
+    const TEXT_LINES: usize = Self::SCREEN_HEIGHT / 8;
+    /// Whether each instruction is checked against the reference CPU, which only happens
+    /// in debug builds with `cpu_compare`
+    pub const COMPARES_TO_REFERENCE: bool = cfg!(all(feature = "cpu_compare", debug_assertions));
+    /// Frames a pulsed key is held, then released, so the game's debounce registers it
+    const PULSE_FRAMES: u32 = 5;
+    /// Times presenting a frame is retried before the frame is given up on
+    const PRESENT_RETRIES: u32 = 2;
+    /// Frames in a row which failed to present before giving up on the window, a second's worth
+    const MAX_FAILED_PRESENTS: u32 = 60;
+    /// Start address of video memory
+    const VRAM_START: usize = 0x2400;
+    /// Size of video memory, one bit per pixel
+    pub const VRAM_SIZE: usize = Self::SCREEN_PIXELS / 8;
+    /// Pixels per line of video memory, which are columns of the rotated screen
+    pub const VRAM_LINE_PIXELS: usize = Self::SCREEN_HEIGHT;
 
+    /// Loads the ROM embedded in the binary
+    #[cfg(feature = "default_rom")]
     pub fn new() -> Self {
-        Self::from_rom(include_bytes!("invaders.rom"))
+        Self::from_rom(include_bytes!("invaders.rom")).expect("Embedded ROM does not fit in memory")
+    }
+
+    /// Loads a ROM file, either a binary image or an Intel HEX file, detected from its contents.
+    /// HEX files start at their start address, if they have one.
+    pub fn from_rom_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let contents = fs::read(path)?;
+
+        if hex::is_intel_hex(&contents) {
+            let image = hex::parse(&String::from_utf8_lossy(&contents))?;
+            Self::from_image(&image.to_bytes(), image.start.unwrap_or(0))
+        } else {
+            Self::from_rom(contents)
+        }
     }
 
-    pub fn from_rom(rom: &[u8]) -> Self {
-        #[cfg(feature = "cpu_compare")] {
-            let mut ref_cpu = i8080::Cpu::new();
-            ref_cpu.load_into_rom(rom, 0);
-            *ref_cpu.pc = 0;
+    /// Overwrites bytes of the loaded program with `(address, byte)` patches, ROM included,
+    /// to try out fixes without editing the ROM file. Fails without applying any patch
+    /// if one of them is outside of memory.
+    pub fn apply_patches(&mut self, patches: &[(u16, u8)]) -> Result<(), Error> {
+        let memory_size = self.cpu.memory().len();
+        if let Some(&(address, _)) = patches.iter().find(|&&(address, _)| usize::from(address) >= memory_size) {
+            return Err(Error::OutOfMemory { address, size: 1 });
+        }
+
+        for &(address, byte) in patches {
+            self.cpu.set_memory_slice(address, &[byte])?;
 
-            Self {
-                ref_cpu,
-                ref_io_state: SpaceInvadersIO::new(),
-                cpu: CpuState::from_rom(rom, 0, 0),
-                io_state: SpaceInvadersIO::new(),
-                window_buffer: [0; 224 * 256],
-                instructions: 0,
-                cycles: 0,
-                frames: 0,
+            #[cfg(all(feature = "cpu_compare", debug_assertions))]
+            if let Some(reference) = &mut self.reference {
+                reference.cpu.load_into_rom(&[byte], address);
             }
         }
-        #[cfg(not(feature = "cpu_compare"))]
-            Self {
-            cpu: CpuState::from_rom(rom, 0, 0),
+
+        Ok(())
+    }
+
+    pub fn from_rom(rom: impl AsRef<[u8]>) -> Result<Self, Error> {
+        Self::from_image(rom.as_ref(), 0)
+    }
+
+    /// Machine running `rom`, loaded at address 0, from `start`
+    fn from_image(rom: &[u8], start: u16) -> Result<Self, Error> {
+        Ok(Self {
+            #[cfg(all(feature = "cpu_compare", debug_assertions))]
+            reference: Some(Reference::new(rom, start, DipSwitches::default())),
+            cpu: CpuState::from_rom(rom, 0, start)?,
             io_state: SpaceInvadersIO::new(),
-            window_buffer: [0; 224 * 256],
+            window_buffer: vec![0; Self::SCREEN_PIXELS],
+            interlaced: false,
+            renderer: Box::new(RotatedRenderer),
+            render_options: Self::RENDER_OPTIONS,
+            effect: None,
+            effect_buffer: Vec::new(),
+            audio_enabled: true,
+            rom_hash: fnv1a_hash(rom),
+            profile: None,
+            joypad: JoypadState::new(),
+            input_sanitizer: InputSanitizer::new(OpposingInputs::Allow),
+            pulses: VecDeque::new(),
+            pulse_frame: 0,
+            watchdog_timeout: None,
+            watchdog_frames: 0,
+            preserved_regions: Vec::new(),
+            interrupt_schedule: Self::INTERRUPT_SCHEDULE.to_vec(),
+            cycles_per_frame: Self::CYCLES_PER_FRAME,
+            cycle_debt: 0,
+            failed_presents: 0,
+            frame_start_cycles: 0,
+            interrupt_log: None,
             instructions: 0,
             cycles: 0,
             frames: 0,
-        }
+        })
+    }
+
+    /// Applies all the settings of `config`, in place of the setters, e.g.
+    /// `SpaceInvaders::from_rom_file(path)?.with_config(config)?`.
+    /// Fails on a clock speed `set_clock_speed` refuses.
+    pub fn with_config(mut self, config: Config) -> Result<Self, Error> {
+        self.set_clock_speed(config.clock_speed)?;
+        self.set_dip_switches(config.dip_switches);
+        self.set_strict(config.strict);
+        self.set_pc_wrap_policy(config.pc_wrap_policy);
+        self.set_interrupt_latency(config.interrupt_latency);
+        self.set_interlaced(config.interlaced);
+        self.set_audio_enabled(config.audio_enabled);
+        self.set_opposing_inputs(config.opposing_inputs);
+        self.set_watchdog(config.watchdog);
+        self.set_preserved_regions(config.preserved_regions);
+        Ok(self)
     }
 
-    // Proceeds one frame of the emulator
-    pub fn step(&mut self, window: &mut minifb::Window) {
-        self.half_step(window, true);
-        self.half_step(window, false);
+    /// Plays recorded sounds instead of the built-in ones, for the sounds `sample_set` has
+    pub fn set_sample_set(&mut self, sample_set: SampleSet) {
+        self.io_state.sound.set_sample_set(sample_set);
+    }
 
-        self.frames += 1;
+    /// In strict mode, writing to ROM and accessing ports the hardware doesn't have are errors,
+    /// instead of being ignored
+    pub fn set_strict(&mut self, strict: bool) {
+        self.cpu.set_strict(strict);
+        self.io_state.strict = strict;
+    }
 
-        // Lastly, update input
-        #[cfg(feature = "cpu_compare")]
-            self.ref_io_state.update_input(&window);
+    /// Extra cycles taken to acknowledge an interrupt, see `CpuState::set_interrupt_latency`
+    pub fn set_interrupt_latency(&mut self, cycles: u64) {
+        self.cpu.set_interrupt_latency(cycles);
+    }
+
+    /// What to do when execution runs past $ffff, see `CpuState::set_pc_wrap_policy`
+    pub fn set_pc_wrap_policy(&mut self, policy: PcWrapPolicy) {
+        self.cpu.set_pc_wrap_policy(policy);
+    }
+
+    /// Updates the state of the controls, read by the CPU from the input ports
+    pub fn set_input(&mut self, joypad: JoypadState) {
+        self.joypad = self.input_sanitizer.sanitize(joypad);
+        self.apply_input();
+    }
+
+    /// Controls the game currently sees as pressed, including pulsed keys, for input overlays
+    pub fn input_state(&self) -> JoypadState {
+        self.io_state.input_state()
+    }
+
+    /// Changes how both directions of a player pressed at once are passed to the game
+    pub fn set_opposing_inputs(&mut self, policy: OpposingInputs) {
+        self.input_sanitizer.set_policy(policy);
+    }
 
-        self.io_state.update_input(window);
+    /// Inserts a coin, holding the coin switch for a few frames then releasing it
+    pub fn insert_coin(&mut self) {
+        self.pulse(GameKey::Credit);
+    }
 
-        std::thread::sleep(std::time::Duration::from_millis(16));
+    /// Starts a 1 player game, holding the start button for a few frames then releasing it
+    pub fn press_start(&mut self) {
+        self.pulse(GameKey::P1Start);
     }
 
-    fn half_step(&mut self, window: &mut minifb::Window, top_half: bool) {
-        let mut cycles_spent = 0;
-        while cycles_spent < Self::CYCLES_PER_FRAME / 2 {
-            let cycles = self.cpu.emulate(&mut self.io_state);
+    /// Queues a press of `key`, after the pulses already queued
+    fn pulse(&mut self, key: GameKey) {
+        self.pulses.push_back(key);
+        self.apply_input();
+    }
 
-            #[cfg(feature = "cpu_compare")] {
-                self.ref_cpu.emulate(&mut self.ref_io_state);
-                self.cpu_compare();
+    /// Sends the controls set by the caller, along with the key being pulsed, to the input ports
+    fn apply_input(&mut self) {
+        let mut joypad = self.joypad;
+        if let Some(&key) = self.pulses.front() {
+            if self.pulse_frame < Self::PULSE_FRAMES {
+                joypad.set(key, true);
             }
+        }
+
+        #[cfg(all(feature = "cpu_compare", debug_assertions))]
+        if let Some(reference) = &mut self.reference {
+            reference.io_state.update_input(joypad);
+        }
+
+        self.io_state.update_input(joypad);
+    }
 
-            cycles_spent += cycles;
+    /// Counts a finished frame, advancing the pulsed keys
+    fn end_frame(&mut self) {
+        self.frames += 1;
+        self.frame_start_cycles = self.cycles;
+
+        if !self.pulses.is_empty() {
+            self.pulse_frame += 1;
+            // Held for PULSE_FRAMES, then released for as long
+            if self.pulse_frame == 2 * Self::PULSE_FRAMES {
+                self.pulses.pop_front();
+                self.pulse_frame = 0;
+            }
+            self.apply_input();
+        }
 
-            // For monitoring/debug purposes
-            self.instructions += 1;
-            self.cycles += cycles;
+        if std::mem::take(&mut self.io_state.watchdog_kicked) {
+            self.kick_watchdog();
         }
+        if let Some(timeout) = self.watchdog_timeout {
+            self.watchdog_frames += 1;
+            if self.watchdog_frames >= timeout {
+                self.reset(ResetKind::Warm);
+            }
+        }
+    }
+
+    /// Forgets the cycle debt, so the next part of the frame runs for its full duration.
+    /// Done on reset and when loading a state.
+    pub fn reset_timing(&mut self) {
+        self.cycle_debt = 0;
+    }
+
+    /// Enables the watchdog, resetting the machine like the reset key when it isn't kicked
+    /// for `timeout` frames, or disables it with `None`
+    pub fn set_watchdog(&mut self, timeout: Option<u32>) {
+        self.watchdog_timeout = timeout;
+        self.watchdog_frames = 0;
+    }
+
+    /// Keeps the memory of `regions` through resets, whether by the reset key or the watchdog,
+    /// like RAM backed by a battery, e.g. `CREDITS_REGION` to keep the credits.
+    /// Everything else is reset as usual.
+    pub fn set_preserved_regions(&mut self, regions: Vec<Range<u16>>) {
+        self.preserved_regions = regions;
+    }
+
+    /// Restarts the watchdog countdown, as the game does by writing to port 6
+    pub fn kick_watchdog(&mut self) {
+        self.watchdog_frames = 0;
+    }
+
+    /// In interlaced mode, the top half of the screen rendered at the middle of frame interrupt
+    /// and the bottom half rendered at the end of frame interrupt are presented together,
+    /// once per frame, like the original display.
+    pub fn set_interlaced(&mut self, interlaced: bool) {
+        self.interlaced = interlaced;
+    }
 
-        // Render half of the screen
-        window.update_with_buffer(&self.screen(top_half))
-              .unwrap_or_else(|e| println!("Failed to update window buffer: {}", e));
+    /// Whether the game is in attract mode (demo or game over), rather than being played
+    pub fn in_attract_mode(&self) -> bool {
+        self.cpu.memory()[Self::GAME_MODE_ADDRESS] == 0
+    }
 
-        // Middle/end of frame interrupt
+    pub fn dip_switches(&self) -> DipSwitches {
+        self.io_state.dip_switches
+    }
 
-        #[cfg(feature = "cpu_compare")]
-            self.ref_cpu.interrupt(if top_half { 8 } else { 16 });
+    /// Changes the DIP switch settings, read by the game from port 2
+    pub fn set_dip_switches(&mut self, dip_switches: DipSwitches) {
+        #[cfg(all(feature = "cpu_compare", debug_assertions))]
+        if let Some(reference) = &mut self.reference {
+            reference.io_state.dip_switches = dip_switches;
+        }
 
-        self.cpu.interrupt(if top_half { 1 } else { 2 });
+        self.io_state.dip_switches = dip_switches;
     }
 
-    fn screen(&mut self, top_half: bool) -> &[u32] {
-        let (start_memory, start_pixel) = if top_half {
-            (0x2400, 0)
+    /// Restarts the game from address 0, for both the reset key and the watchdog.
+    /// A warm reset leaves memory as is, the game initializes it itself, while a cold reset
+    /// also clears RAM and the IO hardware, like powering on. Either way, sounds stop,
+    /// the screen is cleared and the counters start over.
+    pub fn reset(&mut self, kind: ResetKind) {
+        // Regions outside of memory have nothing to preserve
+        let memory = self.cpu.memory();
+        let preserved = self.preserved_regions
+                            .iter()
+                            .filter_map(|region| {
+                                let bytes = memory.get(usize::from(region.start)..usize::from(region.end))?;
+                                Some((region.start, bytes.to_vec()))
+                            })
+                            .collect::<Vec<_>>();
+        self.cpu.reset(kind);
+        for (address, bytes) in preserved {
+            self.cpu.set_memory_slice(address, &bytes)
+                    .expect("Preserved region was read from memory");
+        }
+
+        if kind == ResetKind::Cold {
+            self.io_state.power_on();
         } else {
-            (0x3200, 0x7000)
+            self.io_state.sound.stop();
+        }
+
+        // A new reference CPU is as cold as the emulator's, but a running one can't be made to
+        // disable its interrupts like a warm reset does
+        #[cfg(all(feature = "cpu_compare", debug_assertions))]
+        match kind {
+            ResetKind::Cold => self.reference = Some(Reference::new(self.cpu.memory(), 0, self.io_state.dip_switches)),
+            ResetKind::Warm => self.stop_comparing("a warm reset"),
+        }
+
+        self.window_buffer.iter_mut().for_each(|pixel| *pixel = 0);
+        self.failed_presents = 0;
+        self.pulses.clear();
+        self.pulse_frame = 0;
+        self.watchdog_frames = 0;
+        self.reset_timing();
+        self.instructions = 0;
+        self.cycles = 0;
+        self.frames = 0;
+        self.frame_start_cycles = 0;
+        self.apply_input();
+    }
+
+    /// Stops comparing to the reference CPU after `event`, which it can't follow, until the next cold reset
+    #[cfg(all(feature = "cpu_compare", debug_assertions))]
+    fn stop_comparing(&mut self, event: &str) {
+        if self.reference.take().is_some() {
+            eprintln!("Not comparing to the reference CPU after {event} until the next cold reset");
+        }
+    }
+
+    /// Fills `out` with mono audio samples of the current sounds, at the specified sample rate.
+    /// Meant to be called from the host's audio callback.
+    #[cfg(any(test, feature = "audio"))]
+    pub fn audio_samples(&mut self, out: &mut [f32], sample_rate: u32) {
+        // Sounds still play through while muted, so unmuting doesn't restart them
+        self.io_state.sound.samples(out, sample_rate);
+
+        if !self.audio_enabled {
+            out.fill(0.);
+        }
+    }
+
+    /// Enables or mutes `audio_samples`, for hosts without an audio device.
+    /// Emulation runs the same either way.
+    pub fn set_audio_enabled(&mut self, enabled: bool) {
+        self.audio_enabled = enabled;
+    }
+
+    /// Saves the IO hardware and CPU state to a file, tagged with the hash of the loaded ROM
+    pub fn save_state(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut state = self.rom_hash.to_le_bytes().to_vec();
+        state.extend_from_slice(&self.io_state.save_state());
+        state.extend(self.cpu.save_state());
+        fs::write(path, state)?;
+        Ok(())
+    }
+
+    /// Loads a state saved by `save_state`, refusing states saved with a different ROM
+    pub fn load_state(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let state = fs::read(path)?;
+
+        if state.len() < 8 || state[..8] != self.rom_hash.to_le_bytes() {
+            return Err(Error::InvalidState("saved with a different ROM".into()));
+        }
+
+        let io_end = 8 + SpaceInvadersIO::STATE_SIZE;
+        if state.len() < io_end {
+            return Err(Error::InvalidState("missing IO state".into()));
+        }
+
+        // Load the CPU first, so a failure leaves the IO state untouched
+        self.cpu.load_state(&state[io_end..])?;
+
+        let mut io_state = [0; SpaceInvadersIO::STATE_SIZE];
+        io_state.copy_from_slice(&state[8..io_end]);
+        self.io_state.load_state(io_state);
+        self.reset_timing();
+
+        // The reference CPU's interrupts and memory can't be set to the loaded ones
+        #[cfg(all(feature = "cpu_compare", debug_assertions))]
+        self.stop_comparing("loading a state");
+
+        Ok(())
+    }
+
+    /// Proceeds one frame of the emulator, presenting it to `window`. Pacing is left to the caller.
+    /// The frame's counters are only available through `run_frames`.
+    #[must_use = "errors stop the emulation"]
+    pub fn step(&mut self, window: &mut dyn Present) -> Result<(), Error> {
+        for index in 0..self.interrupt_schedule.len() {
+            self.partial_step(window, index)?;
+        }
+
+        self.run_to_end_of_frame()?;
+        self.end_frame();
+        Ok(())
+    }
+
+    /// Runs `frames` frames without a window, for headless testing and benchmarking.
+    #[must_use = "errors stop the emulation"]
+    pub fn run_frames(&mut self, frames: u64) -> Result<RunStats, Error> {
+        let mut stats = RunStats::default();
+
+        for _ in 0..frames {
+            for index in 0..self.interrupt_schedule.len() {
+                stats += self.run_until_interrupt(index)?;
+            }
+
+            stats += self.run_to_end_of_frame()?;
+            self.end_frame();
+        }
+
+        Ok(stats)
+    }
+
+    /// Runs `frames` frames without a window, returning the hash of the screen at the end of each.
+    /// Comparing them to a previous run catches divergences as soon as they appear.
+    pub fn frame_hashes(&mut self, frames: u64) -> Result<Vec<u64>, Error> {
+        let mut hashes = Vec::with_capacity(frames as usize);
+
+        for _ in 0..frames {
+            self.run_frames(1)?;
+            hashes.push(fnv1a_hash(self.vram()));
+        }
+
+        Ok(hashes)
+    }
+
+    /// Sets the interrupts fired during each frame, as `(cycle_offset, interrupt_num)`, for machines
+    /// other than Space Invaders, e.g. a single vertical blank interrupt. Offsets must be increasing,
+    /// after the start of the frame and not past its end, which the frame runs on to after the last one.
+    /// Each interrupt renders the part of the screen drawn by the beam since the previous one.
+    pub fn set_interrupt_schedule(&mut self, schedule: Vec<(u64, u16)>) -> Result<(), Error> {
+        if schedule.is_empty() {
+            return Err(Error::InvalidInterruptSchedule("no interrupts".into()));
+        }
+        if !schedule.windows(2).all(|pair| pair[0].0 < pair[1].0)
+            || !schedule.iter().all(|&(offset, _)| offset > 0 && offset <= self.cycles_per_frame)
+        {
+            return Err(Error::InvalidInterruptSchedule(format!(
+                "offsets must be increasing, between 1 and {} cycles",
+                self.cycles_per_frame
+            )));
+        }
+        if let Some(&(_, interrupt_num)) = schedule.iter().find(|&&(_, interrupt_num)| interrupt_num > MAX_INTERRUPT) {
+            return Err(Error::InvalidInterruptSchedule(format!("no interrupt {interrupt_num}, only 0 to 7 exist")));
+        }
+
+        self.interrupt_schedule = schedule;
+        self.reset_timing();
+        Ok(())
+    }
+
+    /// Runs `hz` cycles per second instead of `CLOCK_SPEED`, e.g. to overclock the game.
+    /// Frames still last 1/60th of a second, so the interrupt schedule is stretched with them.
+    /// Fails, leaving the speed as is, if the interrupts no longer get a cycle each.
+    pub fn set_clock_speed(&mut self, hz: u64) -> Result<(), Error> {
+        let cycles_per_frame = hz / Self::FRAMES_PER_SECOND;
+        // Offsets are at most a frame, so only the product needs more than 64 bits
+        let stretch = |&(offset, interrupt_num): &(u64, u16)| {
+            let offset = u128::from(offset) * u128::from(cycles_per_frame) / u128::from(self.cycles_per_frame);
+            (offset as u64, interrupt_num)
         };
+        let schedule = self.interrupt_schedule.iter().map(stretch).collect::<Vec<_>>();
+
+        if schedule.first().map_or(true, |&(offset, _)| offset == 0) || !schedule.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            return Err(Error::InvalidClockSpeed(hz));
+        }
+
+        self.cycles_per_frame = cycles_per_frame;
+        self.set_interrupt_schedule(schedule)
+    }
 
-        // Iterate half the screen
-        for offset in 0..0xE00 {
-            let byte = self.cpu.memory()[start_memory + offset];
+    /// Runs the part of the frame ending with the interrupt at `index` in the schedule
+    fn partial_step(&mut self, window: &mut dyn Present, index: usize) -> Result<(), Error> {
+        self.run_until_interrupt(index)?;
 
-            for bit in 0..8 {
-                let color: u32 = if byte & (1 << bit) == 0 {
-                    0x00_00_00_00
-                } else {
-                    0xff_ff_ff_ff
-                };
+        let present = !self.interlaced || index + 1 == self.interrupt_schedule.len();
 
-                let x = (start_pixel + 8 * offset + bit) / Self::SCREEN_HEIGHT;
-                let y = Self::SCREEN_HEIGHT - 1 - (start_pixel + 8 * offset + bit) % Self::SCREEN_HEIGHT;
+        // Render the part of the screen drawn since the previous interrupt
+        self.screen(index);
+
+        if present {
+            let width = self.width();
+            let buffer: &[u32] = match self.effect {
+                Some(effect) => {
+                    self.effect_buffer.clear();
+                    self.effect_buffer.extend(
+                        self.window_buffer.iter().enumerate().map(|(index, &color)| {
+                            effect(index % width, index / width, color)
+                        }),
+                    );
+                    &self.effect_buffer
+                }
+                None => &self.window_buffer,
+            };
+
+            // Backends can fail transiently, so retry, then open the window again, before giving up on the frame
+            let mut result = window.present(buffer);
+            for _ in 0..Self::PRESENT_RETRIES {
+                if result.is_ok() {
+                    break;
+                }
+                result = window.present(buffer);
+            }
+            if result.is_err() {
+                result = window.recreate().and_then(|()| window.present(buffer));
+            }
 
-                self.window_buffer[x + y * Self::SCREEN_WIDTH] = color;
+            match result {
+                Ok(()) => self.failed_presents = 0,
+                Err(e) => {
+                    eprintln!("Failed to update window buffer: {e}");
+                    // The window stays stale until a frame gets through, stop if none does
+                    self.failed_presents += 1;
+                    if self.failed_presents >= Self::MAX_FAILED_PRESENTS {
+                        return Err(Error::Present(e));
+                    }
+                }
             }
         }
 
-        &self.window_buffer
+        Ok(())
     }
 
-    #[cfg(feature = "cpu_compare")]
-    fn cpu_compare(&self) {
-        assert_eq!(self.cpu.pc(), self.ref_cpu.pc.into(), "PC mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.a(), self.ref_cpu.a.into(), "A mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.b(), self.ref_cpu.b.into(), "B mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.c(), self.ref_cpu.c.into(), "C mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.d(), self.ref_cpu.d.into(), "D mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.e(), self.ref_cpu.e.into(), "E mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.h(), self.ref_cpu.h.into(), "H mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.l(), self.ref_cpu.l.into(), "L mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.sp(), self.ref_cpu.sp.into(), "SP mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.flags().sign, self.ref_cpu.conditions.s, "Sign mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.flags().zero, self.ref_cpu.conditions.z, "Zero mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.flags().aux_carry, self.ref_cpu.conditions.ac, "Aux mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.flags().parity, self.ref_cpu.conditions.p, "Parity mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
-        assert_eq!(self.cpu.flags().carry, self.ref_cpu.conditions.cy, "Carry mismatch\n{:?}\n{:?}", self.cpu, self.ref_cpu);
+    /// Runs exactly `instructions` instructions without firing interrupts, for debugging.
+    /// Interrupts already latched during the EI delay are still serviced.
+    #[must_use = "errors stop the emulation"]
+    pub fn run_instructions(&mut self, instructions: u64) -> Result<RunStats, Error> {
+        let mut stats = RunStats::default();
+        let interrupt_count = self.cpu.interrupt_count();
+
+        for _ in 0..instructions {
+            stats.instructions += 1;
+            stats.cycles += self.execute_instruction()?;
+        }
+        stats.interrupts = self.cpu.interrupt_count() - interrupt_count;
+
+        Ok(stats)
     }
-}
 
-pub struct SpaceInvadersIO {
-    shift_register: RegisterPair,
-    shift_amount: u8,
-    port0: u8,
-    port1: u8,
-    port2: u8,
-}
+    /// Runs at most `instructions` instructions without firing interrupts, for debugging. Stops on one of
+    /// `breakpoints` before executing its instruction, after an instruction writing to one of `watchpoints`,
+    /// or when halted. The breakpoint PC starts on doesn't stop it, so running again continues past it.
+    /// Instructions are profiled and compared like those of `run_instructions`.
+    #[must_use = "the stop reason tells whether a breakpoint was hit"]
+    pub fn run_to_breakpoint(&mut self, breakpoints: Vec<u16>, watchpoints: Vec<u16>, instructions: u64) -> StopReason {
+        self.cpu.set_breakpoints(breakpoints);
+        self.cpu.set_watchpoints(watchpoints);
 
-impl SpaceInvadersIO {
-    pub fn new() -> Self {
-        Self {
-            shift_register: RegisterPair::new(),
-            shift_amount: 0,
-            port0: 0b0111_0000,
-            port1: 0b0001_0000,
-            port2: 0b0000_0000,
+        for executed in 0..instructions {
+            if executed > 0 && self.cpu.is_at_breakpoint() {
+                return StopReason::Breakpoint(self.cpu.pc());
+            }
+            if self.cpu.is_halted() {
+                return StopReason::Halted;
+            }
+
+            self.cpu.take_watchpoint_hit();
+            if let Err(e) = self.execute_instruction() {
+                return StopReason::Error(e);
+            }
+            if let Some(address) = self.cpu.take_watchpoint_hit() {
+                return StopReason::Watchpoint(address);
+            }
         }
+
+        StopReason::InstructionCap
     }
 
-    fn update_input(&mut self, window: &minifb::Window) {
-        // Credit
-        Self::set_key(&mut self.port1, 0, window.is_key_down(minifb::Key::C));
-        // P2 Start
-        Self::set_key(&mut self.port1, 1, window.is_key_down(minifb::Key::W));
-        // P1 Start
-        Self::set_key(&mut self.port1, 2, window.is_key_down(minifb::Key::Q));
-        // Always 1
-        Self::set_key(&mut self.port1, 3, true);
+    /// Starts counting the executions and cycles of each opcode, from zero
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Box::new(OpcodeProfile::new()));
+    }
 
-        // P1 Fire
-        Self::set_key(&mut self.port1, 4, window.is_key_down(minifb::Key::Space));
-        // P1 Left
-        Self::set_key(&mut self.port1, 5, window.is_key_down(minifb::Key::A));
-        // P1 Right
-        Self::set_key(&mut self.port1, 6, window.is_key_down(minifb::Key::D));
+    /// Starts or stops recording interrupt requests. Starting clears the previous events.
+    pub fn set_interrupt_logging(&mut self, enabled: bool) {
+        self.interrupt_log = if enabled { Some(Vec::new()) } else { None };
+    }
 
-        // P2 Fire
-        Self::set_key(&mut self.port2, 4, window.is_key_down(minifb::Key::Enter));
-        // P2 Left
-        Self::set_key(&mut self.port2, 5, window.is_key_down(minifb::Key::Left));
-        // P2 Right
-        Self::set_key(&mut self.port2, 6, window.is_key_down(minifb::Key::Right));
+    /// Records the game's writes to RAM, keeping at most `capacity` of them according to `policy`,
+    /// or stops recording them with `None`
+    pub fn set_write_log(&mut self, log: Option<(usize, LogPolicy)>) {
+        self.cpu.set_write_log(log);
     }
 
-    fn set_key(port: &mut u8, bit: u8, on: bool) {
-        if on {
-            *port |= 1 << bit
-        } else {
-            *port &= !(1 << bit)
+    /// Reports instructions starting inside an instruction executed before, see `CpuState::set_check_alignment`
+    pub fn set_check_alignment(&mut self, check: bool) {
+        self.cpu.set_check_alignment(check);
+    }
+
+    /// Interrupt requests recorded since logging was enabled
+    pub fn interrupt_log(&self) -> &[InterruptEvent] {
+        self.interrupt_log.as_deref().unwrap_or(&[])
+    }
+
+    /// Per-opcode counters, if profiling was enabled
+    pub fn profile(&self) -> Option<&OpcodeProfile> {
+        self.profile.as_deref()
+    }
+
+    pub fn cpu(&self) -> &CpuState {
+        &self.cpu
+    }
+
+    /// Executes the next instruction, checking the result against the reference CPU if enabled
+    fn execute_instruction(&mut self) -> Result<u64, Error> {
+        #[cfg(all(feature = "cpu_compare", debug_assertions))]
+        let instruction = (self.cpu.pc(), self.cpu.disassemble_next().1);
+        // A halted CPU is waiting on HLT, with PC past it
+        let op_code = if self.cpu.is_halted() { 0x76 } else { self.cpu.peek(self.cpu.pc()) };
+        // Registers are only known before the instruction, so its access is found first
+        let accessed = self.profile.as_ref().and_then(|_| self.cpu.effective_address(op_code));
+        let cycles = self.cpu.emulate(&mut self.io_state)?;
+
+        if let Some(profile) = &mut self.profile {
+            profile.record(op_code, cycles, accessed);
+        }
+
+        #[cfg(all(feature = "cpu_compare", debug_assertions))]
+        if let Some(reference) = &mut self.reference {
+            reference.step(instruction, self.cpu.registers());
         }
+
+        // For monitoring/debug purposes
+        self.instructions += 1;
+        self.cycles += cycles;
+
+        Ok(cycles)
     }
-}
 
-impl IOState for SpaceInvadersIO {
-    fn input(&self, port: u8) -> u8 {
-        match port {
-            1 => self.port1,
-            2 => self.port2,
-            3 => (self.shift_register.both() >> (8 - self.shift_amount)) as u8,
-            _ => panic!("Cannot read port: {}", port),
+    /// Runs the CPU for `length` cycles, less the cycle debt, without firing interrupts
+    fn run_cycles(&mut self, length: u64) -> Result<RunStats, Error> {
+        let mut stats = RunStats::default();
+        // Cycles run past the previous part of the frame are taken from this one
+        let budget = length.saturating_sub(self.cycle_debt);
+
+        // Interrupts latched during the EI delay are serviced while running
+        let interrupt_count = self.cpu.interrupt_count();
+        while stats.cycles < budget {
+            stats.instructions += 1;
+            stats.cycles += self.execute_instruction()?;
         }
+        self.cycle_debt = (self.cycle_debt + stats.cycles).saturating_sub(length);
+        stats.interrupts = self.cpu.interrupt_count() - interrupt_count;
+
+        Ok(stats)
     }
 
-    fn output(&mut self, port: u8, value: u8) {
-        match port {
-            2 => self.shift_amount = value & 0b111,
-            4 => {
-                *self.shift_register.lsb_mut() = self.shift_register.msb();
-                *self.shift_register.msb_mut() = value;
+    /// Runs the rest of the frame after its last interrupt, when the schedule ends before the frame
+    fn run_to_end_of_frame(&mut self) -> Result<RunStats, Error> {
+        let (last_offset, _) = self.interrupt_schedule[self.interrupt_schedule.len() - 1];
+        self.run_cycles(self.cycles_per_frame - last_offset)
+    }
+
+    /// Runs the CPU from the previous interrupt of the schedule, or the start of the frame,
+    /// until the one at `index`, then fires it
+    fn run_until_interrupt(&mut self, index: usize) -> Result<RunStats, Error> {
+        let (offset, interrupt_num) = self.interrupt_schedule[index];
+        let length = offset - index.checked_sub(1).map_or(0, |previous| self.interrupt_schedule[previous].0);
+        let mut stats = self.run_cycles(length)?;
+
+        #[cfg(all(feature = "cpu_compare", debug_assertions))]
+        if let Some(reference) = &mut self.reference {
+            reference.cpu.interrupt(interrupt_num * 8);
+        }
+
+        let pc = self.cpu.pc();
+        let serviced = self.cpu.interrupt(interrupt_num)?;
+        stats.interrupts += u64::from(serviced);
+
+        if let Some(log) = &mut self.interrupt_log {
+            log.push(InterruptEvent {
+                frame: self.frames,
+                cycle: self.cycles - self.frame_start_cycles,
+                pc,
+                interrupt_num,
+                outcome: if serviced {
+                    InterruptOutcome::Serviced
+                } else if self.cpu.interrupt_pending() {
+                    InterruptOutcome::Latched
+                } else {
+                    InterruptOutcome::Dropped
+                },
+            });
+        }
+
+        Ok(stats)
+    }
+
+    fn screen(&mut self, index: usize) -> &[u32] {
+        let start = Self::VRAM_START;
+        let (offset, size) = self.vram_part(index);
+        let vram = &self.cpu.memory()[start + offset..start + offset + size];
+
+        self.renderer.render(vram, offset, &mut self.window_buffer, &self.render_options);
+        &self.window_buffer
+    }
+
+    /// Renders `lines` of text into `buffer` with the game's own font, from the top left of the screen,
+    /// leaving video memory untouched. Letters are drawn upper case, and characters missing from the font
+    /// as spaces. Text past the edges of the screen is cut. Only meaningful with the Space Invaders ROM.
+    /// Fails if `buffer` isn't the size of the screen.
+    pub fn render_text(&self, lines: &[String], buffer: &mut [u32]) -> Result<(), Error> {
+        self.check_buffer(buffer)?;
+        let mut vram = [0; Self::VRAM_SIZE];
+
+        for (line, text) in lines.iter().take(Self::TEXT_LINES).enumerate() {
+            for (column, character) in text.chars().take(Self::TEXT_COLUMNS).enumerate() {
+                let glyph_start = Self::FONT_ADDRESS + 8 * usize::from(Self::font_code(character));
+                let glyph = &self.cpu.memory()[glyph_start..glyph_start + 8];
+
+                // Each byte of the glyph is a column of pixels, and the first line is at the end of a column
+                for (x, &byte) in glyph.iter().enumerate() {
+                    vram[(8 * column + x) * Self::TEXT_LINES + Self::TEXT_LINES - 1 - line] = byte;
+                }
             }
-            3 | 5 | 6 => {}
-            _ => panic!("Cannot write to port: {}", port),
         }
+
+        self.renderer.render(&vram, 0, buffer, &self.render_options);
+        Ok(())
     }
-}
 
-#[cfg(feature = "cpu_compare")]
-impl i8080::Machine for SpaceInvadersIO {
-    fn input(&mut self, port: u8) -> u8 {
-        IOState::input(self, port)
+    /// Draws `lines` of text over the top of the screen rendered in `buffer`, like `render_text`,
+    /// clearing the lines they take so the text stays readable over the game, e.g. for menus
+    pub fn render_overlay(&self, lines: &[String], buffer: &mut [u32]) -> Result<(), Error> {
+        let mut text = vec![0; buffer.len()];
+        self.render_text(lines, &mut text)?;
+
+        let covered = (8 * lines.len().min(Self::TEXT_LINES) * self.render_options.width).min(buffer.len());
+        buffer[..covered].copy_from_slice(&text[..covered]);
+        Ok(())
     }
 
-    fn output(&mut self, port: u8, byte: u8) {
-        IOState::output(self, port, byte)
+    /// Index of a character in the game's font
+    fn font_code(character: char) -> u8 {
+        match character.to_ascii_uppercase() {
+            letter @ 'A'..='Z' => letter as u8 - b'A',
+            digit @ '0'..='9' => 0x1a + (digit as u8 - b'0'),
+            '<' => 0x24,
+            '>' => 0x25,
+            '=' => 0x27,
+            '*' => 0x28,
+            '-' => 0x3f,
+            // Space
+            _ => 0x26,
+        }
+    }
+
+    /// Renders the whole screen from video memory into `buffer`, without a window.
+    /// Fails if `buffer` isn't the size of the screen.
+    pub fn render_into(&self, buffer: &mut [u32]) -> Result<(), Error> {
+        self.check_buffer(buffer)?;
+        self.renderer.render(self.vram(), 0, buffer, &self.render_options);
+
+        if let Some(effect) = self.effect {
+            let width = self.render_options.width;
+            for (index, color) in buffer.iter_mut().enumerate() {
+                *color = effect(index % width, index / width, *color);
+            }
+        }
+        Ok(())
+    }
+
+    /// Buffers passed in by the caller must hold the whole screen, which renderers draw to without checking
+    fn check_buffer(&self, buffer: &[u32]) -> Result<(), Error> {
+        let expected = self.width() * self.height();
+        if buffer.len() != expected {
+            return Err(Error::BufferSize { expected, actual: buffer.len() });
+        }
+        Ok(())
+    }
+
+    /// Frames run since power on or the last reset
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Sets a per-pixel effect applied to the rendered screen, such as `scanlines`
+    pub fn set_effect(&mut self, effect: Option<PixelEffect>) {
+        self.effect = effect;
+    }
+
+    pub fn render_options(&self) -> RenderOptions {
+        self.render_options
+    }
+
+    /// Changes how video memory is converted to pixels, and the size of the screen
+    pub fn set_renderer(&mut self, renderer: Box<dyn Renderer>, render_options: RenderOptions) {
+        self.renderer = renderer;
+        self.render_options = render_options;
+        self.window_buffer = vec![0; render_options.width * render_options.height];
+    }
+
+    /// Width of the screen in pixels, from the render options
+    pub fn width(&self) -> usize {
+        self.render_options.width
+    }
+
+    /// Height of the screen in pixels, from the render options
+    pub fn height(&self) -> usize {
+        self.render_options.height
+    }
+
+    /// Offset and size of the part of video memory drawn by the beam before the interrupt at `index`,
+    /// since the previous one. The first part starts at the top of the screen and the last one ends
+    /// at the bottom, so the whole screen is drawn each frame.
+    fn vram_part(&self, index: usize) -> (usize, usize) {
+        let beam = |offset: u64| (offset * Self::VRAM_SIZE as u64 / self.cycles_per_frame) as usize;
+
+        let start = index.checked_sub(1).map_or(0, |previous| beam(self.interrupt_schedule[previous].0));
+        let end = if index + 1 == self.interrupt_schedule.len() {
+            Self::VRAM_SIZE
+        } else {
+            beam(self.interrupt_schedule[index].0)
+        };
+
+        (start, end - start)
+    }
+
+    fn vram(&self) -> &[u8] {
+        let start = Self::VRAM_START;
+        &self.cpu.memory()[start..start + Self::VRAM_SIZE]
+    }
+}
+
+/// Reference CPU run alongside the emulator's in debug builds with `cpu_compare`, with its own IO state
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
+struct Reference {
+    cpu: i8080::Cpu,
+    io_state: SpaceInvadersIO,
+    /// Last instructions executed, with the registers of both CPUs after them
+    history: VecDeque<((u16, String), Registers, Registers)>,
+}
+
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
+impl Reference {
+    /// Instructions shown when the reference CPU disagrees
+    const HISTORY: usize = 16;
+
+    /// Reference CPU running `rom`, loaded at address 0, from `start`, with `dip_switches` set
+    fn new(rom: &[u8], start: u16, dip_switches: DipSwitches) -> Self {
+        let mut cpu = i8080::Cpu::new();
+        cpu.load_into_rom(rom, 0);
+        *cpu.pc = start;
+        let mut io_state = SpaceInvadersIO::new();
+        io_state.dip_switches = dip_switches;
+
+        Self {
+            cpu,
+            io_state,
+            history: VecDeque::with_capacity(Self::HISTORY),
+        }
+    }
+
+    /// Registers of the reference CPU, in the same form as the emulator's
+    fn registers(&self) -> Registers {
+        Registers {
+            a: self.cpu.a.into(),
+            b: self.cpu.b.into(),
+            c: self.cpu.c.into(),
+            d: self.cpu.d.into(),
+            e: self.cpu.e.into(),
+            h: self.cpu.h.into(),
+            l: self.cpu.l.into(),
+            sp: self.cpu.sp.into(),
+            pc: self.cpu.pc.into(),
+            flags: Flags {
+                sign: self.cpu.conditions.s,
+                zero: self.cpu.conditions.z,
+                aux_carry: self.cpu.conditions.ac,
+                parity: self.cpu.conditions.p,
+                carry: self.cpu.conditions.cy,
+            },
+        }
+    }
+
+    /// Runs `instruction`, the address and name of the instruction the emulator just executed,
+    /// and records the registers of both CPUs after it. On mismatch, panics with the last
+    /// instructions side by side.
+    fn step(&mut self, instruction: (u16, String), registers: Registers) {
+        self.cpu.emulate(&mut self.io_state);
+        let ref_registers = self.registers();
+
+        if self.history.len() == Self::HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((instruction, registers, ref_registers));
+
+        if let Some(report) = divergence_report(&self.history) {
+            panic!("{}", report);
+        }
+    }
+}
+
+/// Report of the last instructions of `history`, with the registers of both CPUs after each,
+/// if they disagree after the last one. Instructions after which they disagree are marked.
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
+fn divergence_report(history: &VecDeque<((u16, String), Registers, Registers)>) -> Option<String> {
+    let (_, registers, ref_registers) = history.back()?;
+    let field = register_mismatch(registers, ref_registers)?;
+
+    let mut report = format!("{field} mismatch, last instructions (emulator | reference):\n");
+    for ((address, name), registers, ref_registers) in history {
+        let marker = if register_mismatch(registers, ref_registers).is_some() { " <<" } else { "" };
+        report += &format!(
+            "{:04x} {:<16} {} | {}{}\n",
+            address,
+            name,
+            format_registers(registers),
+            format_registers(ref_registers),
+            marker
+        );
+    }
+    for difference in ref_registers.diff(registers) {
+        report += &format!("{difference}\n");
+    }
+    Some(report)
+}
+
+/// Name of the first register differing between two states, ignoring the PSW bits
+/// which aren't flags
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
+fn register_mismatch(registers: &Registers, ref_registers: &Registers) -> Option<&'static str> {
+    let fields = [
+        ("PC", registers.pc, ref_registers.pc),
+        ("A", registers.a.into(), ref_registers.a.into()),
+        ("B", registers.b.into(), ref_registers.b.into()),
+        ("C", registers.c.into(), ref_registers.c.into()),
+        ("D", registers.d.into(), ref_registers.d.into()),
+        ("E", registers.e.into(), ref_registers.e.into()),
+        ("H", registers.h.into(), ref_registers.h.into()),
+        ("L", registers.l.into(), ref_registers.l.into()),
+        ("SP", registers.sp, ref_registers.sp),
+    ];
+
+    fields.iter()
+          .find(|&&(_, value, ref_value)| value != ref_value)
+          .map(|&(name, _, _)| name)
+          .or_else(|| {
+              if Flags::psw_eq(registers.flags.psw(), ref_registers.flags.psw()) {
+                  None
+              } else {
+                  Some("Flags")
+              }
+          })
+}
+
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
+fn format_registers(registers: &Registers) -> String {
+    format!(
+        "pc={:04x} a={:02x} b={:02x} c={:02x} d={:02x} e={:02x} h={:02x} l={:02x} sp={:04x} f={:02x}",
+        registers.pc,
+        registers.a,
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        registers.h,
+        registers.l,
+        registers.sp,
+        registers.flags.psw()
+    )
+}
+
+/// Returns the index of the first frame whose hash differs between two runs,
+/// including frames missing from one of them
+pub fn first_difference(expected: &[u64], actual: &[u64]) -> Option<usize> {
+    expected
+        .iter()
+        .zip(actual)
+        .position(|(expected, actual)| expected != actual)
+        .or_else(|| {
+            if expected.len() == actual.len() {
+                None
+            } else {
+                Some(expected.len().min(actual.len()))
+            }
+        })
+}
+
+/// FNV-1a hash, used to identify ROMs and frames
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Machine for SpaceInvaders {
+    fn frame_duration(&self) -> Duration {
+        Self::FRAME_DURATION
+    }
+
+    fn width(&self) -> usize {
+        SpaceInvaders::width(self)
+    }
+
+    fn height(&self) -> usize {
+        SpaceInvaders::height(self)
+    }
+
+    fn run_frames(&mut self, frames: u64) -> Result<RunStats, Error> {
+        SpaceInvaders::run_frames(self, frames)
+    }
+
+    fn vram_bitmap(&self) -> Vec<u8> {
+        self.vram().to_vec()
+    }
+
+    fn render_into(&self, buffer: &mut [u32]) -> Result<(), Error> {
+        SpaceInvaders::render_into(self, buffer)
+    }
+}
+
+/// Settings of the DIP switches on the board
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DipSwitches {
+    /// Ships per game, from 3 to 6
+    pub ships: u8,
+    /// Whether the extra ship is awarded at 1000 points instead of 1500
+    pub early_bonus: bool,
+    /// Whether to hide the coin info in attract mode
+    pub hide_coin_info: bool,
+}
+
+impl DipSwitches {
+    /// Bits of port 2 set by the switches
+    fn bits(self) -> u8 {
+        let mut bits = self.ships.clamp(3, 6) - 3;
+        if self.early_bonus {
+            bits |= 1 << 3;
+        }
+        if self.hide_coin_info {
+            bits |= 1 << 7;
+        }
+        bits
+    }
+}
+
+impl Default for DipSwitches {
+    fn default() -> Self {
+        Self {
+            ships: 3,
+            early_bonus: false,
+            hide_coin_info: false,
+        }
+    }
+}
+
+/// Settings of a machine, applied all at once by `SpaceInvaders::with_config`.
+/// Start from the defaults and change the ones needed, e.g.
+/// `Config { strict: true, ..Config::default() }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Cycles run per second, see `SpaceInvaders::set_clock_speed`
+    pub clock_speed: u64,
+    pub dip_switches: DipSwitches,
+    /// See `SpaceInvaders::set_strict`
+    pub strict: bool,
+    pub pc_wrap_policy: PcWrapPolicy,
+    /// Extra cycles taken to acknowledge an interrupt
+    pub interrupt_latency: u64,
+    /// See `SpaceInvaders::set_interlaced`
+    pub interlaced: bool,
+    pub audio_enabled: bool,
+    pub opposing_inputs: OpposingInputs,
+    /// Frames without a watchdog kick before resetting, `None` to disable the watchdog
+    pub watchdog: Option<u32>,
+    /// Memory kept through resets, see `SpaceInvaders::set_preserved_regions`
+    pub preserved_regions: Vec<Range<u16>>,
+}
+
+/// The settings of a newly created machine
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            clock_speed: SpaceInvaders::CLOCK_SPEED,
+            dip_switches: DipSwitches::default(),
+            strict: false,
+            pc_wrap_policy: PcWrapPolicy::Ignore,
+            interrupt_latency: 0,
+            interlaced: false,
+            audio_enabled: true,
+            opposing_inputs: OpposingInputs::Allow,
+            watchdog: None,
+            preserved_regions: Vec::new(),
+        }
+    }
+}
+
+pub struct SpaceInvadersIO {
+    /// Midway shift hardware: `OUT 4` shifts a byte in from the top,
+    /// `OUT 2` sets the offset, and `IN 3` reads the shifted result
+    shift_register: ShiftRegister,
+    port0: u8,
+    port1: u8,
+    port2: u8,
+    dip_switches: DipSwitches,
+    /// Sounds triggered through ports 3 and 5
+    sound: SoundState,
+    /// Set by `OUT 6`, until the machine restarts the watchdog countdown
+    watchdog_kicked: bool,
+    /// Whether accessing unknown ports is an error
+    strict: bool,
+}
+
+impl SpaceInvadersIO {
+    /// Bits 1 to 3 of port 0 are wired high
+    const PORT0_ALWAYS_ON: u8 = 0b0000_1110;
+    /// Bit of port 1 which is always set
+    const PORT1_ALWAYS_ON: u8 = 0b0000_1000;
+    /// Player 1 fire, left and right, wired to both port 0 and port 1
+    const PORT0_CONTROLS: u8 = 0b0111_0000;
+    /// Size of a serialized state: shift register, input ports and sound ports
+    const STATE_SIZE: usize = ShiftRegister::STATE_SIZE + 2 + 2;
+
+    pub fn new() -> Self {
+        Self {
+            shift_register: ShiftRegister::new(),
+            port0: Self::PORT0_ALWAYS_ON,
+            // Nothing pressed, rather than player 1 fire held until the first input update
+            port1: Self::PORT1_ALWAYS_ON,
+            port2: 0b0000_0000,
+            dip_switches: DipSwitches::default(),
+            sound: SoundState::new(),
+            watchdog_kicked: false,
+            strict: false,
+        }
+    }
+
+    /// Clears the shift register, inputs and sounds, keeping the settings
+    fn power_on(&mut self) {
+        let mut sound = SoundState::new();
+        sound.set_sample_set(self.sound.take_sample_set());
+
+        *self = Self {
+            dip_switches: self.dip_switches,
+            strict: self.strict,
+            sound,
+            ..Self::new()
+        };
+    }
+
+    fn save_state(&self) -> [u8; Self::STATE_SIZE] {
+        let shift = self.shift_register.save_state();
+        let [port3, port5] = self.sound.save_state();
+
+        [
+            shift[0],
+            shift[1],
+            shift[2],
+            self.port1,
+            self.port2,
+            port3,
+            port5,
+        ]
+    }
+
+    fn load_state(&mut self, state: [u8; Self::STATE_SIZE]) {
+        self.shift_register.load_state([state[0], state[1], state[2]]);
+        self.port1 = state[3];
+        self.port2 = state[4];
+        // Port 0 only mirrors port 1's controls, so it isn't saved
+        self.port0 = Self::PORT0_ALWAYS_ON | (self.port1 & Self::PORT0_CONTROLS);
+        self.sound.load_state([state[5], state[6]]);
+    }
+
+    /// Port 0 has player 1 controls, used by the self-test, alongside lines wired high.
+    /// Port 1 has the cabinet's shared inputs (coin and start buttons) and player 1 controls.
+    /// Port 2 has player 2 controls and tilt, alongside the DIP switches.
+    /// The game reads player 2's controls from port 2 in both upright and cocktail cabinets.
+    fn update_input(&mut self, joypad: JoypadState) {
+        // Credit
+        Self::set_key(&mut self.port1, 0, joypad.is_pressed(GameKey::Credit));
+        // P2 Start
+        Self::set_key(&mut self.port1, 1, joypad.is_pressed(GameKey::P2Start));
+        // P1 Start
+        Self::set_key(&mut self.port1, 2, joypad.is_pressed(GameKey::P1Start));
+        // Always 1
+        Self::set_key(&mut self.port1, 3, true);
+
+        // P1 Fire
+        Self::set_key(&mut self.port1, 4, joypad.is_pressed(GameKey::P1Fire));
+        // P1 Left
+        Self::set_key(&mut self.port1, 5, joypad.is_pressed(GameKey::P1Left));
+        // P1 Right
+        Self::set_key(&mut self.port1, 6, joypad.is_pressed(GameKey::P1Right));
+
+        // P1 Fire, Left and Right, the same bits as port 1
+        self.port0 = Self::PORT0_ALWAYS_ON | (self.port1 & Self::PORT0_CONTROLS);
+
+        // Tilt
+        Self::set_key(&mut self.port2, 2, joypad.is_pressed(GameKey::Tilt));
+        // P2 Fire
+        Self::set_key(&mut self.port2, 4, joypad.is_pressed(GameKey::P2Fire));
+        // P2 Left
+        Self::set_key(&mut self.port2, 5, joypad.is_pressed(GameKey::P2Left));
+        // P2 Right
+        Self::set_key(&mut self.port2, 6, joypad.is_pressed(GameKey::P2Right));
+    }
+
+    /// Controls currently pressed according to the port bits, the inverse of `update_input`
+    fn input_state(&self) -> JoypadState {
+        let mut joypad = JoypadState::new();
+
+        for &(key, port, bit) in &[
+            (GameKey::Credit, self.port1, 0),
+            (GameKey::P2Start, self.port1, 1),
+            (GameKey::P1Start, self.port1, 2),
+            (GameKey::P1Fire, self.port1, 4),
+            (GameKey::P1Left, self.port1, 5),
+            (GameKey::P1Right, self.port1, 6),
+            (GameKey::Tilt, self.port2, 2),
+            (GameKey::P2Fire, self.port2, 4),
+            (GameKey::P2Left, self.port2, 5),
+            (GameKey::P2Right, self.port2, 6),
+        ] {
+            joypad.set(key, port & (1 << bit) != 0);
+        }
+
+        joypad
+    }
+
+    fn set_key(port: &mut u8, bit: u8, on: bool) {
+        if on {
+            *port |= 1 << bit
+        } else {
+            *port &= !(1 << bit)
+        }
+    }
+}
+
+impl IOState for SpaceInvadersIO {
+    fn input(&self, port: u8) -> Result<u8, Error> {
+        match port {
+            0 => Ok(self.port0),
+            1 => Ok(self.port1),
+            2 => Ok(self.port2 | self.dip_switches.bits()),
+            // Writes are applied immediately, so this reflects any preceding OUT 2/OUT 4
+            3 => Ok(self.shift_register.read()),
+            _ if self.strict => Err(Error::InvalidInputPort(port)),
+            _ => Ok(0),
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) -> Result<(), Error> {
+        match port {
+            2 => self.shift_register.set_offset(value),
+            4 => self.shift_register.write_data(value),
+            3 | 5 => self.sound.output(port, value),
+            // Watchdog, the value doesn't matter
+            6 => self.watchdog_kicked = true,
+            _ if self.strict => return Err(Error::InvalidOutputPort(port)),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "cpu_compare", debug_assertions))]
+impl i8080::Machine for SpaceInvadersIO {
+    fn input(&mut self, port: u8) -> u8 {
+        IOState::input(self, port).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn output(&mut self, port: u8, byte: u8) {
+        IOState::output(self, port, byte).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Machine running `program` from address 0
+    fn machine_with(program: &[u8]) -> SpaceInvaders {
+        SpaceInvaders::from_rom(program).unwrap()
+    }
+
+    /// Whether the pixel at `x`, `y` of the rendered screen is lit
+    fn is_lit(invaders: &SpaceInvaders, x: usize, y: usize) -> bool {
+        let mut buffer = vec![0; SpaceInvaders::SCREEN_PIXELS];
+        invaders.render_into(&mut buffer).unwrap();
+        buffer[x + y * SpaceInvaders::SCREEN_WIDTH] != 0
+    }
+
+    #[test]
+    fn inr_m_and_dcr_m_update_the_screen() {
+        // LXI H, $2400; INR M; DCR M
+        let mut invaders = machine_with(&[0x21, 0x00, 0x24, 0x34, 0x35]);
+
+        invaders.run_instructions(1).unwrap();
+        assert_eq!(invaders.run_instructions(1).unwrap().cycles, 10);
+        assert_eq!(invaders.cpu().memory()[0x2400], 0x01);
+        // Bit 0 of the first byte of video memory is the bottom-left corner
+        assert!(is_lit(&invaders, 0, SpaceInvaders::SCREEN_HEIGHT - 1));
+
+        assert_eq!(invaders.run_instructions(1).unwrap().cycles, 10);
+        assert_eq!(invaders.cpu().memory()[0x2400], 0x00);
+        assert!(!is_lit(&invaders, 0, SpaceInvaders::SCREEN_HEIGHT - 1));
+    }
+
+    #[test]
+    fn save_and_load_state_through_a_file() {
+        let path = std::env::temp_dir().join("rust-8080-test-save-state.state");
+        // MVI A, $42; STA $2100; INR A
+        let program = [0x3e, 0x42, 0x32, 0x00, 0x21, 0x3c];
+        let mut invaders = machine_with(&program);
+        invaders.run_instructions(2).unwrap();
+        invaders.save_state(&path).unwrap();
+        let saved = invaders.cpu().registers();
+
+        invaders.run_instructions(1).unwrap();
+        assert_eq!(invaders.cpu().a(), 0x43);
+        invaders.load_state(&path).unwrap();
+        assert_eq!(invaders.cpu().registers(), saved);
+        assert_eq!(invaders.cpu().memory()[0x2100], 0x42);
+
+        // States only apply to the ROM they were saved with
+        let mut other = machine_with(&[0x00]);
+        assert!(matches!(other.load_state(&path), Err(Error::InvalidState(_))));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Program enabling interrupts and looping, with RST 1 and RST 2 handlers re-enabling them
+    const INTERRUPT_LOOP: [u8; 0x12] = [
+        // LXI SP, $2400; EI; JMP $0004
+        0x31, 0x00, 0x24, 0xfb, 0xc3, 0x04, 0x00, 0x00,
+        // RST 1: EI; RET
+        0xfb, 0xc9, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // RST 2: EI; RET
+        0xfb, 0xc9,
+    ];
+
+    #[test]
+    fn run_frames_fires_two_interrupts_per_frame() {
+        let mut invaders = machine_with(&INTERRUPT_LOOP);
+
+        let stats = invaders.run_frames(2).unwrap();
+        assert_eq!(stats.interrupts, 4);
+        assert_eq!(invaders.frame_count(), 2);
+        // Instructions can't be split, so parts of the frame run a few cycles over
+        let expected = 2 * SpaceInvaders::CYCLES_PER_FRAME;
+        assert!(stats.cycles >= expected - 20 && stats.cycles <= expected + 20, "{} cycles", stats.cycles);
+        assert!(stats.instructions > 0);
+    }
+
+    #[test]
+    fn in_3_reads_the_shift_written_just_before() {
+        let mut invaders = machine_with(&[
+            // MVI A, 3; OUT 2
+            0x3e, 0x03, 0xd3, 0x02,
+            // MVI A, $aa; OUT 4; MVI A, $ff; OUT 4
+            0x3e, 0xaa, 0xd3, 0x04, 0x3e, 0xff, 0xd3, 0x04,
+            // IN 3
+            0xdb, 0x03,
+        ]);
+
+        invaders.run_instructions(1).unwrap();
+        assert_eq!(invaders.run_instructions(1).unwrap().cycles, 10);
+        invaders.run_instructions(4).unwrap();
+        assert_eq!(invaders.run_instructions(1).unwrap().cycles, 10);
+        // $ffaa shifted left by 3, high byte
+        assert_eq!(invaders.cpu().a(), 0xfd);
+    }
+
+    #[test]
+    fn shift_offset_applies_to_data_written_before_it() {
+        let mut io_state = SpaceInvadersIO::new();
+        io_state.output(4, 0x0f).unwrap();
+        io_state.output(4, 0xf0).unwrap();
+        // At offset 0, the last byte written
+        assert_eq!(io_state.input(3).unwrap(), 0xf0);
+
+        io_state.output(2, 4).unwrap();
+        assert_eq!(io_state.input(3).unwrap(), 0x00);
+        io_state.output(2, 2).unwrap();
+        assert_eq!(io_state.input(3).unwrap(), 0xc0);
+    }
+
+    #[test]
+    fn self_modifying_code_runs_and_profiles_the_modified_bytes() {
+        // MVI A, $3c (INR A); JMP $2000
+        let mut invaders = machine_with(&[0x3e, 0x3c, 0xc3, 0x00, 0x20]);
+        // STA $2004; NOP; NOP, overwritten with INR A
+        invaders.cpu.set_memory_slice(0x2000, &[0x32, 0x04, 0x20, 0x00, 0x00]).unwrap();
+        invaders.enable_profiling();
+
+        invaders.run_instructions(4).unwrap();
+        assert_eq!(invaders.cpu().pc(), 0x2004);
+        assert_eq!(invaders.cpu().disassemble_next(), (vec![0x3c], "INR A".to_string()));
+
+        invaders.run_instructions(1).unwrap();
+        assert_eq!(invaders.cpu().a(), 0x3d);
+        let profile = invaders.profile().unwrap();
+        assert_eq!(profile.counts[0x3c], 1);
+        assert_eq!(profile.counts[0x00], 1);
+    }
+
+    #[test]
+    fn unknown_ports_are_errors_in_strict_mode() {
+        let mut io_state = SpaceInvadersIO::new();
+        assert_eq!(io_state.input(7).unwrap(), 0);
+        io_state.output(7, 0).unwrap();
+
+        io_state.strict = true;
+        assert!(matches!(io_state.input(7), Err(Error::InvalidInputPort(7))));
+        assert!(matches!(io_state.output(7, 0), Err(Error::InvalidOutputPort(7))));
+    }
+
+    #[test]
+    fn screen_halves_are_rendered_at_their_own_interrupt() {
+        let mut invaders = machine_with(&[0x00]);
+        let bottom_left = (SpaceInvaders::SCREEN_HEIGHT - 1) * SpaceInvaders::SCREEN_WIDTH;
+        let bottom_right = bottom_left + SpaceInvaders::SCREEN_WIDTH - 1;
+        // The first half of video memory is drawn before the middle of frame interrupt,
+        // the second half before the end of frame one
+        let last_line = 0x2400 + SpaceInvaders::VRAM_SIZE as u16 - 32;
+
+        invaders.cpu.set_memory_slice(0x2400, &[0x01]).unwrap();
+        invaders.screen(0);
+        assert_ne!(invaders.window_buffer[bottom_left], 0);
+        assert_eq!(invaders.window_buffer[bottom_right], 0);
+
+        // Changes to the first half after its interrupt only show on the next frame
+        invaders.cpu.set_memory_slice(0x2400, &[0x00]).unwrap();
+        invaders.cpu.set_memory_slice(last_line, &[0x01]).unwrap();
+        invaders.screen(1);
+        assert_ne!(invaders.window_buffer[bottom_left], 0);
+        assert_ne!(invaders.window_buffer[bottom_right], 0);
+
+        invaders.screen(0);
+        assert_eq!(invaders.window_buffer[bottom_left], 0);
+    }
+
+    #[test]
+    fn fire_buttons_set_their_port_bits() {
+        let mut io_state = SpaceInvadersIO::new();
+        let mut joypad = JoypadState::new();
+        joypad.set(GameKey::P1Fire, true);
+        io_state.update_input(joypad);
+        assert_eq!(io_state.input(1).unwrap() & 0b0111_0000, 0b0001_0000);
+        assert_eq!(io_state.input(0).unwrap() & 0b0111_0000, 0b0001_0000);
+        assert_eq!(io_state.input(2).unwrap() & 0b0111_0000, 0);
+
+        joypad.set(GameKey::P1Fire, false);
+        joypad.set(GameKey::P2Fire, true);
+        io_state.update_input(joypad);
+        assert_eq!(io_state.input(1).unwrap() & 0b0111_0000, 0);
+        assert_eq!(io_state.input(2).unwrap() & 0b0111_0000, 0b0001_0000);
+    }
+
+    #[test]
+    fn halted_cpu_reaches_the_next_interrupt() {
+        // LXI SP, $2400; EI; HLT
+        let mut invaders = machine_with(&[0x31, 0x00, 0x24, 0xfb, 0x76]);
+
+        // The first part of the frame lasts until the middle of frame interrupt
+        let cycles = invaders.run_until_interrupt(0).unwrap().cycles;
+        assert!(cycles >= SpaceInvaders::CYCLES_PER_FRAME / 2);
+        assert!(!invaders.cpu().is_halted());
+        assert_eq!(invaders.cpu().current_interrupt(), Some(1));
+        assert_eq!(invaders.cpu().pc(), 0x0008);
+        // Returning after the HLT
+        assert_eq!(invaders.cpu().stack(1), vec![0x0005]);
+    }
+
+    #[test]
+    #[cfg(feature = "default_rom")]
+    fn overlay_covers_only_the_lines_of_text() {
+        // Text is drawn with the font of the embedded ROM
+        let invaders = SpaceInvaders::new();
+        let lines = vec![" SHIPS = 4".to_string()];
+        let mut text = vec![0; SpaceInvaders::SCREEN_PIXELS];
+        invaders.render_text(&lines, &mut text).unwrap();
+
+        let mut buffer = vec![0x00ff_00ff; SpaceInvaders::SCREEN_PIXELS];
+        invaders.render_overlay(&lines, &mut buffer).unwrap();
+        let line = 8 * SpaceInvaders::SCREEN_WIDTH;
+        assert_eq!(buffer[..line], text[..line]);
+        assert!(buffer[..line].iter().any(|&pixel| pixel != 0));
+        assert!(buffer[line..].iter().all(|&pixel| pixel == 0x00ff_00ff));
+    }
+
+    #[test]
+    fn latched_interrupts_are_counted_in_the_run_stats() {
+        // LXI SP, $2400; EI, with NOPs up to the RST 1 handler
+        let mut invaders = machine_with(&[0x31, 0x00, 0x24, 0xfb]);
+        invaders.run_instructions(2).unwrap();
+
+        assert!(!invaders.cpu.interrupt(1).unwrap());
+        let stats = invaders.run_instructions(2).unwrap();
+        assert_eq!(stats, RunStats { instructions: 2, cycles: 4 + 4 + 11, interrupts: 1 });
+    }
+
+    #[test]
+    #[cfg(feature = "default_rom")]
+    fn frame_hashes_are_stable_across_runs() {
+        let hashes = SpaceInvaders::new().frame_hashes(30).unwrap();
+        assert_eq!(hashes.len(), 30);
+        assert_eq!(SpaceInvaders::new().frame_hashes(30).unwrap(), hashes);
+        assert_eq!(first_difference(&hashes, &hashes), None);
+
+        let mut diverged = hashes.clone();
+        diverged[20] ^= 1;
+        assert_eq!(first_difference(&hashes, &diverged), Some(20));
+        assert_eq!(first_difference(&hashes, &hashes[..25]), Some(25));
+    }
+
+    #[test]
+    fn saved_states_keep_the_shift_register() {
+        let path = std::env::temp_dir().join("rust-8080-test-shift-state.state");
+        let mut invaders = machine_with(&[
+            // MVI A, 2; OUT 2; MVI A, $81; OUT 4; MVI A, $c3; OUT 4
+            0x3e, 0x02, 0xd3, 0x02, 0x3e, 0x81, 0xd3, 0x04, 0x3e, 0xc3, 0xd3, 0x04,
+            // IN 3; MVI A, 0; OUT 4; JMP $000c
+            0xdb, 0x03, 0x3e, 0x00, 0xd3, 0x04, 0xc3, 0x0c, 0x00,
+        ]);
+        invaders.run_instructions(6).unwrap();
+        invaders.save_state(&path).unwrap();
+
+        // Shifting in 0 changes what IN 3 reads, until the state is loaded back
+        invaders.run_instructions(5).unwrap();
+        assert_eq!(invaders.cpu().a(), 0x03);
+        invaders.load_state(&path).unwrap();
+        invaders.run_instructions(1).unwrap();
+        // $c381 shifted left by 2, high byte
+        assert_eq!(invaders.cpu().a(), 0x0e);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_reference_cpu_is_only_compared_in_debug_builds() {
+        assert_eq!(SpaceInvaders::COMPARES_TO_REFERENCE, cfg!(all(feature = "cpu_compare", debug_assertions)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "cpu_compare", debug_assertions))]
+    #[should_panic(expected = "mismatch")]
+    fn divergence_from_the_reference_cpu_panics() {
+        // NOP
+        let mut invaders = machine_with(&[0x00]);
+        // A comes first in saved states
+        let mut state = invaders.cpu.save_state();
+        state[0] = 0x42;
+        invaders.cpu.load_state(&state).unwrap();
+        invaders.run_instructions(1).unwrap();
+    }
+
+    #[test]
+    fn player_2_controls_only_set_port_2_bits() {
+        let mut io_state = SpaceInvadersIO::new();
+        io_state.update_input(JoypadState::new());
+        let (idle0, idle1, idle2) = (io_state.input(0).unwrap(), io_state.input(1).unwrap(), io_state.input(2).unwrap());
+
+        let mut joypad = JoypadState::new();
+        for &(key, bit) in &[(GameKey::P2Fire, 4), (GameKey::P2Left, 5), (GameKey::P2Right, 6)] {
+            joypad.set(key, true);
+            io_state.update_input(joypad);
+            assert_eq!(io_state.input(2).unwrap() ^ idle2, 1 << bit, "{key:?}");
+            assert_eq!(io_state.input(1).unwrap(), idle1, "{key:?}");
+            assert_eq!(io_state.input(0).unwrap(), idle0, "{key:?}");
+            joypad.set(key, false);
+        }
+
+        // The start buttons and coin slot are shared by both players, on port 1
+        joypad.set(GameKey::P2Start, true);
+        io_state.update_input(joypad);
+        assert_eq!(io_state.input(1).unwrap() ^ idle1, 1 << 1);
+        assert_eq!(io_state.input(2).unwrap(), idle2);
+    }
+
+    #[test]
+    fn supported_keys_are_the_ones_read_by_the_game() {
+        let keys = SpaceInvaders::SUPPORTED_KEYS;
+        assert_eq!(keys.len(), 10);
+
+        for &key in keys {
+            let mut joypad = JoypadState::new();
+            joypad.set(key, true);
+            let mut io_state = SpaceInvadersIO::new();
+            io_state.update_input(joypad);
+            assert_eq!(io_state.input_state(), joypad, "{key:?}");
+        }
+        for key in &[GameKey::Credit, GameKey::P1Start, GameKey::P2Start, GameKey::P1Fire, GameKey::P2Fire, GameKey::Tilt] {
+            assert!(keys.contains(key), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn custom_renderers_replace_the_default_one() {
+        /// Fills the screen with the foreground, whatever video memory holds
+        struct FillRenderer;
+
+        impl Renderer for FillRenderer {
+            fn render(&self, _vram: &[u8], _offset: usize, out: &mut [u32], options: &RenderOptions) {
+                for pixel in out.iter_mut() {
+                    *pixel = options.foreground;
+                }
+            }
+        }
+
+        let mut invaders = machine_with(&[]);
+        let options = RenderOptions { width: 4, height: 2, foreground: 0x12_34_56, background: 0 };
+        invaders.set_renderer(Box::new(FillRenderer), options);
+
+        let mut buffer = vec![0; 8];
+        invaders.render_into(&mut buffer).unwrap();
+        assert_eq!(buffer, vec![0x12_34_56; 8]);
+        assert_eq!((invaders.width(), invaders.height()), (4, 2));
+    }
+
+    #[test]
+    fn frames_last_a_sixtieth_of_a_second() {
+        let invaders = machine_with(&[]);
+        let machine: &dyn Machine = &invaders;
+
+        let duration = machine.frame_duration().as_secs_f64() * 1000.0;
+        assert!((duration - 16.667).abs() < 0.001, "{} ms", duration);
+    }
+
+    #[test]
+    fn screen_buffers_are_the_size_of_the_screen() {
+        let mut invaders = machine_with(&[]);
+        assert_eq!(invaders.screen(0).len(), SpaceInvaders::SCREEN_WIDTH * SpaceInvaders::SCREEN_HEIGHT);
+        assert_eq!(invaders.screen(1).len(), SpaceInvaders::SCREEN_PIXELS);
+
+        let mut small = vec![0; SpaceInvaders::SCREEN_PIXELS - 1];
+        assert!(matches!(
+            invaders.render_into(&mut small),
+            Err(Error::BufferSize { expected: SpaceInvaders::SCREEN_PIXELS, actual }) if actual == small.len()
+        ));
+        assert!(matches!(invaders.render_overlay(&[], &mut small), Err(Error::BufferSize { .. })));
+
+        // Changing the renderer resizes the screen
+        let options = RenderOptions { width: 256, height: 224, ..invaders.render_options() };
+        invaders.set_renderer(Box::new(RotatedRenderer), options);
+        assert_eq!(invaders.screen(0).len(), 256 * 224);
+        assert!(invaders.render_into(&mut vec![0; 256 * 224]).is_ok());
+    }
+
+    #[test]
+    fn inserted_coins_are_held_then_released() {
+        let mut invaders = machine_with(&INTERRUPT_LOOP);
+        invaders.insert_coin();
+        invaders.insert_coin();
+
+        let mut credit = Vec::new();
+        for _ in 0..=(4 * SpaceInvaders::PULSE_FRAMES) {
+            credit.push(invaders.input_state().is_pressed(GameKey::Credit));
+            invaders.run_frames(1).unwrap();
+        }
+
+        // Each coin is held, then released for as long before the next one
+        let held = |frame: u32| frame < 4 * SpaceInvaders::PULSE_FRAMES && frame / SpaceInvaders::PULSE_FRAMES % 2 == 0;
+        assert_eq!(credit, (0..=4 * SpaceInvaders::PULSE_FRAMES).map(held).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn profile_counts_each_executed_opcode() {
+        // MVI A, 1; INR A; INR A; INR A; NOP
+        let mut invaders = machine_with(&[0x3e, 0x01, 0x3c, 0x3c, 0x3c, 0x00]);
+        assert!(invaders.profile().is_none());
+        invaders.enable_profiling();
+        invaders.run_instructions(5).unwrap();
+
+        let profile = invaders.profile().unwrap();
+        assert_eq!(profile.by_cycles(), vec![(0x3c, 3, 15), (0x3e, 1, 7), (0x00, 1, 4)]);
+    }
+
+    #[test]
+    fn every_port_2_value_selects_an_offset_from_0_to_7() {
+        let mut io_state = SpaceInvadersIO::new();
+        io_state.output(4, 0x3c).unwrap();
+        io_state.output(4, 0xa5).unwrap();
+
+        for value in 0..=255u8 {
+            io_state.output(2, value).unwrap();
+            // Only the 3 low bits are decoded, higher ones must not shift bits out of the register
+            let offset = value & 0b111;
+            let expected = (0xa53cu16 >> (8 - offset)) as u8;
+            assert_eq!(io_state.input(3).unwrap(), expected, "OUT 2 with {value:#04x}");
+        }
+    }
+
+    #[test]
+    fn roms_run_without_the_embedded_one() {
+        // MVI A, $42; STA $2400; HLT
+        let mut invaders = SpaceInvaders::from_rom([0x3e, 0x42, 0x32, 0x00, 0x24, 0x76]).unwrap();
+        invaders.run_instructions(3).unwrap();
+        assert_eq!(invaders.cpu().peek(0x2400), 0x42);
+        assert!(invaders.cpu().is_halted());
+    }
+
+    #[test]
+    #[cfg(feature = "default_rom")]
+    fn the_embedded_rom_boots() {
+        let mut invaders = SpaceInvaders::new();
+        invaders.run_frames(60).unwrap();
+        // The attract mode has drawn its text by then
+        assert!(invaders.vram_bitmap().iter().any(|&byte| byte != 0));
+    }
+
+    #[test]
+    fn cancelled_directions_are_cleared_before_the_ports_are_set() {
+        let mut invaders = machine_with(&[]);
+        invaders.set_opposing_inputs(OpposingInputs::Cancel);
+        let mut joypad = JoypadState::new();
+        joypad.set(GameKey::P1Left, true);
+        joypad.set(GameKey::P1Right, true);
+        joypad.set(GameKey::P2Left, true);
+        joypad.set(GameKey::P2Right, true);
+        invaders.set_input(joypad);
+
+        // Bits 5 and 6 are left and right, on port 1 for player 1 and port 2 for player 2
+        assert_eq!(invaders.io_state.input(1).unwrap() & 0b0110_0000, 0);
+        assert_eq!(invaders.io_state.input(2).unwrap() & 0b0110_0000, 0);
+    }
+
+    #[test]
+    fn the_watchdog_resets_like_the_reset_key() {
+        // MVI A, $01; OUT 3 (UFO sound); STA $2400; JMP $0007, never kicking the watchdog
+        let program = [0x3e, 0x01, 0xd3, 0x03, 0x32, 0x00, 0x24, 0xc3, 0x07, 0x00];
+        let mut by_key = machine_with(&program);
+        by_key.run_frames(2).unwrap();
+        // Ports 3 and 5 of the sound hardware
+        assert_eq!(by_key.io_state.sound.save_state(), [0x01, 0x00]);
+        by_key.reset(ResetKind::Warm);
+
+        let mut by_watchdog = machine_with(&program);
+        by_watchdog.set_watchdog(Some(2));
+        by_watchdog.run_frames(2).unwrap();
+
+        for invaders in &[&by_key, &by_watchdog] {
+            assert_eq!(invaders.cpu().pc(), 0x0000);
+            assert_eq!(invaders.frame_count(), 0);
+            assert_eq!((invaders.instructions, invaders.cycles), (0, 0));
+            assert_eq!(invaders.io_state.sound.save_state(), [0x00, 0x00]);
+            assert!(invaders.window_buffer.iter().all(|&pixel| pixel == invaders.window_buffer[0]));
+            // A warm reset keeps RAM
+            assert_eq!(invaders.cpu().peek(0x2400), 0x01);
+        }
+        assert_eq!(by_key.cpu().save_state(), by_watchdog.cpu().save_state());
+    }
+
+    #[test]
+    fn frames_run_the_same_instructions_from_the_same_state() {
+        // NOP; JMP $0000, taking 14 cycles a loop, which doesn't divide a frame evenly
+        let frames = |mut invaders: SpaceInvaders| (0..10).map(|_| invaders.run_frames(1).unwrap()).collect::<Vec<_>>();
+        let first = frames(machine_with(&[0x00, 0xc3, 0x00, 0x00]));
+        let second = frames(machine_with(&[0x00, 0xc3, 0x00, 0x00]));
+        assert_eq!(first, second);
+
+        // Cycles left over at the end of a frame are taken from the next one
+        let cycles = first.iter().map(|stats| stats.cycles).sum::<u64>();
+        assert!(cycles - 10 * SpaceInvaders::CYCLES_PER_FRAME < 14);
+    }
+
+    #[test]
+    fn injected_fire_is_read_back_from_the_ports() {
+        let mut invaders = machine_with(&[]);
+        assert!(!invaders.input_state().is_pressed(GameKey::P1Fire));
+
+        let mut joypad = JoypadState::new();
+        joypad.set(GameKey::P1Fire, true);
+        invaders.set_input(joypad);
+        assert!(invaders.input_state().is_pressed(GameKey::P1Fire));
+        assert!(!invaders.input_state().is_pressed(GameKey::P2Fire));
+        assert_eq!(invaders.input_state(), joypad);
+
+        invaders.set_input(JoypadState::new());
+        assert!(!invaders.input_state().is_pressed(GameKey::P1Fire));
+    }
+
+    #[test]
+    #[cfg(all(feature = "cpu_compare", debug_assertions))]
+    fn divergence_reports_both_cpus_side_by_side() {
+        let agreed = Registers { a: 0x00, ..machine_with(&[]).cpu().registers() };
+        let mut history = VecDeque::new();
+        history.push_back(((0x0000, "NOP".to_string()), Registers { pc: 1, ..agreed }, Registers { pc: 1, ..agreed }));
+        assert_eq!(divergence_report(&history), None);
+
+        history.push_back(((0x0001, "INR A".to_string()), Registers { pc: 2, a: 0x43, ..agreed }, Registers { pc: 2, a: 0x01, ..agreed }));
+        let report = divergence_report(&history).expect("The CPUs disagree on A");
+        let lines: Vec<_> = report.lines().collect();
+
+        assert_eq!(lines[0], "A mismatch, last instructions (emulator | reference):");
+        // The instruction both CPUs agreed on isn't marked, the one they diverged after is
+        assert!(lines[1].starts_with("0000 NOP ") && !lines[1].ends_with("<<"));
+        assert!(lines[2].starts_with("0001 INR A ") && lines[2].ends_with(" <<"));
+        let (emulator, reference) = lines[2].split_once(" | ").unwrap();
+        assert!(emulator.contains("a=43"));
+        assert!(reference.contains("a=01"));
+        // Followed by the differing fields, taking the reference as expected
+        assert_eq!(lines[3..], ["A: expected 0x01, got 0x43"]);
+    }
+
+    #[test]
+    fn out_6_kicks_the_watchdog() {
+        // OUT 6; JMP $0000
+        let mut kicking = machine_with(&[0xd3, 0x06, 0xc3, 0x00, 0x00]);
+        kicking.set_watchdog(Some(2));
+        kicking.run_frames(5).unwrap();
+        // Kicked every frame, the countdown never gets past the current frame
+        assert_eq!(kicking.frame_count(), 5);
+        assert_eq!(kicking.watchdog_frames, 1);
+
+        // JMP $0000
+        let mut stuck = machine_with(&[0xc3, 0x00, 0x00]);
+        stuck.set_watchdog(Some(2));
+        stuck.run_frames(3).unwrap();
+        assert_eq!(stuck.frame_count(), 1);
+
+        // Any value kicks it, even in strict mode
+        let mut io_state = SpaceInvadersIO::new();
+        io_state.strict = true;
+        io_state.output(6, 0xff).unwrap();
+        assert!(io_state.watchdog_kicked);
+    }
+
+    #[test]
+    fn the_window_buffer_is_the_size_of_the_screen() {
+        let mut invaders = machine_with(&[]);
+        assert_eq!(invaders.window_buffer.len(), SpaceInvaders::SCREEN_WIDTH * SpaceInvaders::SCREEN_HEIGHT);
+        // One bit of video memory per pixel
+        assert_eq!(8 * SpaceInvaders::VRAM_SIZE, invaders.window_buffer.len());
+
+        invaders.reset(ResetKind::Cold);
+        assert_eq!(invaders.window_buffer.len(), SpaceInvaders::SCREEN_PIXELS);
+        invaders.run_frames(1).unwrap();
+        assert_eq!(invaders.window_buffer.len(), SpaceInvaders::SCREEN_PIXELS);
+    }
+
+    #[test]
+    fn rom_files_are_loaded_as_binary_or_intel_hex() {
+        // MVI A, $42; HLT
+        let binary = std::env::temp_dir().join("rust-8080-test-rom.bin");
+        let hex = std::env::temp_dir().join("rust-8080-test-rom.hex");
+        std::fs::write(&binary, [0x3e, 0x42, 0x76]).unwrap();
+        std::fs::write(&hex, ":030000003E427607\n:00000001FF\n").unwrap();
+
+        for path in &[&binary, &hex] {
+            let mut invaders = SpaceInvaders::from_rom_file(path).unwrap();
+            assert_eq!(invaders.cpu().memory()[..4], [0x3e, 0x42, 0x76, 0x00], "{path:?}");
+            invaders.run_instructions(2).unwrap();
+            assert_eq!(invaders.cpu().a(), 0x42);
+            assert!(invaders.cpu().is_halted());
+        }
+
+        // Malformed HEX files are errors rather than loaded as binary
+        std::fs::write(&hex, ":030000003E427600\n:00000001FF\n").unwrap();
+        assert!(matches!(SpaceInvaders::from_rom_file(&hex), Err(Error::Hex(_))));
+        assert!(matches!(
+            SpaceInvaders::from_rom_file(std::env::temp_dir().join("rust-8080-test-missing.rom")),
+            Err(Error::Io(_))
+        ));
+        std::fs::remove_file(binary).unwrap();
+
+        // MVI A, $42; HLT at $0100, starting there
+        std::fs::write(&hex, ":030100003E427606\n:0400000500000100F6\n:00000001FF\n").unwrap();
+        let mut invaders = SpaceInvaders::from_rom_file(&hex).unwrap();
+        assert_eq!(invaders.cpu().pc(), 0x0100);
+        invaders.run_instructions(2).unwrap();
+        assert_eq!(invaders.cpu().a(), 0x42);
+        std::fs::remove_file(hex).unwrap();
+    }
+
+    #[test]
+    fn reset_timing_forgets_the_cycle_debt() {
+        // Only NOPs, 4 cycles each
+        let half_frame = SpaceInvaders::CYCLES_PER_FRAME / 2;
+        let mut indebted = machine_with(&[]);
+        indebted.cycle_debt = 10_000;
+        let cycles = indebted.run_until_interrupt(0).unwrap().cycles;
+        assert!(cycles >= half_frame - 10_000 && cycles < half_frame - 10_000 + 4);
+        assert!(indebted.cycle_debt < 4);
+
+        let mut reset = machine_with(&[]);
+        reset.cycle_debt = 10_000;
+        reset.reset_timing();
+        assert_eq!(reset.cycle_debt, 0);
+        let cycles = reset.run_until_interrupt(0).unwrap().cycles;
+        assert!(cycles >= half_frame && cycles < half_frame + 4);
+    }
+
+    #[test]
+    fn interrupts_are_logged_with_their_frame_and_cycle() {
+        // LXI SP, $2400; EI; JMP $0004, with handlers EI; RET at $0008 and $0010
+        let mut program = vec![0x31, 0x00, 0x24, 0xfb, 0xc3, 0x04, 0x00, 0x00, 0xfb, 0xc9];
+        program.resize(0x10, 0x00);
+        program.extend_from_slice(&[0xfb, 0xc9]);
+        let mut invaders = machine_with(&program);
+        invaders.set_interrupt_logging(true);
+        invaders.run_frames(1).unwrap();
+
+        let log = invaders.interrupt_log();
+        assert_eq!(log.len(), 2);
+        for (event, &(offset, interrupt_num)) in log.iter().zip(&SpaceInvaders::INTERRUPT_SCHEDULE) {
+            assert_eq!((event.frame, event.interrupt_num, event.outcome), (0, interrupt_num, InterruptOutcome::Serviced));
+            // Requested once the instruction running past the schedule, a JMP, completes
+            assert!(event.cycle >= offset && event.cycle < offset + 10, "{:?}", event);
+            assert_eq!(event.pc, 0x0004);
+        }
+
+        invaders.set_interrupt_logging(false);
+        assert!(invaders.interrupt_log().is_empty());
+    }
+
+    #[test]
+    fn scanlines_darken_the_odd_rows_of_the_rendered_screen() {
+        let mut invaders = machine_with(&[0x00]);
+        invaders.cpu.memory_mut()[0x2400..0x4000].fill(0xff);
+        invaders.set_effect(Some(crate::render::scanlines));
+
+        let mut buffer = vec![0; SpaceInvaders::SCREEN_PIXELS];
+        invaders.render_into(&mut buffer).unwrap();
+        for (y, row) in buffer.chunks(SpaceInvaders::SCREEN_WIDTH).enumerate() {
+            let expected = if y % 2 == 0 { 0xff_ff_ff_ff } else { 0x00_7f_7f_7f };
+            assert!(row.iter().all(|&color| color == expected), "row {}", y);
+        }
+
+        // Without an effect, every row is lit
+        invaders.set_effect(None);
+        invaders.render_into(&mut buffer).unwrap();
+        assert!(buffer.iter().all(|&color| color == 0xff_ff_ff_ff));
+    }
+
+    #[test]
+    fn player_1_fire_is_read_from_ports_0_and_1() {
+        // IN 0; MOV B, A; IN 1
+        let program = [0xdb, 0x00, 0x47, 0xdb, 0x01];
+        let fire = 1 << 4;
+
+        for pressed in [false, true] {
+            let mut invaders = machine_with(&program);
+            let mut joypad = JoypadState::new();
+            joypad.set(GameKey::P1Fire, pressed);
+            invaders.set_input(joypad);
+            invaders.run_instructions(3).unwrap();
+
+            let registers = invaders.cpu().registers();
+            assert_eq!(registers.b & fire != 0, pressed, "port 0");
+            assert_eq!(registers.a & fire != 0, pressed, "port 1");
+            // Bits 1 to 3 of port 0 are wired high
+            assert_eq!(registers.b & 0b0000_1110, 0b0000_1110);
+        }
+    }
+
+    #[test]
+    fn muted_machines_run_the_same_silently() {
+        // MVI A, $22; OUT $03, turning the amplifier on and playing the shot; JMP $0004
+        let program = [0x3e, 0x22, 0xd3, 0x03, 0xc3, 0x04, 0x00];
+        let mut playing = machine_with(&program);
+        let mut muted = machine_with(&program).with_config(Config { audio_enabled: false, ..Config::default() }).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(playing.run_frames(1).unwrap(), muted.run_frames(1).unwrap());
+        }
+        assert_eq!(playing.cpu().registers(), muted.cpu().registers());
+
+        let mut samples = [0.; 1000];
+        playing.audio_samples(&mut samples, 44_100);
+        assert!(samples.iter().any(|&sample| sample != 0.));
+        muted.audio_samples(&mut samples, 44_100);
+        assert!(samples.iter().all(|&sample| sample == 0.));
+
+        // The sound played on while muted, so it carries on where it is when unmuted
+        muted.set_audio_enabled(true);
+        muted.audio_samples(&mut samples, 44_100);
+        assert!(samples.iter().any(|&sample| sample != 0.));
+    }
+
+    #[test]
+    fn runs_count_both_instructions_and_cycles() {
+        // NOP; MVI A, $01; JMP $0000
+        let mut invaders = machine_with(&[0x00, 0x3e, 0x01, 0xc3, 0x00, 0x00]);
+
+        let stats = invaders.run_instructions(3).unwrap();
+        assert_eq!((stats.instructions, stats.cycles), (3, 4 + 7 + 10));
+
+        let stats = invaders.run_frames(1).unwrap();
+        // Instructions take 4 cycles at least
+        assert!(stats.instructions > 0 && 4 * stats.instructions <= stats.cycles, "{:?}", stats);
+        assert!(stats.cycles >= SpaceInvaders::CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn rendering_reuses_the_window_buffer() {
+        let mut invaders = machine_with(&[0x00]);
+        // The bottom-left and top-right corners, drawn by different parts of the frame
+        invaders.cpu.memory_mut()[0x2400] = 0x01;
+        invaders.cpu.memory_mut()[0x3fff] = 0x80;
+        let pointer = invaders.window_buffer.as_ptr();
+
+        let first = invaders.screen(0).as_ptr();
+        let second = invaders.screen(1).as_ptr();
+        assert_eq!((first, second), (pointer, pointer));
+
+        let bottom_left = (SpaceInvaders::SCREEN_HEIGHT - 1) * SpaceInvaders::SCREEN_WIDTH;
+        let top_right = SpaceInvaders::SCREEN_WIDTH - 1;
+        assert_ne!(invaders.window_buffer[bottom_left], 0);
+        assert_ne!(invaders.window_buffer[top_right], 0);
+        assert_eq!(invaders.window_buffer.iter().filter(|&&pixel| pixel != 0).count(), 2);
+    }
+
+    #[test]
+    fn a_single_vblank_interrupt_fires_once_per_frame() {
+        // LXI SP, $2400; EI; JMP $0004, with an EI; RET handler at $0010
+        let mut program = vec![0x31, 0x00, 0x24, 0xfb, 0xc3, 0x04, 0x00];
+        program.resize(0x10, 0x00);
+        program.extend_from_slice(&[0xfb, 0xc9]);
+        let mut invaders = machine_with(&program);
+        invaders.set_interrupt_schedule(vec![(50_000, 2)]).unwrap();
+        invaders.set_interrupt_logging(true);
+
+        for _ in 0..3 {
+            assert_eq!(invaders.run_frames(1).unwrap().interrupts, 1);
+        }
+        let log = invaders.interrupt_log();
+        assert_eq!(log.len(), 3);
+        for (frame, event) in log.iter().enumerate() {
+            assert_eq!((event.frame, event.interrupt_num), (frame as u64, 2));
+            assert!(event.cycle >= 50_000 && event.cycle < 50_010, "{:?}", event);
+        }
+
+        for schedule in [vec![], vec![(0, 1)], vec![(40_000, 1), (30_000, 2)], vec![(SpaceInvaders::CYCLES_PER_FRAME + 1, 1)], vec![(50_000, 8)], vec![(50_000, 8192)]] {
+            assert!(matches!(invaders.set_interrupt_schedule(schedule), Err(Error::InvalidInterruptSchedule(_))));
+        }
+        // The schedule is left as it was
+        assert_eq!(invaders.run_frames(1).unwrap().interrupts, 1);
+    }
+
+    #[test]
+    fn preserved_credits_survive_resets_which_clear_the_rest() {
+        // MVI A, $42; LXI SP, $2400; JMP $0004
+        let mut invaders = machine_with(&[0x3e, 0x42, 0x31, 0x00, 0x24, 0xc3, 0x05, 0x00]);
+        invaders.set_preserved_regions(vec![SpaceInvaders::CREDITS_REGION]);
+        let credits = usize::from(SpaceInvaders::CREDITS_REGION.start);
+
+        for kind in [ResetKind::Warm, ResetKind::Cold] {
+            invaders.run_instructions(3).unwrap();
+            invaders.cpu.memory_mut()[credits] = 0x03;
+            invaders.cpu.memory_mut()[credits + 1] = 0x07;
+
+            invaders.reset(kind);
+            assert_eq!(invaders.cpu().memory()[credits], 0x03, "{kind:?}");
+            assert_eq!(invaders.cpu().pc(), 0x0000);
+        }
+        // Only a cold reset clears the registers and the rest of RAM, like powering on
+        let registers = invaders.cpu().registers();
+        assert_eq!((registers.a, registers.sp), (0x00, 0x0000));
+        assert_eq!(invaders.cpu().memory()[credits + 1], 0x00);
+    }
+
+    #[test]
+    fn the_frame_buffer_follows_the_screen_dimensions() {
+        /// Draws nothing, whatever the screen size
+        struct BlankRenderer;
+
+        impl Renderer for BlankRenderer {
+            fn render(&self, _vram: &[u8], _offset: usize, _out: &mut [u32], _options: &RenderOptions) {}
+        }
+
+        let mut invaders = machine_with(&[0x00]);
+        let options = RenderOptions { width: 256, height: 192, ..SpaceInvaders::RENDER_OPTIONS };
+        invaders.set_renderer(Box::new(BlankRenderer), options);
+
+        assert_eq!((Machine::width(&invaders), Machine::height(&invaders)), (256, 192));
+        assert_eq!(invaders.window_buffer.len(), 256 * 192);
+        invaders.run_frames(1).unwrap();
+        assert_eq!(invaders.screen(1).len(), 256 * 192);
+
+        let mut buffer = vec![0; 256 * 192];
+        invaders.render_into(&mut buffer).unwrap();
+        // Buffers the size of the Space Invaders screen no longer fit
+        let mut buffer = vec![0; SpaceInvaders::SCREEN_PIXELS];
+        assert!(matches!(invaders.render_into(&mut buffer), Err(Error::BufferSize { .. })));
+    }
+
+    #[test]
+    fn stores_to_the_ram_mirror_read_back_below_it() {
+        // MVI A, $01; STA $4400; MVI A, $00; LDA $2400; LXI H, $8001; SHLD $4402; LXI H, $0000; LHLD $2402
+        let mut invaders = machine_with(&[
+            0x3e, 0x01, 0x32, 0x00, 0x44, 0x3e, 0x00, 0x3a, 0x00, 0x24,
+            0x21, 0x01, 0x80, 0x22, 0x02, 0x44, 0x21, 0x00, 0x00, 0x2a, 0x02, 0x24,
+        ]);
+
+        invaders.run_instructions(4).unwrap();
+        assert_eq!(invaders.cpu().a(), 0x01);
+        assert_eq!(invaders.cpu().memory()[0x2400], 0x01);
+        // $2400 is the start of video memory, whose bit 0 is the bottom-left corner
+        assert!(is_lit(&invaders, 0, SpaceInvaders::SCREEN_HEIGHT - 1));
+
+        invaders.run_instructions(4).unwrap();
+        assert_eq!(invaders.cpu().hl(), 0x8001);
+        assert_eq!(invaders.cpu().memory()[0x2402..0x2404], [0x01, 0x80]);
+    }
+
+    #[test]
+    fn watchpoints_stop_on_stores_to_the_ram_mirror() {
+        // MVI A, $01; STA $4400; NOP
+        let mut invaders = machine_with(&[0x3e, 0x01, 0x32, 0x00, 0x44, 0x00]);
+
+        assert!(matches!(invaders.run_to_breakpoint(Vec::new(), vec![0x4400], 10), StopReason::Watchpoint(0x4400)));
+        assert_eq!(invaders.cpu().pc(), 0x0005);
+        assert_eq!(invaders.cpu().memory()[0x2400], 0x01);
+    }
+
+    #[test]
+    fn vram_bitmaps_are_the_bytes_the_game_wrote() {
+        let mut invaders = machine_with(&[0x00]);
+        let pattern = (0..0x1c00).map(|index| (index * 7 % 256) as u8).collect::<Vec<_>>();
+        invaders.cpu.memory_mut()[0x2400..0x4000].copy_from_slice(&pattern);
+
+        let bitmap = Machine::vram_bitmap(&invaders);
+        assert_eq!(bitmap, pattern);
+
+        // Each line of video memory is a column of the screen, 256 pixels high
+        let pbm = crate::render::bitmap_to_pbm(&bitmap, SpaceInvaders::VRAM_LINE_PIXELS);
+        assert!(pbm.starts_with(b"P4\n256 224\n"));
+        assert_eq!(pbm.len(), "P4\n256 224\n".len() + 0x1c00);
+        assert_eq!(pbm[pbm.len() - 0x1c00 + 1], pattern[1].reverse_bits());
+    }
+
+    #[test]
+    fn watchpoints_stop_on_writes_to_the_ram_they_mirror() {
+        // MVI A, $01; STA $4400; STA $2401; NOP
+        let program = [0x3e, 0x01, 0x32, 0x00, 0x44, 0x32, 0x01, 0x24, 0x00];
+
+        let mut invaders = machine_with(&program);
+        assert!(matches!(invaders.run_to_breakpoint(Vec::new(), vec![0x2400, 0x4401], 10), StopReason::Watchpoint(0x2400)));
+        assert_eq!(invaders.cpu().pc(), 0x0005);
+        assert!(matches!(invaders.run_to_breakpoint(Vec::new(), vec![0x2400, 0x4401], 10), StopReason::Watchpoint(0x4401)));
+        assert_eq!(invaders.cpu().pc(), 0x0008);
+    }
+
+    #[test]
+    fn running_to_a_breakpoint_counts_and_profiles_each_instruction() {
+        // MVI A, $01; INR A; NOP; JMP $0000
+        let mut invaders = machine_with(&[0x3e, 0x01, 0x3c, 0x00, 0xc3, 0x00, 0x00]);
+        invaders.enable_profiling();
+
+        assert!(matches!(invaders.run_to_breakpoint(vec![0x0003], Vec::new(), 10), StopReason::Breakpoint(0x0003)));
+        assert_eq!(invaders.instructions, 2);
+        assert_eq!(invaders.cycles, 7 + 5);
+        assert_eq!(invaders.profile().unwrap().by_cycles(), [(0x3e, 1, 7), (0x3c, 1, 5)]);
+
+        assert!(matches!(invaders.run_to_breakpoint(Vec::new(), Vec::new(), 2), StopReason::InstructionCap));
+        assert_eq!(invaders.instructions, 4);
+        assert_eq!(invaders.profile().unwrap().by_cycles(), [(0xc3, 1, 10), (0x3e, 1, 7), (0x3c, 1, 5), (0x00, 1, 4)]);
+    }
+
+    #[test]
+    fn inr_m_writes_like_any_store() {
+        // LXI H, $2400; INR M; LXI H, $0000; INR M
+        let program = [0x21, 0x00, 0x24, 0x34, 0x21, 0x00, 0x00, 0x34];
+
+        // Video memory is watched, and the screen is rendered from it every frame
+        let mut invaders = machine_with(&program);
+        assert!(matches!(invaders.run_to_breakpoint(Vec::new(), vec![0x2400], 10), StopReason::Watchpoint(0x2400)));
+        assert!(is_lit(&invaders, 0, SpaceInvaders::SCREEN_HEIGHT - 1));
+
+        // ROM is left as is, or the write is an error in strict mode
+        invaders.run_instructions(2).unwrap();
+        assert_eq!(invaders.cpu().memory()[0x0000], 0x21);
+        let mut strict = machine_with(&program);
+        strict.set_strict(true);
+        strict.run_instructions(3).unwrap();
+        assert!(matches!(strict.run_instructions(1), Err(Error::RomWrite(0x0000))));
+        assert_eq!(strict.cpu().memory()[0x0000], 0x21);
+    }
+
+    /// Window failing to present its first `failures` frames
+    struct FlakyWindow {
+        failures: u32,
+        presented: u32,
+        recreated: u32,
+    }
+
+    impl FlakyWindow {
+        fn new(failures: u32) -> Self {
+            Self { failures, presented: 0, recreated: 0 }
+        }
+    }
+
+    impl Present for FlakyWindow {
+        fn present(&mut self, _buffer: &[u32]) -> Result<(), String> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("backend unavailable".into());
+            }
+            self.presented += 1;
+            Ok(())
+        }
+
+        fn recreate(&mut self) -> Result<(), String> {
+            self.recreated += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn presenting_recovers_from_transient_failures() {
+        let mut invaders = machine_with(&[0xc3, 0x00, 0x00]);
+
+        // The first present fails its retries and once more after recreating the window,
+        // the second gets through on its retry
+        let mut window = FlakyWindow::new(SpaceInvaders::PRESENT_RETRIES + 3);
+        invaders.step(&mut window).unwrap();
+        assert_eq!((window.presented, window.recreated), (1, 1));
+        assert_eq!(invaders.failed_presents, 0);
+
+        invaders.step(&mut window).unwrap();
+        assert_eq!((window.presented, window.recreated), (3, 1));
+
+        // Failures are forgotten by resets
+        let mut window = FlakyWindow::new(u32::MAX);
+        invaders.step(&mut window).unwrap();
+        assert_eq!(invaders.failed_presents, 2);
+        invaders.reset(ResetKind::Warm);
+        assert_eq!(invaders.failed_presents, 0);
+    }
+
+    #[test]
+    fn presenting_gives_up_on_windows_which_keep_failing() {
+        let mut invaders = machine_with(&[0xc3, 0x00, 0x00]);
+        let mut window = FlakyWindow::new(u32::MAX);
+
+        // Two presents per frame
+        let frames = SpaceInvaders::MAX_FAILED_PRESENTS / 2;
+        for _ in 1..frames {
+            invaders.step(&mut window).unwrap();
+        }
+        assert!(matches!(invaders.step(&mut window), Err(Error::Present(reason)) if reason == "backend unavailable"));
+        assert_eq!(window.recreated, SpaceInvaders::MAX_FAILED_PRESENTS);
+        assert_eq!(window.presented, 0);
+    }
+
+    #[test]
+    fn profiles_export_their_counts_as_csv() {
+        // LXI H, $2400; MOV M, A; INX H; JMP $0003, writing to $2400, $2401 and $2402
+        let mut invaders = machine_with(&[0x21, 0x00, 0x24, 0x77, 0x23, 0xc3, 0x03, 0x00]);
+        invaders.enable_profiling();
+        invaders.run_instructions(10).unwrap();
+
+        let csv = invaders.profile().unwrap().to_csv();
+        assert_eq!(
+            csv,
+            "section,key,count,cycles\n\
+             opcode,c3,3,30\n\
+             opcode,77,3,21\n\
+             opcode,23,3,15\n\
+             opcode,21,1,10\n\
+             category,branch,3,30\n\
+             category,transfer,4,31\n\
+             category,arithmetic,3,15\n\
+             memory,2400,3,\n"
+        );
+        // Every line has the 4 fields of the header
+        assert!(csv.lines().all(|line| line.split(',').count() == 4));
+    }
+
+    #[test]
+    fn clock_speeds_too_slow_for_the_interrupts_are_refused() {
+        let mut invaders = machine_with(&[0x00]);
+
+        // Below 60 Hz frames have no cycles, and 60 Hz leaves none for the middle of frame interrupt
+        for &hz in &[0, 59, 60, 119] {
+            assert!(matches!(invaders.set_clock_speed(hz), Err(Error::InvalidClockSpeed(refused)) if refused == hz));
+            assert_eq!(invaders.cycles_per_frame, SpaceInvaders::CYCLES_PER_FRAME);
+            assert_eq!(invaders.interrupt_schedule, SpaceInvaders::INTERRUPT_SCHEDULE);
+        }
+
+        invaders.set_clock_speed(120).unwrap();
+        assert_eq!(invaders.interrupt_schedule, [(1, 1), (2, 2)]);
+
+        // Stretching the schedule doesn't overflow
+        invaders.set_clock_speed(u64::MAX).unwrap();
+        let cycles_per_frame = u64::MAX / 60;
+        assert_eq!(invaders.cycles_per_frame, cycles_per_frame);
+        assert_eq!(invaders.interrupt_schedule, [(cycles_per_frame / 2, 1), (cycles_per_frame, 2)]);
+    }
+
+    #[test]
+    fn configs_built_with_a_custom_clock_and_switches_are_applied() {
+        let dip_switches = DipSwitches { ships: 5, early_bonus: true, ..DipSwitches::default() };
+        let config = Config { clock_speed: 2_000_000, dip_switches, ..Config::default() };
+
+        // IN 2
+        let mut invaders = machine_with(&[0xdb, 0x02]).with_config(config).unwrap();
+        assert_eq!(invaders.cycles_per_frame, 33_333);
+        assert_eq!(invaders.interrupt_schedule, [(16_666, 1), (33_333, 2)]);
+        assert_eq!(invaders.dip_switches(), dip_switches);
+        invaders.run_instructions(1).unwrap();
+        // Ships above 3 in bits 0 and 1, and the early bonus in bit 3
+        assert_eq!(invaders.cpu().a() & 0b0000_1011, 0b0000_1010);
+
+        assert!(matches!(machine_with(&[0x00]).with_config(Config { clock_speed: 30, ..Config::default() }), Err(Error::InvalidClockSpeed(30))));
+    }
+
+    #[test]
+    fn the_rendered_screen_lights_the_corners_set_in_video_memory() {
+        // Top-left: bit 7 of the last byte of the first line, $241f. Bottom-right: bit 0 of the first byte
+        // of the last line, $2400 + 223 * 32 = $3fe0. See `RotatedRenderer`.
+        let mut invaders = machine_with(&[0x00]);
+        invaders.cpu.memory_mut()[0x241f] = 0x80;
+        invaders.cpu.memory_mut()[0x3fe0] = 0x01;
+
+        let mut buffer = vec![0; SpaceInvaders::SCREEN_PIXELS];
+        invaders.render_into(&mut buffer).unwrap();
+        let lit = (0..buffer.len()).filter(|&index| buffer[index] != 0).collect::<Vec<_>>();
+        let (width, height) = (SpaceInvaders::SCREEN_WIDTH, SpaceInvaders::SCREEN_HEIGHT);
+        assert_eq!(lit, [0, width * height - 1]);
     }
 }
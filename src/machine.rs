@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+use crate::{error::Error, invaders::RunStats};
+
+/// Emulated machine, driven by a front-end
+pub trait Machine {
+    /// Real time a frame lasts on the original hardware, to pace the emulation
+    fn frame_duration(&self) -> Duration;
+
+    /// Runs `frames` frames without presenting them
+    fn run_frames(&mut self, frames: u64) -> Result<RunStats, Error>;
+
+    /// Width of the screen in pixels
+    fn width(&self) -> usize;
+
+    /// Height of the screen in pixels
+    fn height(&self) -> usize;
+
+    /// Copy of video memory as the game wrote it, before rotation or colors are applied
+    fn vram_bitmap(&self) -> Vec<u8>;
+
+    /// Renders the current screen into `buffer`, which must be `width() * height()` pixels
+    fn render_into(&self, buffer: &mut [u32]) -> Result<(), Error>;
+}
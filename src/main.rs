@@ -1,30 +1,952 @@
 #![deny(clippy::pedantic)]
 #![allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
 
-use invaders::SpaceInvaders;
+use std::{
+    env, fs,
+    ops::{Deref, DerefMut},
+    process,
+    time::Duration,
+};
 
+use attract::AutoStart;
+use clock::{FpsCounter, FrameLimiter, SystemClock};
+use cpu::{LogPolicy, PcWrapPolicy, ResetKind, StopReason};
+use error::Error;
+use input::{GameKey, JoypadState, OpposingInputs};
+use invaders::{first_difference, Config, DipSwitches, SpaceInvaders};
+use machine::Machine;
+use render::{ColorMask, MaskedRenderer, Present, RotatedRenderer};
+use sound::{Sample, SampleSet, Sound};
+
+mod asm;
+mod attract;
+#[cfg(feature = "audio")]
+mod audio;
+mod clock;
+mod cpm;
 mod cpu;
+mod error;
+mod input;
 mod invaders;
+mod machine;
+mod minimize;
+mod render;
+mod shift_hardware;
+mod simple_io;
+mod sound;
+mod terminal;
 mod flags;
+mod hex;
+
+/// Writes printed by `--write-log` and `--first-writes`
+const WRITE_LOG_SIZE: usize = 1000;
+
+/// Frames the displayed frame rate is averaged over, and refreshed every
+const FPS_FRAMES: u64 = 60;
+
+/// File used by the quick-save and quick-load hotkeys
+const STATE_PATH: &str = "rust-8080.state";
+
+/// Frames the control panel stays on screen after a setting changed, two seconds' worth
+const PANEL_FRAMES: u32 = 120;
 
+/// Keyboard key bound to each control
+const KEY_BINDINGS: [(GameKey, minifb::Key); 10] = [
+    (GameKey::Credit, minifb::Key::C),
+    (GameKey::P1Start, minifb::Key::Q),
+    (GameKey::P2Start, minifb::Key::W),
+    (GameKey::P1Fire, minifb::Key::Space),
+    (GameKey::P1Left, minifb::Key::A),
+    (GameKey::P1Right, minifb::Key::D),
+    (GameKey::P2Fire, minifb::Key::Enter),
+    (GameKey::P2Left, minifb::Key::Left),
+    (GameKey::P2Right, minifb::Key::Right),
+    (GameKey::Tilt, minifb::Key::T),
+];
+
+/// Where `--step-to` and `--watch-to` stop early
+#[derive(Debug, Clone, Copy)]
+enum StopAt {
+    /// PC reaching the address
+    Breakpoint(u16),
+    /// An instruction writing to the address
+    Watchpoint(u16),
+}
+
+/// Mode running without a window, which prints its results then exits
+enum Command {
+    TestRom(String),
+    FrameHashes(u64),
+    VramDump(u64, String),
+    CheckFrameHashes(String),
+    Step(Option<StopAt>, u64),
+    Profile(u64),
+    ProfileReport(u64, String),
+    Coverage(u64),
+    InterruptLog(u64),
+    WriteLog(u64, LogPolicy),
+    CheckAlignment(u64),
+    Minimize { input: String, output: String, instructions: u64 },
+    Assemble { input: String, output: String },
+    Listing { start: u16, end: u16, cycles: bool },
+    Controls,
+}
+
+#[allow(clippy::too_many_lines)] // One arm per option, then the window's key handling
 fn main() {
+    let mut args = env::args().skip(1);
+    let mut config = Config::default();
+    let mut terminal = false;
+    let mut auto_start = None;
+    let mut rom = None;
+    let mut overlay = None;
+    let mut scanlines = false;
+    let mut show_fps = false;
+    let mut patches = Vec::new();
+    let mut sample_set = SampleSet::new();
+    let mut help_screen = false;
+    let mut command = None;
+
+    if cfg!(feature = "cpu_compare") && !SpaceInvaders::COMPARES_TO_REFERENCE {
+        eprintln!("Warning: cpu_compare only runs in debug builds, this build doesn't compare to the reference CPU");
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Load a ROM file instead of the embedded one
+            "--rom" => rom = Some(args.next().expect("Missing ROM path")),
+            // Run a CP/M CPU test ROM, exiting with its result
+            "--test-rom" => command = Some(Command::TestRom(args.next().expect("Missing test ROM path"))),
+            // Print the screen hash of each of the first N frames
+            "--frame-hashes" => command = Some(Command::FrameHashes(frames(&mut args))),
+            // Run N frames and save video memory as an image
+            "--vram-dump" => {
+                let frames = frames(&mut args);
+                command = Some(Command::VramDump(frames, args.next().expect("Missing output path")));
+            }
+            // Compare the screen hash of each frame with a file printed by --frame-hashes
+            "--check-frame-hashes" => {
+                command = Some(Command::CheckFrameHashes(args.next().expect("Missing frame hashes path")));
+            }
+            // Run N instructions, or until reaching or writing to an address, and print the resulting state
+            "--step" | "--step-to" | "--watch-to" => {
+                let stop_at = if arg == "--step" {
+                    None
+                } else {
+                    let address = args.next()
+                                      .and_then(|address| u16::from_str_radix(&address, 16).ok())
+                                      .expect("Missing or invalid address");
+                    if arg == "--step-to" {
+                        Some(StopAt::Breakpoint(address))
+                    } else {
+                        Some(StopAt::Watchpoint(address))
+                    }
+                };
+                let instructions = args.next()
+                                       .and_then(|instructions| instructions.parse().ok())
+                                       .expect("Missing or invalid instruction count");
+                command = Some(Command::Step(stop_at, instructions));
+            }
+            // Run N frames and print the executions and cycles of each opcode
+            "--profile" => command = Some(Command::Profile(frames(&mut args))),
+            // Run N frames and save the profile of the opcodes and memory accesses as CSV
+            "--profile-report" => {
+                let frames = frames(&mut args);
+                command = Some(Command::ProfileReport(frames, args.next().expect("Missing output path")));
+            }
+            // Run N frames and print how many times each opcode was executed
+            "--coverage" => command = Some(Command::Coverage(frames(&mut args))),
+            // Run N frames and print each interrupt request
+            "--interrupt-log" => command = Some(Command::InterruptLog(frames(&mut args))),
+            // Run N frames and print the last writes to RAM
+            "--write-log" => command = Some(Command::WriteLog(frames(&mut args), LogPolicy::DropOldest)),
+            // Run N frames and print the first writes to RAM
+            "--first-writes" => command = Some(Command::WriteLog(frames(&mut args), LogPolicy::Stop)),
+            // Run N frames and report jumps inside instructions
+            "--check-alignment" => command = Some(Command::CheckAlignment(frames(&mut args))),
+            // Shrink a ROM to the bytes needed to reproduce its error
+            "--minimize" => {
+                let (input, output) = (
+                    args.next().expect("Missing ROM path"),
+                    args.next().expect("Missing output path"),
+                );
+                let instructions = args.next()
+                                       .and_then(|instructions| instructions.parse().ok())
+                                       .expect("Missing or invalid instruction count");
+                command = Some(Command::Minimize { input, output, instructions });
+            }
+            // Assemble source in the format of --listing into a binary image
+            "--assemble" => {
+                let (input, output) = (
+                    args.next().expect("Missing source path"),
+                    args.next().expect("Missing output path"),
+                );
+                command = Some(Command::Assemble { input, output });
+            }
+            // Print the instructions between two hex addresses as assembler source
+            "--listing" | "--cycle-listing" => {
+                let cycles = arg == "--cycle-listing";
+                let mut address = || {
+                    args.next()
+                        .and_then(|address| u16::from_str_radix(&address, 16).ok())
+                        .expect("Missing or invalid address")
+                };
+                let (start, end) = (address(), address());
+                command = Some(Command::Listing { start, end, cycles });
+            }
+            // List the controls and their keys
+            "--controls" => command = Some(Command::Controls),
+            // List the controls in the window before starting
+            "--help-screen" => help_screen = true,
+            // Present the window once per frame
+            "--interlaced" => config.interlaced = true,
+            // Fail on ROM writes and unknown ports
+            "--strict" => config.strict = true,
+            // Start a game after idling in attract mode for N seconds
+            "--auto-start" => {
+                let seconds = args.next()
+                                  .and_then(|seconds| seconds.parse().ok())
+                                  .expect("Missing or invalid idle timeout");
+                auto_start = Some(AutoStart::new(Duration::from_secs(seconds)));
+            }
+            // Color the screen with an overlay image
+            "--overlay" => {
+                let path = args.next().expect("Missing overlay path");
+                let mask = fs::read(&path).map_err(Error::from).and_then(|ppm| ColorMask::from_ppm(&ppm));
+                overlay = Some(mask.unwrap_or_else(|e| {
+                    eprintln!("Failed to load overlay {path}: {e}");
+                    process::exit(1);
+                }));
+            }
+            // Darken every other row
+            "--scanlines" => scanlines = true,
+            // Show the achieved frame rate in the title
+            "--fps" => show_fps = true,
+            // Replace a sound with a WAV file
+            "--sound" => {
+                let mapping = args.next().expect("Missing sound, expected <sound>=<path>");
+                let (sound, path) = parse_sound(&mapping).unwrap_or_else(|| {
+                    eprintln!("Invalid sound {mapping}, expected <sound>=<path>");
+                    process::exit(2);
+                });
+                // The built-in sound still plays if the file can't be used
+                match fs::read(path).map_err(Error::from).and_then(|wav| Sample::from_wav(&wav)) {
+                    Ok(sample) => sample_set.set(sound, sample),
+                    Err(e) => eprintln!("Failed to load sound {path}, using the built-in one: {e}"),
+                }
+            }
+            // Output silence instead of the sounds
+            "--no-audio" => config.audio_enabled = false,
+            // Reset the game when it stops writing to the watchdog port for N frames
+            "--watchdog" => {
+                let frames = args.next()
+                                 .and_then(|frames| frames.parse().ok())
+                                 .expect("Missing or invalid watchdog timeout");
+                config.watchdog = Some(frames);
+            }
+            // Take N more cycles to acknowledge interrupts
+            "--interrupt-latency" => {
+                config.interrupt_latency = args.next()
+                                               .and_then(|cycles| cycles.parse().ok())
+                                               .expect("Missing or invalid interrupt latency");
+            }
+            // Keep the credits through resets
+            "--keep-credits" => config.preserved_regions = vec![SpaceInvaders::CREDITS_REGION],
+            // Overwrite a byte of the ROM
+            "--patch" => {
+                let patch = args.next()
+                                .as_deref()
+                                .and_then(parse_patch)
+                                .expect("Missing or invalid patch, expected <address>=<byte> in hexadecimal");
+                patches.push(patch);
+            }
+            // Handle both directions of a player pressed at once
+            "--opposing-inputs" => {
+                config.opposing_inputs = match args.next().as_deref() {
+                    Some("allow") => OpposingInputs::Allow,
+                    Some("cancel") => OpposingInputs::Cancel,
+                    Some("latest") => OpposingInputs::Latest,
+                    _ => {
+                        eprintln!("Missing or invalid policy, expected allow, cancel or latest");
+                        process::exit(2);
+                    }
+                };
+            }
+            // Warn or stop when execution runs past $ffff
+            "--pc-wrap" => {
+                config.pc_wrap_policy = match args.next().as_deref() {
+                    Some("ignore") => PcWrapPolicy::Ignore,
+                    Some("warn") => PcWrapPolicy::Warn,
+                    Some("break") => PcWrapPolicy::Break,
+                    _ => {
+                        eprintln!("Missing or invalid policy, expected ignore, warn or break");
+                        process::exit(2);
+                    }
+                };
+            }
+            // Print the screen to the terminal instead of opening a window
+            "--terminal" => terminal = true,
+            _ => {
+                eprintln!("Unknown argument: {arg}");
+                process::exit(2);
+            }
+        }
+    }
+
+    // Options may come in any order, so commands only run once they are all parsed
+    if let Some(command) = command {
+        process::exit(run_command(command, rom.as_deref(), &patches));
+    }
+
     // Init machine
-    let mut invaders = SpaceInvaders::new();
+    #[cfg(feature = "audio")]
+    let audio_enabled = config.audio_enabled;
+    let mut invaders = match machine(rom.as_deref(), &patches).with_config(config) {
+        Ok(invaders) => invaders,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    invaders.set_sample_set(sample_set);
+    if scanlines {
+        invaders.set_effect(Some(render::scanlines));
+    }
+    if let Some(mask) = overlay {
+        invaders.set_renderer(Box::new(MaskedRenderer::new(RotatedRenderer, mask)), invaders.render_options());
+    }
+
+    if terminal {
+        if let Err(e) = terminal::run(&mut invaders) {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    }
 
     // Create window
-    let mut window = minifb::Window::new(
-        "rust-8080",
-        SpaceInvaders::SCREEN_WIDTH,
-        SpaceInvaders::SCREEN_HEIGHT,
-        minifb::WindowOptions {
-            borderless: false,
-            title: true,
-            resize: false,
-            scale: minifb::Scale::X2,
-        },
-    ).expect("Could not create window");
+    let mut window = GameWindow::open(invaders.width(), invaders.height()).expect("Could not create window");
+
+    // Without an audio device the game runs the same, silently
+    #[cfg(feature = "audio")]
+    let audio = if audio_enabled {
+        audio::AudioOutput::new().map_err(|e| eprintln!("{e}, playing without sound")).ok()
+    } else {
+        None
+    };
+    #[cfg(feature = "audio")]
+    let mut samples = Vec::new();
+
+    let mut paused = false;
+    let mut frame_limiter = FrameLimiter::new(SystemClock, invaders.frame_duration());
+    let mut fps_counter = FpsCounter::new(SystemClock, FPS_FRAMES as usize);
+    // Frames left showing the control panel, which is also shown while paused
+    let mut panel_frames = 0;
+    let mut buffer = vec![0; invaders.width() * invaders.height()];
+
+    if help_screen {
+        let mut buffer = vec![0; invaders.width() * invaders.height()];
+        invaders.render_text(&help_lines(SpaceInvaders::SUPPORTED_KEYS), &mut buffer)
+                .expect("Buffer is the size of the screen");
+
+        // Any key starts the game
+        while window.is_open() && window.get_keys().map_or(true, |keys| keys.is_empty()) {
+            window.update_with_buffer(&buffer)
+                  .unwrap_or_else(|e| eprintln!("Failed to update window buffer: {e}"));
+            frame_limiter.wait();
+        }
+    }
 
     while window.is_open() {
-        invaders.step(&mut window);
+        // Control panel
+        let mut dip_switches = invaders.dip_switches();
+        for &key in &[minifb::Key::F2, minifb::Key::F3] {
+            if window.is_key_pressed(key, minifb::KeyRepeat::No) {
+                dip_switches = toggle_setting(dip_switches, key);
+            }
+        }
+        if dip_switches != invaders.dip_switches() {
+            invaders.set_dip_switches(dip_switches);
+            panel_frames = PANEL_FRAMES;
+        }
+        if window.is_key_pressed(minifb::Key::F4, minifb::KeyRepeat::No) {
+            invaders.reset(ResetKind::Warm);
+            panel_frames = PANEL_FRAMES;
+        }
+        if window.is_key_pressed(minifb::Key::P, minifb::KeyRepeat::No) {
+            paused = !paused;
+            panel_frames = PANEL_FRAMES;
+        }
+
+        // Quick-save / quick-load
+        if window.is_key_pressed(minifb::Key::F5, minifb::KeyRepeat::No) {
+            invaders.save_state(STATE_PATH)
+                    .unwrap_or_else(|e| eprintln!("Failed to save state: {e}"));
+        }
+        if window.is_key_pressed(minifb::Key::F9, minifb::KeyRepeat::No) {
+            invaders.load_state(STATE_PATH)
+                    .unwrap_or_else(|e| eprintln!("Failed to load state: {e}"));
+        }
+
+        if !paused {
+            invaders.set_input(read_joypad(&window));
+            if let Some(auto_start) = &mut auto_start {
+                auto_start.update(&SystemClock, &mut invaders);
+            }
+        }
+
+        let result = if paused || panel_frames > 0 {
+            // The panel is drawn over the whole screen, so the frame is presented here instead of by `step`
+            let result = if paused { Ok(()) } else { invaders.run_frames(1).map(|_| ()) };
+            let panel = control_panel(invaders.dip_switches(), paused);
+            let result = result.and_then(|()| invaders.render_into(&mut buffer))
+                               .and_then(|()| invaders.render_overlay(&panel, &mut buffer));
+            window.update_with_buffer(&buffer)
+                  .unwrap_or_else(|e| eprintln!("Failed to update window buffer: {e}"));
+            panel_frames = panel_frames.saturating_sub(1);
+            result
+        } else {
+            invaders.step(&mut window)
+        };
+        if let Err(e) = result {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+
+        #[cfg(feature = "audio")]
+        if let (Some(audio), false) = (&audio, paused) {
+            samples.resize(audio.samples_per_frame(invaders.frame_duration()), 0.);
+            invaders.audio_samples(&mut samples, audio.sample_rate());
+            audio.queue(&samples);
+        }
+
+        frame_limiter.wait();
+
+        if show_fps && !paused {
+            fps_counter.tick();
+            if invaders.frame_count() % FPS_FRAMES == 0 {
+                window.set_title(&title(fps_counter.fps()));
+            }
+        }
+    }
+}
+
+/// Loads the ROM file at `rom`, or the embedded ROM, and applies `patches` to it
+fn machine(rom: Option<&str>, patches: &[(u16, u8)]) -> SpaceInvaders {
+    let invaders = match rom {
+        Some(path) => SpaceInvaders::from_rom_file(path),
+        #[cfg(feature = "default_rom")]
+        None => Ok(SpaceInvaders::new()),
+        #[cfg(not(feature = "default_rom"))]
+        None => {
+            eprintln!("No embedded ROM, use --rom <path>");
+            process::exit(2);
+        }
+    };
+
+    let mut invaders = invaders.unwrap_or_else(|e| {
+        eprintln!("Failed to load ROM: {e}");
+        process::exit(1);
+    });
+    if let Err(e) = invaders.apply_patches(patches) {
+        eprintln!("Failed to patch ROM: {e}");
+        process::exit(1);
+    }
+    invaders
+}
+
+/// Reads the frame count argument of a command
+fn frames(args: &mut impl Iterator<Item = String>) -> u64 {
+    args.next()
+        .and_then(|frames| frames.parse().ok())
+        .expect("Missing or invalid frame count")
+}
+
+/// Runs `command` on the ROM file at `rom`, or the embedded ROM, with `patches` applied.
+/// Returns the exit status.
+#[allow(clippy::too_many_lines)] // One arm per command
+fn run_command(command: Command, rom: Option<&str>, patches: &[(u16, u8)]) -> i32 {
+    match command {
+        Command::TestRom(path) => {
+            let passed = cpm::run_test_rom(&path).unwrap_or_else(|e| {
+                eprintln!("Failed to run test ROM {path}: {e}");
+                false
+            });
+            i32::from(!passed)
+        }
+        Command::FrameHashes(frames) => match machine(rom, patches).frame_hashes(frames) {
+            Ok(hashes) => {
+                for hash in hashes {
+                    println!("{hash:016x}");
+                }
+                0
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                1
+            }
+        },
+        Command::VramDump(frames, path) => {
+            let mut invaders = machine(rom, patches);
+            if let Err(e) = invaders.run_frames(frames) {
+                eprintln!("{e}");
+                return 1;
+            }
+            let pbm = render::bitmap_to_pbm(&invaders.vram_bitmap(), SpaceInvaders::VRAM_LINE_PIXELS);
+            if let Err(e) = fs::write(&path, pbm) {
+                eprintln!("Failed to write {path}: {e}");
+                return 1;
+            }
+            0
+        }
+        Command::CheckFrameHashes(path) => check_frame_hashes(&path, rom, patches),
+        Command::Step(stop_at, instructions) => {
+            print!("{}", step(&mut machine(rom, patches), stop_at, instructions));
+            0
+        }
+        Command::Profile(frames) => {
+            let mut invaders = machine(rom, patches);
+            invaders.enable_profiling();
+            if let Err(e) = invaders.run_frames(frames) {
+                eprintln!("{e}");
+            }
+
+            if let Some(profile) = invaders.profile() {
+                println!("opcode\tcount\tcycles");
+                for (op_code, count, cycles) in profile.by_cycles() {
+                    println!("{op_code:02x}\t{count}\t{cycles}");
+                }
+            }
+            0
+        }
+        Command::ProfileReport(frames, path) => {
+            let mut invaders = machine(rom, patches);
+            invaders.enable_profiling();
+            if let Err(e) = invaders.run_frames(frames) {
+                eprintln!("{e}");
+            }
+
+            let csv = invaders.profile().map(invaders::OpcodeProfile::to_csv).unwrap_or_default();
+            if let Err(e) = fs::write(&path, csv) {
+                eprintln!("Failed to write {path}: {e}");
+                return 1;
+            }
+            0
+        }
+        Command::Coverage(frames) => coverage(&mut machine(rom, patches), frames),
+        Command::InterruptLog(frames) => {
+            let mut invaders = machine(rom, patches);
+            invaders.set_interrupt_logging(true);
+            if let Err(e) = invaders.run_frames(frames) {
+                eprintln!("{e}");
+            }
+
+            println!("frame\tcycle\tpc\trst\toutcome");
+            for event in invaders.interrupt_log() {
+                println!(
+                    "{}\t{}\t{:04x}\t{}\t{:?}",
+                    event.frame, event.cycle, event.pc, event.interrupt_num, event.outcome
+                );
+            }
+            0
+        }
+        Command::WriteLog(frames, policy) => {
+            let mut invaders = machine(rom, patches);
+            invaders.set_write_log(Some((WRITE_LOG_SIZE, policy)));
+            if let Err(e) = invaders.run_frames(frames) {
+                eprintln!("{e}");
+            }
+
+            println!("pc\taddress\tvalue");
+            for write in invaders.cpu().write_log() {
+                println!("{:04x}\t{:04x}\t{:02x}", write.pc, write.address, write.value);
+            }
+            0
+        }
+        Command::CheckAlignment(frames) => {
+            let mut invaders = machine(rom, patches);
+            invaders.set_check_alignment(true);
+            if let Err(e) = invaders.run_frames(frames) {
+                eprintln!("{e}");
+            }
+
+            let misaligned = invaders.cpu().misaligned_instructions();
+            for address in misaligned {
+                println!("{address:04x}");
+            }
+            i32::from(!misaligned.is_empty())
+        }
+        Command::Minimize { input, output, instructions } => {
+            let rom = match fs::read(&input) {
+                Ok(rom) => rom,
+                Err(e) => {
+                    eprintln!("Failed to read ROM {input}: {e}");
+                    return 1;
+                }
+            };
+
+            if let Some(minimized) = minimize::minimize_failure(&rom, instructions) {
+                if let Err(e) = fs::write(&output, &minimized) {
+                    eprintln!("Failed to write {output}: {e}");
+                    return 1;
+                }
+                println!("Minimized {} bytes to {}", rom.len(), minimized.len());
+                0
+            } else {
+                eprintln!("The ROM runs {instructions} instructions without an error");
+                1
+            }
+        }
+        Command::Assemble { input, output } => {
+            let source = match fs::read_to_string(&input) {
+                Ok(source) => source,
+                Err(e) => {
+                    eprintln!("Failed to read source {input}: {e}");
+                    return 1;
+                }
+            };
+
+            match asm::assemble(&source) {
+                Ok((origin, bytes)) => {
+                    if let Err(e) = fs::write(&output, &bytes) {
+                        eprintln!("Failed to write {output}: {e}");
+                        return 1;
+                    }
+                    println!("Assembled {} bytes from ${:04x}", bytes.len(), origin);
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Failed to assemble {input}: {e}");
+                    1
+                }
+            }
+        }
+        Command::Listing { start, end, cycles } => {
+            print!("{}", machine(rom, patches).cpu().listing(start, end, cycles));
+            0
+        }
+        Command::Controls => {
+            for &key in SpaceInvaders::SUPPORTED_KEYS {
+                match binding(key) {
+                    Some(binding) => println!("{key:?}: {binding:?}"),
+                    None => println!("{key:?}: unbound"),
+                }
+            }
+            0
+        }
+    }
+}
+
+/// Runs `instructions` instructions, or until `stop_at`, then describes the final state:
+/// the registers, the top of the stack and the instructions from PC
+fn step(invaders: &mut SpaceInvaders, stop_at: Option<StopAt>, instructions: u64) -> String {
+    let points = match stop_at {
+        Some(StopAt::Breakpoint(address)) => Some((vec![address], Vec::new())),
+        Some(StopAt::Watchpoint(address)) => Some((Vec::new(), vec![address])),
+        None => None,
+    };
+    match points {
+        Some((breakpoints, watchpoints)) => match invaders.run_to_breakpoint(breakpoints, watchpoints, instructions) {
+            StopReason::Breakpoint(address) => eprintln!("Stopped at ${address:04x}"),
+            StopReason::Watchpoint(address) => eprintln!("Stopped after writing to ${address:04x}"),
+            StopReason::Halted => eprintln!("Stopped on HLT"),
+            StopReason::InstructionCap => eprintln!("Stopped after {instructions} instructions"),
+            StopReason::Error(e) => eprintln!("{e}"),
+        },
+        None => {
+            if let Err(e) = invaders.run_instructions(instructions) {
+                eprintln!("{e}");
+            }
+        }
+    }
+
+    let cpu = invaders.cpu();
+    let mut report = format!(
+        "{cpu:#?}\nInstructions: {}, cycles: {}\nStack: {:04x?}\n",
+        cpu.instruction_count(),
+        cpu.total_cycles(),
+        cpu.stack(4),
+    );
+    // Registers are only known for the next instruction, so only it is annotated
+    let accessed = cpu.effective_address(cpu.peek(cpu.pc()));
+    report.extend(cpu.instructions(cpu.pc(), cpu.pc().saturating_add(16)).map(|(address, name)| match accessed {
+        Some(accessed) if address == cpu.pc() => format!("{address:04x}\t{name}\t-> ${accessed:04x}\n"),
+        _ => format!("{address:04x}\t{name}\n"),
+    }));
+    report
+}
+
+/// Runs `frames` frames, then prints the opcodes executed with their counts. Returns the exit status.
+fn coverage(invaders: &mut SpaceInvaders, frames: u64) -> i32 {
+    invaders.enable_profiling();
+    if let Err(e) = invaders.run_frames(frames) {
+        eprintln!("{e}");
+        return 1;
+    }
+
+    let executed = invaders.profile()
+                           .map(invaders::OpcodeProfile::by_cycles)
+                           .unwrap_or_default()
+                           .into_iter()
+                           .map(|(op_code, count, _)| (op_code, count))
+                           .collect::<Vec<_>>();
+    print!("{}", coverage_report(executed));
+
+    0
+}
+
+/// Lists the `executed` opcodes with their counts, in opcode order
+fn coverage_report(mut executed: Vec<(u8, u64)>) -> String {
+    executed.sort_unstable();
+
+    let mut report = "opcode\tcount\n".to_string();
+    report.extend(executed.into_iter().map(|(op_code, count)| format!("{op_code:02x}\t{count}\n")));
+    report
+}
+
+/// Runs as many frames as there are hashes in the file at `path`, reporting the first
+/// frame whose hash differs. Returns the exit status.
+fn check_frame_hashes(path: &str, rom: Option<&str>, patches: &[(u16, u8)]) -> i32 {
+    let expected = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|hashes| {
+            hashes.lines()
+                  .map(|hash| u64::from_str_radix(hash.trim(), 16).map_err(|e| e.to_string()))
+                  .collect::<Result<Vec<_>, _>>()
+        });
+    let expected = match expected {
+        Ok(expected) => expected,
+        Err(e) => {
+            eprintln!("Failed to read frame hashes {path}: {e}");
+            return 2;
+        }
+    };
+
+    match machine(rom, patches).frame_hashes(expected.len() as u64) {
+        Ok(actual) => if let Some(frame) = first_difference(&expected, &actual) {
+            println!("Frame {frame} differs");
+            1
+        } else {
+            println!("All {} frames match", expected.len());
+            0
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            1
+        }
+    }
+}
+
+/// Parses a patch written `<address>=<byte>`, both in hexadecimal
+fn parse_patch(patch: &str) -> Option<(u16, u8)> {
+    let (address, byte) = patch.split_once('=')?;
+    Some((u16::from_str_radix(address, 16).ok()?, u8::from_str_radix(byte, 16).ok()?))
+}
+
+/// Parses a sound replacement written `<sound>=<path>`, with the names of `Sound::from_name`
+fn parse_sound(mapping: &str) -> Option<(Sound, &str)> {
+    let (name, path) = mapping.split_once('=')?;
+    Some((Sound::from_name(name)?, path))
+}
+
+/// Window of the game, which can be opened again if presenting to it keeps failing
+struct GameWindow {
+    window: minifb::Window,
+    width: usize,
+    height: usize,
+}
+
+impl GameWindow {
+    fn open(width: usize, height: usize) -> Result<Self, minifb::Error> {
+        Ok(Self {
+            window: Self::create(width, height)?,
+            width,
+            height,
+        })
+    }
+
+    fn create(width: usize, height: usize) -> Result<minifb::Window, minifb::Error> {
+        minifb::Window::new(
+            "rust-8080",
+            width,
+            height,
+            minifb::WindowOptions {
+                borderless: false,
+                title: true,
+                resize: false,
+                scale: minifb::Scale::X2,
+            },
+        )
+    }
+}
+
+impl Deref for GameWindow {
+    type Target = minifb::Window;
+
+    fn deref(&self) -> &minifb::Window {
+        &self.window
+    }
+}
+
+impl DerefMut for GameWindow {
+    fn deref_mut(&mut self) -> &mut minifb::Window {
+        &mut self.window
+    }
+}
+
+impl Present for GameWindow {
+    fn present(&mut self, buffer: &[u32]) -> Result<(), String> {
+        self.window.update_with_buffer(buffer).map_err(|e| e.to_string())
+    }
+
+    fn recreate(&mut self) -> Result<(), String> {
+        self.window = Self::create(self.width, self.height).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Window title showing the achieved frame rate
+fn title(fps: Option<f64>) -> String {
+    fps.map_or_else(|| "rust-8080".to_string(), |fps| format!("rust-8080 - {fps:.1} fps"))
+}
+
+/// Changes the setting of the control panel bound to `key`: F2 cycles the ships from 3 to 6,
+/// F3 toggles the extra ship between 1000 and 1500 points. Other keys change nothing.
+fn toggle_setting(mut dip_switches: DipSwitches, key: minifb::Key) -> DipSwitches {
+    match key {
+        minifb::Key::F2 => dip_switches.ships = if dip_switches.ships >= 6 { 3 } else { dip_switches.ships + 1 },
+        minifb::Key::F3 => dip_switches.early_bonus = !dip_switches.early_bonus,
+        _ => {}
+    }
+    dip_switches
+}
+
+/// Text of the control panel, with the current settings and the key changing each
+fn control_panel(dip_switches: DipSwitches, paused: bool) -> Vec<String> {
+    vec![
+        format!(" F2 SHIPS = {}", dip_switches.ships),
+        format!(" F3 BONUS = {}", if dip_switches.early_bonus { 1000 } else { 1500 }),
+        " F4 RESET".to_string(),
+        if paused { " P  PAUSED".to_string() } else { " P  PAUSE".to_string() },
+    ]
+}
+
+/// Keyboard key bound to a control
+fn binding(key: GameKey) -> Option<minifb::Key> {
+    KEY_BINDINGS.iter()
+                .find(|&&(bound_key, _)| bound_key == key)
+                .map(|&(_, binding)| binding)
+}
+
+/// Text of the help screen, with the key bound to each control
+fn help_lines(keys: &[GameKey]) -> Vec<String> {
+    let mut lines = vec![String::new(), " CONTROLS".to_string(), String::new()];
+
+    for &key in keys {
+        let binding = binding(key).map_or_else(|| "UNBOUND".to_string(), |binding| format!("{binding:?}"));
+        lines.push(format!(" {key:?} = {binding}"));
+    }
+    lines.push(String::new());
+    lines.push(" PRESS ANY KEY".to_string());
+
+    lines
+}
+
+/// Reads the state of the controls from the keyboard
+fn read_joypad(window: &minifb::Window) -> JoypadState {
+    let mut joypad = JoypadState::new();
+
+    for &(key, binding) in &KEY_BINDINGS {
+        joypad.set(key, window.is_key_down(binding));
+    }
+
+    joypad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ships_key_cycles_the_ships_and_updates_the_panel() {
+        let mut dip_switches = DipSwitches::default();
+        assert_eq!(control_panel(dip_switches, false)[0], " F2 SHIPS = 3");
+
+        dip_switches = toggle_setting(dip_switches, minifb::Key::F2);
+        assert_eq!(dip_switches.ships, 4);
+        assert_eq!(control_panel(dip_switches, false)[0], " F2 SHIPS = 4");
+
+        for _ in 0..3 {
+            dip_switches = toggle_setting(dip_switches, minifb::Key::F2);
+        }
+        assert_eq!(dip_switches.ships, 3);
+        assert!(!dip_switches.early_bonus);
+
+        dip_switches = toggle_setting(dip_switches, minifb::Key::F3);
+        assert_eq!(control_panel(dip_switches, true)[1..], [" F3 BONUS = 1000", " F4 RESET", " P  PAUSED"]);
+    }
+
+    #[test]
+    fn step_mode_reports_the_final_state() {
+        let path = env::temp_dir().join("rust-8080-test-step.rom");
+        // MVI A, $41; INR A; NOP
+        std::fs::write(&path, [0x3e, 0x41, 0x3c, 0x00]).unwrap();
+        let mut invaders = machine(path.to_str(), &[]);
+
+        let report = step(&mut invaders, None, 2);
+        assert!(report.contains("a: 42"), "{}", report);
+        assert!(report.contains("pc: 0003"), "{}", report);
+        assert!(report.contains("Instructions: 2, cycles: 12\n"), "{}", report);
+        assert!(report.contains("0003\tNOP"), "{}", report);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn headless_modes_run_the_patched_rom() {
+        let path = env::temp_dir().join("rust-8080-test-patch.rom");
+        // MVI A, $41; INR A; NOP, with INR A patched to DCR A
+        std::fs::write(&path, [0x3e, 0x41, 0x3c, 0x00]).unwrap();
+        let mut invaders = machine(path.to_str(), &[(0x0002, 0x3d)]);
+
+        assert_eq!(invaders.cpu().instructions(0x0002, 0x0003).next(), Some((0x0002, "DCR A".to_string())));
+        let report = step(&mut invaders, None, 2);
+        assert!(report.contains("a: 40"), "{}", report);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn help_screen_lists_the_key_of_each_control() {
+        let keys = [GameKey::Credit, GameKey::P1Fire, GameKey::Tilt];
+        let lines = help_lines(&keys);
+
+        for &key in &keys {
+            let expected = match binding(key) {
+                Some(binding) => format!(" {key:?} = {binding:?}"),
+                None => format!(" {key:?} = UNBOUND"),
+            };
+            assert!(lines.contains(&expected), "{} missing from {:?}", expected, lines);
+        }
+        for &(key, binding) in &KEY_BINDINGS {
+            let lines = help_lines(&[key]);
+            assert!(lines.contains(&format!(" {key:?} = {binding:?}")));
+        }
+        assert_eq!(lines.last().map(String::as_str), Some(" PRESS ANY KEY"));
+    }
+
+    #[test]
+    fn coverage_lists_the_executed_opcodes_in_order() {
+        // NOP; INR A; JMP $0000
+        let mut invaders = SpaceInvaders::from_rom([0x00, 0x3c, 0xc3, 0x00, 0x00]).unwrap();
+        invaders.enable_profiling();
+        invaders.run_instructions(6).unwrap();
+        let executed = invaders.profile()
+                               .unwrap()
+                               .by_cycles()
+                               .into_iter()
+                               .map(|(op_code, count, _)| (op_code, count))
+                               .collect::<Vec<_>>();
+
+        assert_eq!(coverage_report(executed), "opcode\tcount\n00\t2\n3c\t2\nc3\t2\n");
     }
 }
@@ -0,0 +1,89 @@
+use std::mem;
+
+use crate::{cpu::{CpuState, PcWrapPolicy}, error::Error, simple_io::SimpleIO};
+
+/// Runs `rom` from address 0 without interrupts, for at most `max_instructions` instructions,
+/// returning the error it stopped on if any. Writes to ROM and running past $ffff are errors.
+pub fn run_bounded(rom: &[u8], max_instructions: u64) -> Result<(), Error> {
+    let mut cpu = CpuState::from_rom(rom, 0, 0)?;
+    cpu.set_strict(true);
+    cpu.set_pc_wrap_policy(PcWrapPolicy::Break);
+    let mut io_state = SimpleIO::new();
+
+    for _ in 0..max_instructions {
+        cpu.emulate(&mut io_state)?;
+    }
+
+    Ok(())
+}
+
+/// Shrinks a ROM on which `reproduces` returns true, such as a fuzzer finding, by zeroing
+/// ever smaller blocks of it as long as it still reproduces, then dropping the trailing zeros.
+/// Zeros are NOPs, so the remaining bytes are the ones the problem depends on.
+pub fn minimize(rom: &[u8], mut reproduces: impl FnMut(&[u8]) -> bool) -> Vec<u8> {
+    let mut rom = rom.to_vec();
+    let mut block = (rom.len() / 2).max(1);
+
+    loop {
+        let mut start = 0;
+        while start < rom.len() {
+            let end = (start + block).min(rom.len());
+
+            if rom[start..end].iter().any(|&byte| byte != 0) {
+                let mut candidate = rom.clone();
+                candidate[start..end].iter_mut().for_each(|byte| *byte = 0);
+                if reproduces(&candidate) {
+                    rom = candidate;
+                }
+            }
+
+            start = end;
+        }
+
+        if block == 1 {
+            break;
+        }
+        block /= 2;
+    }
+
+    let mut length = rom.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+    // Memory past the ROM is zeros too, but it may be what the ROM needs to be long enough
+    if !reproduces(&rom[..length]) {
+        length = rom.len();
+    }
+    rom.truncate(length);
+
+    rom
+}
+
+/// Minimizes `rom` so that `run_bounded` still fails with the same kind of error,
+/// or returns `None` if it doesn't fail in the first place
+pub fn minimize_failure(rom: &[u8], max_instructions: u64) -> Option<Vec<u8>> {
+    let error = run_bounded(rom, max_instructions).err()?;
+    let kind = mem::discriminant(&error);
+
+    Some(minimize(rom, |candidate| {
+        run_bounded(candidate, max_instructions).err()
+                                                .map_or(false, |e| mem::discriminant(&e) == kind)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_roms_shrink_to_the_failing_instruction() {
+        // MVI B, $01; INR C; MVI D, $02; MVI A, $ff; NOP; STA $0100, writing to ROM; INR E; MOV A, B; JMP $0000
+        let rom = [0x06, 0x01, 0x0c, 0x16, 0x02, 0x3e, 0xff, 0x00, 0x32, 0x00, 0x01, 0x1c, 0x78, 0xc3, 0x00, 0x00];
+        assert!(matches!(run_bounded(&rom, 100), Err(Error::RomWrite(0x0100))));
+
+        // Only the STA is left, writing to $0000 with the zeros past the end
+        let minimized = minimize_failure(&rom, 100).unwrap();
+        assert_eq!(minimized, [0, 0, 0, 0, 0, 0, 0, 0, 0x32]);
+        assert!(matches!(run_bounded(&minimized, 100), Err(Error::RomWrite(0x0000))));
+
+        // ROMs which run fine have nothing to minimize
+        assert_eq!(minimize_failure(&[0xc3, 0x00, 0x00], 100), None);
+    }
+}
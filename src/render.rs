@@ -0,0 +1,252 @@
+use crate::error::Error;
+
+/// Options shared by renderers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Width of the output in pixels
+    pub width: usize,
+    /// Height of the output in pixels
+    pub height: usize,
+    /// Color of lit pixels
+    pub foreground: u32,
+    /// Color of unlit pixels
+    pub background: u32,
+}
+
+/// Converts video memory to pixels
+pub trait Renderer {
+    /// Renders the bytes of video memory `vram`, which start `offset` bytes into video memory,
+    /// into the pixels of `out`
+    fn render(&self, vram: &[u8], offset: usize, out: &mut [u32], options: &RenderOptions);
+}
+
+/// Window frames are presented to, replaceable so presenting can be tested without a display
+pub trait Present {
+    /// Shows a rendered frame
+    fn present(&mut self, buffer: &[u32]) -> Result<(), String>;
+    /// Opens the window again, after presenting to it kept failing
+    fn recreate(&mut self) -> Result<(), String>;
+}
+
+/// Post-processing effect, returning the new color of the pixel at `x`, `y` from its rendered color
+pub type PixelEffect = fn(x: usize, y: usize, color: u32) -> u32;
+
+/// Darkens every other row, like the scanlines of a CRT
+pub fn scanlines(_x: usize, y: usize, color: u32) -> u32 {
+    if y % 2 == 0 {
+        color
+    } else {
+        // Halve each channel
+        color >> 1 & 0x7f_7f_7f
+    }
+}
+
+/// One bit per pixel, with each byte holding 8 vertical pixels going up,
+/// rotated like the monitor of the upright Space Invaders cabinet.
+/// Each line of video memory is a column of the screen, drawn bottom to top, the first one on the left.
+/// At 224x256, bit 0 of byte $0000 is the bottom-left corner, bit 7 of byte $001f the top-left one,
+/// bit 0 of byte $1be0 the bottom-right one and bit 7 of byte $1bff the top-right one.
+pub struct RotatedRenderer;
+
+impl Renderer for RotatedRenderer {
+    fn render(&self, vram: &[u8], offset: usize, out: &mut [u32], options: &RenderOptions) {
+        for (index, &byte) in vram.iter().enumerate() {
+            for bit in 0..8 {
+                let color = if byte & (1 << bit) == 0 {
+                    options.background
+                } else {
+                    options.foreground
+                };
+
+                let pixel = 8 * (offset + index) + bit;
+                let x = pixel / options.height;
+                let y = options.height - 1 - pixel % options.height;
+
+                out[x + y * options.width] = color;
+            }
+        }
+    }
+}
+
+/// Encodes a 1 bit per pixel bitmap, such as raw video memory, as a binary PBM (P4) image,
+/// with lit pixels in black. `bitmap` holds lines of `width` pixels, lowest bit first,
+/// and `width` must be a multiple of 8.
+pub fn bitmap_to_pbm(bitmap: &[u8], width: usize) -> Vec<u8> {
+    assert_eq!(width % 8, 0, "bitmap width must be a multiple of 8");
+    let height = bitmap.len() * 8 / width;
+
+    let mut pbm = format!("P4\n{width} {height}\n").into_bytes();
+    // PBM pixels go from the highest bit of each byte
+    pbm.extend(bitmap.iter().map(|byte| byte.reverse_bits()));
+    pbm
+}
+
+/// Colors of the transparent overlay stuck on the monitor of the cabinet,
+/// one per screen coordinate, stretched to the screen size if smaller
+pub struct ColorMask {
+    width: usize,
+    height: usize,
+    colors: Vec<u32>,
+}
+
+impl ColorMask {
+    /// Reads a binary PPM (P6) image with 8-bit channels
+    pub fn from_ppm(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidImage(reason.into());
+
+        // Header: magic number, width, height and maximum value, separated by whitespace or comments
+        let mut fields = Vec::with_capacity(4);
+        let mut position = 0;
+        while fields.len() < 4 {
+            match bytes.get(position) {
+                Some(b'#') => {
+                    while bytes.get(position).map_or(false, |&byte| byte != b'\n') {
+                        position += 1;
+                    }
+                }
+                Some(byte) if byte.is_ascii_whitespace() => position += 1,
+                Some(_) => {
+                    let start = position;
+                    while bytes.get(position).map_or(false, |byte| !byte.is_ascii_whitespace()) {
+                        position += 1;
+                    }
+                    fields.push(String::from_utf8_lossy(&bytes[start..position]).into_owned());
+                }
+                None => return Err(invalid("truncated header")),
+            }
+        }
+        // A single whitespace character separates the header from the pixels
+        position += 1;
+
+        if fields[0] != "P6" {
+            return Err(invalid("not a binary PPM image"));
+        }
+        let parse = |field: &str| field.parse::<usize>().map_err(|_| invalid("invalid header"));
+        let (width, height) = (parse(&fields[1])?, parse(&fields[2])?);
+        if width == 0 || height == 0 {
+            return Err(invalid("empty image"));
+        }
+        if parse(&fields[3])? != 255 {
+            return Err(invalid("only 8-bit channels are supported"));
+        }
+
+        let pixels = bytes.get(position..position + 3 * width * height)
+                          .ok_or_else(|| invalid("truncated pixels"))?;
+        let colors = pixels.chunks(3)
+                           .map(|rgb| u32::from(rgb[0]) << 16 | u32::from(rgb[1]) << 8 | u32::from(rgb[2]))
+                           .collect();
+
+        Ok(Self { width, height, colors })
+    }
+
+    /// Color of the overlay at the screen pixel `x`, `y`
+    fn color(&self, x: usize, y: usize, options: &RenderOptions) -> u32 {
+        let mask_x = x * self.width / options.width;
+        let mask_y = y * self.height / options.height;
+        self.colors[mask_x + mask_y * self.width]
+    }
+}
+
+/// Renders with another renderer, then colors lit pixels with a `ColorMask`
+pub struct MaskedRenderer<R> {
+    renderer: R,
+    mask: ColorMask,
+}
+
+impl<R: Renderer> MaskedRenderer<R> {
+    pub fn new(renderer: R, mask: ColorMask) -> Self {
+        Self { renderer, mask }
+    }
+}
+
+impl<R: Renderer> Renderer for MaskedRenderer<R> {
+    fn render(&self, vram: &[u8], offset: usize, out: &mut [u32], options: &RenderOptions) {
+        self.renderer.render(vram, offset, out, options);
+
+        for (index, pixel) in out.iter_mut().enumerate() {
+            if *pixel == options.foreground {
+                *pixel = self.mask.color(index % options.width, index / options.width, options);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: RenderOptions = RenderOptions {
+        width: 224,
+        height: 256,
+        foreground: 0xff_ff_ff,
+        background: 0x00_00_00,
+    };
+
+    /// Pixels lit by rendering `vram`, `offset` bytes into video memory, as `(x, y)`
+    fn lit_pixels(renderer: &impl Renderer, vram: &[u8], offset: usize) -> Vec<(usize, usize)> {
+        let mut out = vec![OPTIONS.background; OPTIONS.width * OPTIONS.height];
+        renderer.render(vram, offset, &mut out, &OPTIONS);
+
+        (0..out.len()).filter(|&index| out[index] == OPTIONS.foreground)
+                      .map(|index| (index % OPTIONS.width, index / OPTIONS.width))
+                      .collect()
+    }
+
+    #[test]
+    fn rotated_renderer_draws_a_single_byte_as_a_column() {
+        assert_eq!(lit_pixels(&RotatedRenderer, &[0x01], 0), vec![(0, 255)]);
+        // Bit 7 is the highest of the 8 pixels
+        assert_eq!(lit_pixels(&RotatedRenderer, &[0x80], 0), vec![(0, 248)]);
+        // Rendering part of video memory starts at its offset
+        assert_eq!(lit_pixels(&RotatedRenderer, &[0x80], 0x1f), vec![(0, 0)]);
+        assert_eq!(lit_pixels(&RotatedRenderer, &[0x00, 0x01], 0x20), vec![(1, 247)]);
+    }
+
+    #[test]
+    fn masks_color_the_lit_pixels_of_their_region() {
+        // 2x2 mask, stretched to quarters of the screen: red, green on top, blue, white at the bottom
+        let mut ppm = b"P6\n# overlay\n2 2\n255\n".to_vec();
+        ppm.extend_from_slice(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255]);
+        let renderer = MaskedRenderer::new(RotatedRenderer, ColorMask::from_ppm(&ppm).unwrap());
+
+        let mut out = vec![OPTIONS.background; OPTIONS.width * OPTIONS.height];
+        // Bit 0 of $0000 is the bottom-left pixel, bit 7 of $1bff the top-right one
+        renderer.render(&[0x01], 0, &mut out, &OPTIONS);
+        renderer.render(&[0x80], 0x1bff, &mut out, &OPTIONS);
+        renderer.render(&[0x80], 0x001f, &mut out, &OPTIONS);
+
+        assert_eq!(out[(OPTIONS.height - 1) * OPTIONS.width], 0x00_00_ff);
+        assert_eq!(out[OPTIONS.width - 1], 0x00_ff_00);
+        assert_eq!(out[0], 0xff_00_00);
+        // Unlit pixels aren't colored
+        assert_eq!(out.iter().filter(|&&pixel| pixel != OPTIONS.background).count(), 3);
+
+        assert!(matches!(ColorMask::from_ppm(b"P6\n2 2\n255\n\0\0\0"), Err(Error::InvalidImage(_))));
+        assert!(matches!(ColorMask::from_ppm(b"P3\n1 1\n255\n0 0 0"), Err(Error::InvalidImage(_))));
+    }
+
+    #[test]
+    fn corner_bits_of_video_memory_light_the_corners_of_the_screen() {
+        // Video memory holds 224 lines of 32 bytes. Line n is column x = n of the screen, drawn from the
+        // bottom: bit b of byte k of the line is y = 255 - (8k + b). So byte $1f of line 0 holds the top-left
+        // corner in bit 7, and byte 0 of line 223, $1be0, the bottom-right corner in bit 0.
+        let corners = [
+            (0x0000, 0x01, (0, 255)),
+            (0x001f, 0x80, (0, 0)),
+            (0x1be0, 0x01, (223, 255)),
+            (0x1bff, 0x80, (223, 0)),
+        ];
+
+        for &(address, bit, pixel) in &corners {
+            let mut vram = vec![0; 0x1c00];
+            vram[address] = bit;
+            assert_eq!(lit_pixels(&RotatedRenderer, &vram, 0), vec![pixel], "bit {bit:02x} of ${address:04x}");
+        }
+
+        // Both at once, with nothing in between
+        let mut vram = vec![0; 0x1c00];
+        vram[0x001f] = 0x80;
+        vram[0x1be0] = 0x01;
+        assert_eq!(lit_pixels(&RotatedRenderer, &vram, 0), vec![(0, 0), (223, 255)]);
+    }
+}
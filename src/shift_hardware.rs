@@ -0,0 +1,106 @@
+use crate::cpu::RegisterPair;
+
+/// Midway shift hardware, the 16-bit shift register of Midway's 8080 boards, which
+/// shifts bytes in a single `IN` instead of a loop of rotations
+pub struct ShiftRegister {
+    /// Bytes written, the last one at the top
+    register: RegisterPair,
+    offset: u8,
+}
+
+impl ShiftRegister {
+    /// The hardware only decodes the 3 low bits of the offset, so offsets are 0 to 7
+    const OFFSET_MASK: u8 = 0b111;
+    /// Size of a serialized state: register, then offset
+    pub const STATE_SIZE: usize = 3;
+
+    pub fn new() -> Self {
+        Self {
+            register: RegisterPair::new(),
+            offset: 0,
+        }
+    }
+
+    /// Sets how many bits below the top `read` starts, written by `OUT 2` on Space Invaders
+    pub fn set_offset(&mut self, offset: u8) {
+        self.offset = offset & Self::OFFSET_MASK;
+    }
+
+    /// Shifts a byte in from the top, the previous top byte moving to the bottom,
+    /// written by `OUT 4` on Space Invaders
+    pub fn write_data(&mut self, value: u8) {
+        *self.register.lsb_mut() = self.register.msb();
+        *self.register.msb_mut() = value;
+    }
+
+    /// The 8 bits of the register starting `offset` bits below the top, i.e. bits `15 - offset`
+    /// down to `8 - offset`, read by `IN 3` on Space Invaders. An offset of 0 returns the byte
+    /// last written, an offset of 7 returns its lowest bit followed by the top 7 bits of the
+    /// byte written before it.
+    pub fn read(&self) -> u8 {
+        (self.register.both() >> (8 - self.offset)) as u8
+    }
+
+    /// Register bytes, lowest first, then offset
+    pub fn save_state(&self) -> [u8; Self::STATE_SIZE] {
+        let [lsb, msb] = self.register.bytes();
+        [lsb, msb, self.offset]
+    }
+
+    pub fn load_state(&mut self, [lsb, msb, offset]: [u8; Self::STATE_SIZE]) {
+        self.register = RegisterPair::from_bytes([lsb, msb]);
+        self.set_offset(offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Register holding $a5 written after $3c
+    fn register_a53c() -> ShiftRegister {
+        let mut register = ShiftRegister::new();
+        register.write_data(0x3c);
+        register.write_data(0xa5);
+        register
+    }
+
+    #[test]
+    fn each_offset_reads_its_own_bits() {
+        // $a53c = 1010_0101_0011_1100, read 8 bits from bit 15 - offset
+        let expected = [0xa5, 0x4a, 0x94, 0x29, 0x53, 0xa7, 0x4f, 0x9e];
+        let mut register = register_a53c();
+
+        for (offset, &value) in expected.iter().enumerate() {
+            register.set_offset(offset as u8);
+            assert_eq!(register.read(), value, "offset {offset}");
+        }
+
+        // Only the 3 low bits are decoded
+        register.set_offset(8);
+        assert_eq!(register.read(), 0xa5);
+        register.set_offset(0xff);
+        assert_eq!(register.read(), 0x9e);
+    }
+
+    #[test]
+    fn writes_in_sequence_shift_the_oldest_byte_out() {
+        let mut register = ShiftRegister::new();
+        register.set_offset(4);
+        assert_eq!(register.read(), 0x00);
+
+        // Offset 4 reads the low nibble of the last byte, then the high nibble of the one before
+        let writes = [(0x12, 0x20), (0x34, 0x41), (0x56, 0x63), (0x78, 0x85)];
+        for &(value, expected) in &writes {
+            register.write_data(value);
+            assert_eq!(register.read(), expected, "after writing {value:02x}");
+        }
+        assert_eq!(register.save_state(), [0x56, 0x78, 4]);
+
+        // The offset applies to bytes already written
+        register.set_offset(0);
+        assert_eq!(register.read(), 0x78);
+        register.set_offset(7);
+        assert_eq!(register.read(), 0x2b);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{error::Error, invaders::IOState};
+
+/// IO with no hardware behind it: inputs return values set beforehand,
+/// and outputs are recorded in the order they were written
+pub struct SimpleIO {
+    inputs: [u8; 256],
+    outputs: Vec<(u8, u8)>,
+}
+
+impl SimpleIO {
+    /// All ports read 0 until set
+    pub fn new() -> Self {
+        Self {
+            inputs: [0; 256],
+            outputs: Vec::new(),
+        }
+    }
+}
+
+/// Scripting and inspection, for tests
+#[cfg(test)]
+impl SimpleIO {
+    /// Sets the value returned by `IN port`
+    pub fn set_input(&mut self, port: u8, value: u8) {
+        self.inputs[port as usize] = value;
+    }
+
+    /// Every `(port, value)` written by `OUT`, oldest first
+    pub fn outputs(&self) -> &[(u8, u8)] {
+        &self.outputs
+    }
+
+    /// Last value written to `port`, if any
+    pub fn last_output(&self, port: u8) -> Option<u8> {
+        self.outputs.iter().rev().find(|&&(p, _)| p == port).map(|&(_, value)| value)
+    }
+
+    pub fn clear_outputs(&mut self) {
+        self.outputs.clear();
+    }
+}
+
+impl IOState for SimpleIO {
+    fn input(&self, port: u8) -> Result<u8, Error> {
+        Ok(self.inputs[port as usize])
+    }
+
+    fn output(&mut self, port: u8, value: u8) -> Result<(), Error> {
+        self.outputs.push((port, value));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CpuState;
+
+    #[test]
+    fn outputs_are_recorded_and_inputs_read_back() {
+        // MVI A, $05; OUT $02; IN $01; OUT $03
+        let mut cpu = CpuState::from_rom([0x3e, 0x05, 0xd3, 0x02, 0xdb, 0x01, 0xd3, 0x03], 0, 0).unwrap();
+        let mut io_state = SimpleIO::new();
+        io_state.set_input(1, 0x42);
+
+        for _ in 0..4 {
+            cpu.emulate(&mut io_state).unwrap();
+        }
+
+        assert_eq!(io_state.outputs(), &[(0x02, 0x05), (0x03, 0x42)]);
+        assert_eq!(io_state.last_output(0x02), Some(0x05));
+        assert_eq!(io_state.last_output(0x04), None);
+        assert_eq!(cpu.registers().a, 0x42);
+
+        io_state.clear_outputs();
+        assert!(io_state.outputs().is_empty());
+    }
+}
@@ -0,0 +1,443 @@
+use crate::error::Error;
+
+/// Sound effects of Space Invaders, triggered by setting bits of output ports 3 and 5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sound {
+    Ufo,
+    Shot,
+    PlayerDeath,
+    InvaderDeath,
+    ExtraLife,
+    Fleet1,
+    Fleet2,
+    Fleet3,
+    Fleet4,
+    UfoHit,
+}
+
+impl Sound {
+    /// Every sound, in the order sounds starting on the same write are queued:
+    /// port 3 then port 5, lowest bit first
+    const ALL: [Sound; 10] = [
+        Sound::Ufo,
+        Sound::Shot,
+        Sound::PlayerDeath,
+        Sound::InvaderDeath,
+        Sound::ExtraLife,
+        Sound::Fleet1,
+        Sound::Fleet2,
+        Sound::Fleet3,
+        Sound::Fleet4,
+        Sound::UfoHit,
+    ];
+
+    /// Sound called `name`, in lower case with dashes, e.g. `player-death` or `fleet1`
+    pub fn from_name(name: &str) -> Option<Self> {
+        let sound = match name {
+            "ufo" => Sound::Ufo,
+            "shot" => Sound::Shot,
+            "player-death" => Sound::PlayerDeath,
+            "invader-death" => Sound::InvaderDeath,
+            "extra-life" => Sound::ExtraLife,
+            "fleet1" => Sound::Fleet1,
+            "fleet2" => Sound::Fleet2,
+            "fleet3" => Sound::Fleet3,
+            "fleet4" => Sound::Fleet4,
+            "ufo-hit" => Sound::UfoHit,
+            _ => return None,
+        };
+        Some(sound)
+    }
+
+    /// Port and bit controlling the sound
+    fn port_bit(self) -> (u8, u8) {
+        match self {
+            Sound::Ufo => (3, 0),
+            Sound::Shot => (3, 1),
+            Sound::PlayerDeath => (3, 2),
+            Sound::InvaderDeath => (3, 3),
+            Sound::ExtraLife => (3, 4),
+            Sound::Fleet1 => (5, 0),
+            Sound::Fleet2 => (5, 1),
+            Sound::Fleet3 => (5, 2),
+            Sound::Fleet4 => (5, 3),
+            Sound::UfoHit => (5, 4),
+        }
+    }
+
+    /// Frequency in Hz and duration in seconds of the square wave approximating the sound.
+    /// The UFO sound loops for as long as its bit is set.
+    #[cfg(any(test, feature = "audio"))]
+    fn tone(self) -> (f32, f32) {
+        match self {
+            Sound::Ufo => (480., 0.1),
+            Sound::Shot => (1200., 0.25),
+            Sound::PlayerDeath => (90., 1.),
+            Sound::InvaderDeath => (600., 0.2),
+            Sound::ExtraLife => (1000., 0.8),
+            Sound::Fleet1 => (98., 0.1),
+            Sound::Fleet2 => (87., 0.1),
+            Sound::Fleet3 => (78., 0.1),
+            Sound::Fleet4 => (73., 0.1),
+            Sound::UfoHit => (300., 0.6),
+        }
+    }
+}
+
+/// Amplitude of a single sound in the mix
+#[cfg(any(test, feature = "audio"))]
+const VOLUME: f32 = 0.2;
+
+/// Recorded sound replacing a built-in one, mixed down to mono
+#[derive(Debug, Clone)]
+#[cfg_attr(not(any(test, feature = "audio")), allow(dead_code))] // Only played back with audio
+pub struct Sample {
+    sample_rate: u32,
+    /// Samples between -1 and 1
+    frames: Vec<f32>,
+}
+
+impl Sample {
+    /// Reads a PCM WAV file with 8-bit or 16-bit samples, mixing its channels down to mono
+    pub fn from_wav(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidSample(reason.into());
+        let u16_at = |position: usize| u16::from_le_bytes([bytes[position], bytes[position + 1]]);
+        let u32_at = |position: usize| u32::from(u16_at(position)) | u32::from(u16_at(position + 2)) << 16;
+
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("not a WAV file"));
+        }
+
+        // Chunks of any kind follow the header, each padded to an even size
+        let mut format = None;
+        let mut data = None;
+        let mut position = 12;
+        while position + 8 <= bytes.len() {
+            let size = u32_at(position + 4) as usize;
+            let body = bytes.get(position + 8..position + 8 + size).ok_or_else(|| invalid("truncated chunk"))?;
+            match &bytes[position..position + 4] {
+                b"fmt " if size >= 16 => format = Some(position + 8),
+                b"data" => data = Some(body),
+                _ => {}
+            }
+            position += 8 + size + size % 2;
+        }
+
+        let format = format.ok_or_else(|| invalid("missing format"))?;
+        let data = data.ok_or_else(|| invalid("missing data"))?;
+        let (encoding, channels, sample_rate, bits) = (u16_at(format), u16_at(format + 2), u32_at(format + 4), u16_at(format + 14));
+        if encoding != 1 {
+            return Err(invalid("only PCM samples are supported"));
+        }
+        if channels == 0 || sample_rate == 0 {
+            return Err(invalid("invalid format"));
+        }
+
+        let decode: fn(&[u8]) -> f32 = match bits {
+            8 => |bytes| (f32::from(bytes[0]) - 128.) / 128.,
+            16 => |bytes| f32::from(i16::from_le_bytes([bytes[0], bytes[1]])) / 32768.,
+            _ => return Err(invalid("only 8-bit and 16-bit samples are supported")),
+        };
+        let sample_size = usize::from(bits / 8);
+        let frames = data.chunks_exact(sample_size * usize::from(channels))
+                         .map(|frame| frame.chunks(sample_size).map(decode).sum::<f32>() / f32::from(channels))
+                         .collect();
+
+        Ok(Self { sample_rate, frames })
+    }
+
+    /// Length in seconds
+    #[cfg(any(test, feature = "audio"))]
+    #[allow(clippy::cast_precision_loss)]
+    fn duration(&self) -> f32 {
+        self.frames.len() as f32 / self.sample_rate as f32
+    }
+
+    /// Value `time` seconds in, silent past the end
+    #[cfg(any(test, feature = "audio"))]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    fn at(&self, time: f32) -> f32 {
+        let index = (time * self.sample_rate as f32) as usize;
+        self.frames.get(index).copied().unwrap_or(0.)
+    }
+}
+
+/// Recorded sounds played instead of the built-in square waves, for the sounds they are set for
+pub struct SampleSet {
+    samples: Vec<(Sound, Sample)>,
+}
+
+impl SampleSet {
+    /// Set playing the built-in sounds
+    pub fn new() -> Self {
+        Self { samples: Vec::new() }
+    }
+
+    /// Plays `sample` instead of the built-in `sound`, replacing any previous sample for it.
+    /// The UFO sound loops its sample for as long as its bit is set.
+    pub fn set(&mut self, sound: Sound, sample: Sample) {
+        self.samples.retain(|(replaced, _)| *replaced != sound);
+        self.samples.push((sound, sample));
+    }
+
+    #[cfg(any(test, feature = "audio"))]
+    fn get(&self, sound: Sound) -> Option<&Sample> {
+        self.samples.iter().find(|(replaced, _)| *replaced == sound).map(|(_, sample)| sample)
+    }
+
+    /// Value of `sound` `time` seconds in, from its sample or otherwise its square wave
+    #[cfg(any(test, feature = "audio"))]
+    fn value(&self, sound: Sound, time: f32) -> f32 {
+        match self.get(sound) {
+            Some(sample) if sound == Sound::Ufo && sample.duration() > 0. => VOLUME * sample.at(time % sample.duration()),
+            Some(sample) => VOLUME * sample.at(time),
+            None => {
+                if (time * sound.tone().0).fract() < 0.5 { VOLUME } else { -VOLUME }
+            }
+        }
+    }
+
+    /// How long a one-shot sound plays, in seconds
+    #[cfg(any(test, feature = "audio"))]
+    fn duration(&self, sound: Sound) -> f32 {
+        self.get(sound).map_or(sound.tone().1, Sample::duration)
+    }
+}
+
+/// State of the sound hardware, from which audio samples can be pulled
+pub struct SoundState {
+    port3: u8,
+    port5: u8,
+    /// UFO sound playback time, in seconds
+    ufo_time: f32,
+    /// One-shot sounds currently playing, with their playback time in seconds
+    playing: Vec<(Sound, f32)>,
+    /// Recorded sounds played instead of the built-in ones
+    sample_set: SampleSet,
+}
+
+impl SoundState {
+    pub fn new() -> Self {
+        Self {
+            port3: 0,
+            port5: 0,
+            ufo_time: 0.,
+            playing: Vec::new(),
+            sample_set: SampleSet::new(),
+        }
+    }
+
+    /// Sounds played instead of the built-in ones, replacing the previous set
+    pub fn set_sample_set(&mut self, sample_set: SampleSet) {
+        self.sample_set = sample_set;
+    }
+
+    /// Takes the custom sounds out, leaving the built-in ones
+    pub fn take_sample_set(&mut self) -> SampleSet {
+        std::mem::replace(&mut self.sample_set, SampleSet::new())
+    }
+
+    /// Whether the sound of the specified port bits is currently on
+    fn is_on(&self, sound: Sound) -> bool {
+        let (port, bit) = sound.port_bit();
+        let value = if port == 3 { self.port3 } else { self.port5 };
+        value & (1 << bit) != 0
+    }
+
+    /// The amplifier is enabled by bit 5 of port 3
+    #[cfg(any(test, feature = "audio"))]
+    fn amplifier_enabled(&self) -> bool {
+        self.port3 & (1 << 5) != 0
+    }
+
+    /// Handles a write to sound port 3 or 5, starting the sounds whose bit was just set.
+    /// Sounds are queued in the order of their writes, then in the fixed order of `Sound::ALL`
+    /// for those set by the same write, so replays always queue them the same way.
+    pub fn output(&mut self, port: u8, value: u8) {
+        let previous = Sound::ALL
+            .iter()
+            .map(|&sound| self.is_on(sound))
+            .collect::<Vec<_>>();
+
+        match port {
+            3 => self.port3 = value,
+            5 => self.port5 = value,
+            _ => return,
+        }
+
+        for (&sound, was_on) in Sound::ALL.iter().zip(previous) {
+            if sound != Sound::Ufo && !was_on && self.is_on(sound) {
+                self.playing.push((sound, 0.));
+            }
+        }
+    }
+
+    /// Silences all sounds, as if 0 was written to both ports
+    pub fn stop(&mut self) {
+        self.port3 = 0;
+        self.port5 = 0;
+        self.ufo_time = 0.;
+        self.playing.clear();
+    }
+
+    /// Values last written to ports 3 and 5
+    pub fn save_state(&self) -> [u8; 2] {
+        [self.port3, self.port5]
+    }
+
+    /// Restores the port values, without starting the one-shot sounds
+    pub fn load_state(&mut self, [port3, port5]: [u8; 2]) {
+        self.port3 = port3;
+        self.port5 = port5;
+        self.playing.clear();
+    }
+
+    /// Synthesizes the current sounds into `out`, at the specified sample rate
+    #[cfg(any(test, feature = "audio"))]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn samples(&mut self, out: &mut [f32], sample_rate: u32) {
+        let dt = 1. / sample_rate as f32;
+
+        for sample in out.iter_mut() {
+            let mut value = 0.;
+
+            if self.is_on(Sound::Ufo) {
+                value += self.sample_set.value(Sound::Ufo, self.ufo_time);
+                self.ufo_time += dt;
+            }
+
+            let sample_set = &self.sample_set;
+            for (sound, time) in &mut self.playing {
+                value += sample_set.value(*sound, *time);
+                *time += dt;
+            }
+            self.playing.retain(|&(sound, time)| time < sample_set.duration(sound));
+
+            *sample = if self.amplifier_enabled() {
+                value.clamp(-1., 1.)
+            } else {
+                0.
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bit 5 of port 3 enables the amplifier
+    const AMPLIFIER: u8 = 1 << 5;
+
+    fn pull(sound: &mut SoundState, length: usize) -> Vec<f32> {
+        let mut samples = vec![0.; length];
+        sound.samples(&mut samples, 44_100);
+        samples
+    }
+
+    #[test]
+    fn ufo_sound_produces_samples_only_while_on() {
+        let mut sound = SoundState::new();
+        assert!(pull(&mut sound, 1000).iter().all(|&sample| sample == 0.));
+
+        sound.output(3, AMPLIFIER | 1);
+        assert!(pull(&mut sound, 1000).iter().any(|&sample| sample != 0.));
+        // The UFO loops for as long as its bit is set
+        assert!(pull(&mut sound, 44_100).iter().skip(44_000).any(|&sample| sample != 0.));
+
+        sound.output(3, AMPLIFIER);
+        assert!(pull(&mut sound, 1000).iter().all(|&sample| sample == 0.));
+
+        // Nothing comes out with the amplifier off
+        sound.output(3, 1);
+        assert!(pull(&mut sound, 1000).iter().all(|&sample| sample == 0.));
+    }
+
+    /// One-shot sounds playing, in the order they were queued
+    fn queued(sound: &SoundState) -> Vec<Sound> {
+        sound.playing.iter().map(|&(sound, _)| sound).collect()
+    }
+
+    #[test]
+    fn sounds_set_together_are_queued_in_a_fixed_order() {
+        // Invader death and shot on port 3, fleet 1 and UFO hit on port 5, set by a single write each
+        let mut sound = SoundState::new();
+        sound.output(5, 1 << 4 | 1);
+        sound.output(3, AMPLIFIER | 1 << 3 | 1 << 1);
+        assert_eq!(queued(&sound), [Sound::Fleet1, Sound::UfoHit, Sound::Shot, Sound::InvaderDeath]);
+
+        // Separate writes are queued in the order they were made
+        let mut sound = SoundState::new();
+        sound.output(3, AMPLIFIER | 1 << 3);
+        sound.output(3, AMPLIFIER | 1 << 3 | 1 << 1);
+        assert_eq!(queued(&sound), [Sound::InvaderDeath, Sound::Shot]);
+    }
+
+    /// WAV file of `data` in the format `encoding`, `channels`, 44.1 kHz and `bits` per sample
+    fn wav(encoding: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&encoding.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&44_100u32.to_le_bytes());
+        let block_size = channels * bits / 8;
+        bytes.extend_from_slice(&(44_100 * u32::from(block_size)).to_le_bytes());
+        bytes.extend_from_slice(&block_size.to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn invalid_reason(bytes: &[u8]) -> String {
+        match Sample::from_wav(bytes) {
+            Err(Error::InvalidSample(reason)) => reason,
+            other => panic!("expected an invalid sample, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wav_files_are_decoded_to_mono() {
+        // Stereo 16-bit frames of 0.5 and -0.25, then 0.25 and 0.25
+        let sample = Sample::from_wav(&wav(1, 2, 16, &[0x00, 0x40, 0x00, 0xe0, 0x00, 0x20, 0x00, 0x20])).unwrap();
+        assert_eq!(sample.sample_rate, 44_100);
+        assert_eq!(sample.frames, [0.125, 0.25]);
+
+        // 8-bit samples are unsigned, centered on 128
+        let sample = Sample::from_wav(&wav(1, 1, 8, &[0x80, 0xc0, 0x00])).unwrap();
+        assert_eq!(sample.frames, [0., 0.5, -1.]);
+    }
+
+    #[test]
+    fn malformed_or_unsupported_wav_files_are_refused() {
+        let valid = wav(1, 1, 16, &[0x00, 0x40, 0x00, 0x40]);
+        assert_eq!(invalid_reason(&valid[..valid.len() - 1]), "truncated chunk");
+        assert_eq!(invalid_reason(&valid[..8]), "not a WAV file");
+        // Float samples
+        assert_eq!(invalid_reason(&wav(3, 1, 32, &[0; 8])), "only PCM samples are supported");
+        assert_eq!(invalid_reason(&wav(1, 1, 24, &[0; 6])), "only 8-bit and 16-bit samples are supported");
+    }
+
+    #[test]
+    fn custom_samples_replace_the_built_in_sound() {
+        // 10 ms at 0.5 for the shot
+        let mut sample_set = SampleSet::new();
+        let data = [0x00, 0x40].repeat(441);
+        sample_set.set(Sound::Shot, Sample::from_wav(&wav(1, 1, 16, &data)).unwrap());
+        let mut sound = SoundState::new();
+        sound.set_sample_set(sample_set);
+
+        sound.output(3, AMPLIFIER | 1 << 1);
+        assert!(pull(&mut sound, 441).iter().all(|&sample| sample == VOLUME * 0.5));
+        // The shot ends with its sample, instead of after its built-in duration
+        assert!(pull(&mut sound, 441).iter().all(|&sample| sample == 0.));
+        assert!(queued(&sound).is_empty());
+
+        // Other sounds keep their square wave
+        sound.output(3, AMPLIFIER | 1 << 1 | 1 << 3);
+        assert!(pull(&mut sound, 1000).iter().all(|&sample| sample.abs() == VOLUME));
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{
+    clock::{FrameLimiter, SystemClock},
+    error::Error,
+    machine::Machine,
+};
+
+/// Width in pixels of the screen area shown by a single character
+const CELL_WIDTH: usize = 4;
+/// Height in pixels of the screen area shown by a single character
+const CELL_HEIGHT: usize = 8;
+
+/// Characters used for cells, from empty to fully lit
+const SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+
+/// Runs the emulator without a window, printing the screen to the terminal each frame
+pub fn run(machine: &mut impl Machine) -> Result<(), Error> {
+    let (width, height) = (machine.width(), machine.height());
+    let mut buffer = vec![0; width * height];
+    let mut frame_limiter = FrameLimiter::new(SystemClock, machine.frame_duration());
+
+    loop {
+        machine.run_frames(1)?;
+        machine.render_into(&mut buffer)?;
+
+        // Move the cursor back to the top left, drawing over the previous frame
+        print!("\x1b[H{}", render(&buffer, width, height));
+
+        frame_limiter.wait();
+    }
+}
+
+/// Downscales a screen buffer of `width` by `height` pixels to lines of characters,
+/// each showing a block of pixels
+pub fn render(buffer: &[u32], width: usize, height: usize) -> String {
+    let columns = width / CELL_WIDTH;
+    let rows = height / CELL_HEIGHT;
+    let mut text = String::with_capacity((columns + 1) * rows * 3);
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let mut lit = 0;
+
+            for y in row * CELL_HEIGHT..(row + 1) * CELL_HEIGHT {
+                for x in column * CELL_WIDTH..(column + 1) * CELL_WIDTH {
+                    if buffer[x + y * width] != 0 {
+                        lit += 1;
+                    }
+                }
+            }
+
+            text.push(cell_char(lit));
+        }
+
+        text.push('\n');
+    }
+
+    text
+}
+
+/// Character shading a cell with `lit` pixels on
+fn cell_char(lit: usize) -> char {
+    SHADES[lit * (SHADES.len() - 1) / (CELL_WIDTH * CELL_HEIGHT)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_are_shaded_by_their_lit_pixels() {
+        // Two cells side by side, the left one fully lit
+        let (width, height) = (2 * CELL_WIDTH, CELL_HEIGHT);
+        let buffer = (0..width * height).map(|pixel| if pixel % width < CELL_WIDTH { 0xff_ff_ff } else { 0 })
+                                        .collect::<Vec<_>>();
+
+        assert_eq!(render(&buffer, width, height), "█ \n");
+        assert_eq!(cell_char(CELL_WIDTH * CELL_HEIGHT / 2), '▒');
+    }
+}